@@ -1,12 +1,21 @@
 #![allow(unused)]
 
 use std::cmp::max;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
 use std::iter::repeat_with;
 use std::net::SocketAddr;
-use std::ops::{AddAssign, RangeInclusive};
-use std::time::{Duration, Instant};
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context};
+use clap::Parser;
 use futures::SinkExt;
-use chat_server::{choose, connection_refused, parse_socket_addr, random_english_msg, random_rust_msg, stdout_logging};
+use serde::{Deserialize, Serialize};
+use chat_server::{choose, connection_refused, random_english_msg, random_name, random_rust_msg, stdout_logging, valid_name, AddrArgs, MAX_NAME_LEN};
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
 use tokio::net::{
     tcp::{OwnedReadHalf, OwnedWriteHalf},
     TcpStream,
@@ -16,10 +25,14 @@ use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
 
 struct Bot<M> {
+    id: usize,
     msgs: M,
-    msg_delay: RangeInclusive<u64>,
+    msg_delay: Delay,
+    deadline: Option<Instant>,
     sink: FramedWrite<OwnedWriteHalf, LinesCodec>,
     stream: FramedRead<OwnedReadHalf, LinesCodec>,
+    room: String,
+    msgs_tagged: u64,
     stats: Stats,
 }
 
@@ -29,6 +42,20 @@ struct Stats {
     got_bytes: usize,
     sent_msgs: usize,
     got_msgs: usize,
+    // tagged msgs this bot sent which came back to it
+    own_msgs: usize,
+    // tagged msgs from other bots
+    peer_msgs: usize,
+    // msgs the server told us it dropped
+    lagged_msgs: usize,
+    latency: Histogram,
+    rooms: HashMap<String, RoomStats>,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+struct RoomStats {
+    sent: usize,
+    delivered: usize,
 }
 
 impl AddAssign for Stats {
@@ -37,40 +64,215 @@ impl AddAssign for Stats {
         self.sent_msgs += rhs.sent_msgs;
         self.got_bytes += rhs.got_bytes;
         self.got_msgs += rhs.got_msgs;
+        self.own_msgs += rhs.own_msgs;
+        self.peer_msgs += rhs.peer_msgs;
+        self.lagged_msgs += rhs.lagged_msgs;
+        self.latency += rhs.latency;
+        for (room, room_stats) in rhs.rooms {
+            let entry = self.rooms.entry(room).or_default();
+            entry.sent += room_stats.sent;
+            entry.delivered += room_stats.delivered;
+        }
+    }
+}
+
+// LATENCY //
+
+// every chat msg a bot sends gets a tag appended
+// with the bot's id, a per-bot sequence number
+// and the unix time in micros when it was sent,
+// e.g. "hello world ~12.345.1715000000000000"
+const TAG_PREFIX: &str = " ~";
+
+fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+struct Tag {
+    bot: usize,
+    seq: u64,
+    sent_micros: u64,
+}
+
+impl Tag {
+    fn append(msg: &mut String, bot: usize, seq: u64) {
+        write!(msg, "{TAG_PREFIX}{bot}.{seq}.{}", unix_micros()).unwrap();
+    }
+    fn parse(msg: &str) -> Option<Self> {
+        let (_, tag) = msg.rsplit_once(TAG_PREFIX)?;
+        let mut parts = tag.split('.').map(str::parse::<u64>);
+        let bot = parts.next()?.ok()? as usize;
+        let seq = parts.next()?.ok()?;
+        let sent_micros = parts.next()?.ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            bot,
+            seq,
+            sent_micros,
+        })
     }
 }
 
+// latency histogram with log-linear buckets, values
+// below 128 get exact buckets and every power of two
+// above that is split into 64 buckets, so percentiles
+// are accurate to within ~1.5% without having to keep
+// every sample around
+const SUB_BUCKETS: u64 = 64;
+const EXACT_BUCKETS: u64 = SUB_BUCKETS * 2;
+const BUCKETS: usize = (EXACT_BUCKETS + 57 * SUB_BUCKETS) as usize;
+
+#[derive(Debug)]
+struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; BUCKETS],
+            total: 0,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn bucket(value: u64) -> usize {
+        if value < EXACT_BUCKETS {
+            return value as usize;
+        }
+        let magnitude = 63 - value.leading_zeros() as u64;
+        let shift = magnitude - 6;
+        let sub = (value >> shift) - SUB_BUCKETS;
+        (EXACT_BUCKETS + (shift - 1) * SUB_BUCKETS + sub) as usize
+    }
+    fn bucket_value(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < EXACT_BUCKETS {
+            return bucket;
+        }
+        let offset = bucket - EXACT_BUCKETS;
+        let shift = offset / SUB_BUCKETS + 1;
+        let sub = offset % SUB_BUCKETS + SUB_BUCKETS;
+        sub << shift
+    }
+    fn record(&mut self, value: u64) {
+        self.counts[Self::bucket(value)] += 1;
+        self.total += 1;
+        self.max = max(self.max, value);
+    }
+    fn percentile(&self, percentile: f64) -> u64 {
+        let target = ((percentile / 100.0) * self.total as f64).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target.max(1) {
+                return Self::bucket_value(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+impl AddAssign for Histogram {
+    fn add_assign(&mut self, rhs: Self) {
+        for (count, rhs_count) in self.counts.iter_mut().zip(rhs.counts) {
+            *count += rhs_count;
+        }
+        self.total += rhs.total;
+        self.max = max(self.max, rhs.max);
+    }
+}
+
+// the server tells users how many msgs it dropped if
+// their room is too busy or they read too slowly, e.g.
+// "Server is very busy and dropped 3 messages, sorry!"
+// "You're reading too slowly, server dropped 3 messages"
+fn dropped_notice(msg: &str) -> Option<usize> {
+    // msgs from users always have a colon
+    if msg.contains(':') {
+        return None;
+    }
+    let (_, dropped) = msg.split_once(" dropped ")?;
+    dropped.split_ascii_whitespace().next()?.parse().ok()
+}
+
 impl<M: Iterator<Item = String>> Bot<M> {
-    async fn new(addr: SocketAddr, msgs: M, msg_delay: RangeInclusive<u64>) -> anyhow::Result<Self> {
+    async fn new(addr: SocketAddr, id: usize, msgs: M, msg_delay: Delay, deadline: Option<Instant>) -> anyhow::Result<Self> {
         let conn = TcpStream::connect(addr).await?;
         let (reader, writer) = conn.into_split();
         let sink = FramedWrite::new(writer, LinesCodec::new());
         let stream = FramedRead::new(reader, LinesCodec::new());
         Ok(Self {
+            id,
             msgs,
             msg_delay,
+            deadline,
             sink,
             stream,
+            room: "main".to_owned(),
+            msgs_tagged: 0,
             stats: Stats::default(),
         })
     }
+    fn got(&mut self, msg: &str) {
+        self.stats.got_bytes += msg.len() + 1;
+        self.stats.got_msgs += 1;
+        if let Some(tag) = Tag::parse(msg) {
+            let latency = unix_micros().saturating_sub(tag.sent_micros);
+            self.stats.latency.record(latency);
+            if tag.bot == self.id {
+                self.stats.own_msgs += 1;
+            } else {
+                self.stats.peer_msgs += 1;
+            }
+            self.stats.rooms.entry(self.room.clone()).or_default().delivered += 1;
+        } else if let Some(room) = msg.strip_prefix("You joined ") {
+            self.room = room.to_owned();
+        } else if let Some(dropped) = dropped_notice(msg) {
+            self.stats.lagged_msgs += dropped;
+        }
+    }
     async fn chat(mut self) -> anyhow::Result<Stats> {
-        for msg in self.msgs {
+        while let Some(mut msg) = self.msgs.next() {
+            let out_of_time = self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline);
+            if out_of_time {
+                msg = "/quit".to_owned();
+            }
+            if let Some(room) = msg.strip_prefix("/join ") {
+                // don't wait for the server to confirm so
+                // the msgs that follow count for the new room
+                self.room = room.to_owned();
+            } else if !msg.starts_with('/') {
+                Tag::append(&mut msg, self.id, self.msgs_tagged);
+                self.msgs_tagged += 1;
+                self.stats.rooms.entry(self.room.clone()).or_default().sent += 1;
+            }
             let msg_len = msg.len();
             self.sink.send(msg).await?;
             self.stats.sent_bytes += msg_len + 1;
             self.stats.sent_msgs += 1;
-            let sleep = tokio::time::sleep(
-                Duration::from_millis(fastrand::u64(self.msg_delay.clone()))
-            );
+            if out_of_time {
+                break;
+            }
+            let sleep = tokio::time::sleep(self.msg_delay.sample());
             tokio::pin!(sleep);
             loop {
                 tokio::select! {
                     option = self.stream.next() => {
                         if let Some(result) = option {
                             let msg = result?;
-                            self.stats.got_bytes += msg.len() + 1;
-                            self.stats.got_msgs += 1;
+                            self.got(&msg);
                         }
                     },
                     _ = &mut sleep => {
@@ -83,104 +285,740 @@ impl<M: Iterator<Item = String>> Bot<M> {
     }
 }
 
-struct Simple {
-    send_msgs: usize,
-    msgs_sent: usize,
+// SUMMARY //
+
+#[derive(Serialize)]
+struct Summary {
+    bots: usize,
+    elapsed_secs: f64,
+    sent_bytes: usize,
+    got_bytes: usize,
+    sent_msgs: usize,
+    got_msgs: usize,
+    own_msgs: usize,
+    peer_msgs: usize,
+    lagged_msgs: usize,
+    // tagged msgs which never made it back to their
+    // sender, should roughly match lagged_msgs
+    missing_own_msgs: usize,
+    latency_ms: LatencySummary,
+    rooms: BTreeMap<String, RoomSummary>,
+    adversaries: Vec<Report>,
+}
+
+#[derive(Serialize)]
+struct LatencySummary {
+    samples: u64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+#[derive(Serialize)]
+struct RoomSummary {
+    sent: usize,
+    delivered: usize,
+    // avg number of bots each msg reached
+    fan_out: f64,
 }
 
-impl Simple {
-    fn new() -> Self {
+impl Summary {
+    fn new(bots: usize, elapsed: Duration, stats: Stats, adversaries: Vec<Report>) -> Self {
+        let ms = |micros: u64| micros as f64 / 1000.0;
+        let latency_ms = LatencySummary {
+            samples: stats.latency.total,
+            p50: ms(stats.latency.percentile(50.0)),
+            p90: ms(stats.latency.percentile(90.0)),
+            p99: ms(stats.latency.percentile(99.0)),
+            max: ms(stats.latency.max),
+        };
+        let tagged_msgs: usize = stats
+            .rooms
+            .values()
+            .map(|room_stats| room_stats.sent)
+            .sum();
+        let missing_own_msgs = tagged_msgs.saturating_sub(stats.own_msgs);
+        let rooms = stats
+            .rooms
+            .into_iter()
+            .map(|(room, room_stats)| {
+                let fan_out = if room_stats.sent == 0 {
+                    0.0
+                } else {
+                    room_stats.delivered as f64 / room_stats.sent as f64
+                };
+                (room, RoomSummary {
+                    sent: room_stats.sent,
+                    delivered: room_stats.delivered,
+                    fan_out,
+                })
+            })
+            .collect();
         Self {
-            send_msgs: 100,
-            msgs_sent: 0,
+            bots,
+            elapsed_secs: elapsed.as_secs_f64(),
+            sent_bytes: stats.sent_bytes,
+            got_bytes: stats.got_bytes,
+            sent_msgs: stats.sent_msgs,
+            got_msgs: stats.got_msgs,
+            own_msgs: stats.own_msgs,
+            peer_msgs: stats.peer_msgs,
+            lagged_msgs: stats.lagged_msgs,
+            missing_own_msgs,
+            latency_ms,
+            rooms,
+            adversaries,
         }
     }
+    fn log(&self) {
+        tracing::info!("sent bytes - {}", self.sent_bytes);
+        tracing::info!("got bytes  - {}", self.got_bytes);
+        tracing::info!("sent msgs  - {}", self.sent_msgs);
+        tracing::info!("got msgs   - {}", self.got_msgs);
+        tracing::info!("own msgs   - {}", self.own_msgs);
+        tracing::info!("peer msgs  - {}", self.peer_msgs);
+        tracing::info!("lagged     - {}", self.lagged_msgs);
+        tracing::info!("missing    - {}", self.missing_own_msgs);
+        let latency = &self.latency_ms;
+        tracing::info!(
+            "latency ms - p50 {:.2}, p90 {:.2}, p99 {:.2}, max {:.2}",
+            latency.p50, latency.p90, latency.p99, latency.max,
+        );
+        for (room, room_summary) in &self.rooms {
+            tracing::info!(
+                "room {room} - sent {}, delivered {}, fan-out {:.1}",
+                room_summary.sent, room_summary.delivered, room_summary.fan_out,
+            );
+        }
+        for report in &self.adversaries {
+            let observed = report
+                .observed
+                .iter()
+                .map(|(observed, count)| format!("{observed} x{count}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            if report.unexpected > 0 {
+                tracing::warn!(
+                    "{:?} - {} of {} runs unexpected: {observed}",
+                    report.kind, report.unexpected, report.runs,
+                );
+            } else {
+                tracing::info!("{:?} - {} runs: {observed}", report.kind, report.runs);
+            }
+        }
+    }
+    fn write(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json)
+            .with_context(|| format!("could not write {}", path.display()))?;
+        tracing::info!("wrote summary to {}", path.display());
+        Ok(())
+    }
 }
 
-impl Iterator for Simple {
-    type Item = String;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.msgs_sent >= self.send_msgs {
-            return None;
-        }
-        let msg = if self.msgs_sent == self.send_msgs - 1 {
-            "/quit".to_owned()
-        } else {
-            random_english_msg()
+// SCENARIOS //
+
+const DEFAULT_SCENARIO: &str = include_str!("scenario.toml");
+
+#[derive(Parser)]
+#[command(long_about = None)]
+struct Cli {
+    #[command(flatten)]
+    addr: AddrArgs,
+
+    /// Scenario file describing which bots to run,
+    /// see src/bin/scenario.toml for the format
+    #[arg(short, long)]
+    scenario: Option<PathBuf>,
+
+    /// Where to write the JSON summary of the run
+    #[arg(long, default_value = "logs/chat-bots-summary.json")]
+    summary: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    duration_secs: Option<u64>,
+    ramp_up_per_sec: Option<f64>,
+    #[serde(default)]
+    bots: Vec<BotSpec>,
+    #[serde(default)]
+    adversaries: Vec<AdversarySpec>,
+}
+
+impl Scenario {
+    fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let scenario = match path {
+            Some(path) => fs::read_to_string(path)
+                .with_context(|| format!("could not read {}", path.display()))?,
+            None => DEFAULT_SCENARIO.to_owned(),
         };
-        self.msgs_sent += 1;
-        Some(msg)
+        let scenario: Self = toml::from_str(&scenario)?;
+        for spec in &scenario.bots {
+            spec.validate()?;
+        }
+        for spec in &scenario.adversaries {
+            if let Some(room) = &spec.room {
+                if !valid_name(Some(room)) {
+                    bail!("room {room:?} must be 2 - 20 alphanumeric chars");
+                }
+            }
+        }
+        Ok(scenario)
     }
 }
 
-struct Rusty {
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct BotSpec {
+    #[serde(default = "one")]
+    count: usize,
+    #[serde(default)]
+    msgs: MsgGen,
+    #[serde(default)]
+    rooms: Vec<String>,
+    #[serde(default = "one_hundred")]
     send_msgs: usize,
-    msgs_sent: usize,
+    delay: Delay,
+    #[serde(default)]
+    switch_room_chance: f64,
+    #[serde(default)]
+    rename_chance: f64,
 }
 
-impl Rusty {
-    fn new() -> Self {
-        Self {
-            send_msgs: 100,
-            msgs_sent: 0,
+fn one() -> usize {
+    1
+}
+
+fn one_hundred() -> usize {
+    100
+}
+
+impl BotSpec {
+    fn validate(&self) -> anyhow::Result<()> {
+        for room in &self.rooms {
+            if !valid_name(Some(room)) {
+                bail!("room {room:?} must be 2 - 20 alphanumeric chars");
+            }
+        }
+        if !(0.0..=1.0).contains(&self.switch_room_chance) {
+            bail!("switch_room_chance must be between 0.0 and 1.0");
         }
+        if !(0.0..=1.0).contains(&self.rename_chance) {
+            bail!("rename_chance must be between 0.0 and 1.0");
+        }
+        if let Delay::Uniform { min, max } = self.delay {
+            if min > max {
+                bail!("uniform delay min must not be greater than max");
+            }
+        }
+        Ok(())
     }
 }
 
-impl Iterator for Rusty {
-    type Item = String;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.msgs_sent >= self.send_msgs {
-            return None;
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+enum MsgGen {
+    #[default]
+    English,
+    Rust,
+    Fixed(String),
+    Sized(usize),
+}
+
+impl MsgGen {
+    fn generate(&self) -> String {
+        match self {
+            MsgGen::English => random_english_msg(),
+            MsgGen::Rust => random_rust_msg(),
+            MsgGen::Fixed(msg) => msg.clone(),
+            MsgGen::Sized(len) => {
+                let mut msg = String::with_capacity(*len);
+                while msg.len() < *len {
+                    msg.push_str(&random_english_msg());
+                    msg.push(' ');
+                }
+                // english msgs can end with multi-byte emojis
+                let mut len = *len;
+                while !msg.is_char_boundary(len) {
+                    len -= 1;
+                }
+                msg.truncate(len);
+                msg
+            },
         }
-        let msg = if self.msgs_sent == 0 {
-            "/join rust".to_owned()
-        } else if self.msgs_sent == self.send_msgs - 1 {
-            "/quit".to_owned()
-        } else {
-            random_rust_msg()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+enum Delay {
+    Constant(u64),
+    Uniform { min: u64, max: u64 },
+    Exponential { mean: u64 },
+}
+
+impl Delay {
+    fn sample(&self) -> Duration {
+        let millis = match *self {
+            Delay::Constant(millis) => millis,
+            Delay::Uniform { min, max } => fastrand::u64(min..=max),
+            Delay::Exponential { mean } => {
+                // inverse transform sampling, 1 - f64() is
+                // in (0, 1] so ln never gets a zero
+                let sample = -(1.0 - fastrand::f64()).ln() * mean as f64;
+                sample as u64
+            },
         };
-        self.msgs_sent += 1;
-        Some(msg)
+        Duration::from_millis(millis)
     }
 }
 
-struct StressTest {
-    send_msgs: usize,
+// generates the msgs a single bot sends
+// according to its spec
+struct Chatter {
+    spec: BotSpec,
+    room: Option<String>,
     msgs_sent: usize,
 }
 
-impl StressTest {
-    fn new() -> Self {
+impl Chatter {
+    fn new(spec: BotSpec) -> Self {
+        let room = fastrand::choice(&spec.rooms).cloned();
         Self {
-            send_msgs: 100000,
+            spec,
+            room,
             msgs_sent: 0,
         }
     }
+    fn switch_room(&mut self) -> Option<String> {
+        let others: Vec<&String> = self
+            .spec
+            .rooms
+            .iter()
+            .filter(|room| Some(*room) != self.room.as_ref())
+            .collect();
+        if others.is_empty() {
+            return None;
+        }
+        let room = choose(&others).clone();
+        let msg = format!("/join {room}");
+        self.room = Some(room);
+        Some(msg)
+    }
+}
+
+fn random_valid_name() -> String {
+    loop {
+        let name = random_name();
+        if valid_name(Some(&name)) {
+            break name;
+        }
+    }
 }
 
-impl Iterator for StressTest {
+impl Iterator for Chatter {
     type Item = String;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.msgs_sent >= self.send_msgs {
+        if self.msgs_sent >= self.spec.send_msgs {
             return None;
         }
-        let msg = if self.msgs_sent == 0 {
-            "/join stress-test".to_owned()
-        } else if self.msgs_sent == self.send_msgs - 1 {
-            "/quit".to_owned()
-        } else {
-            random_english_msg()
-        };
+        let first = self.msgs_sent == 0;
+        let last = self.msgs_sent == self.spec.send_msgs - 1;
         self.msgs_sent += 1;
-        Some(msg)
+        if last {
+            return Some("/quit".to_owned());
+        }
+        if first {
+            if let Some(room) = &self.room {
+                return Some(format!("/join {room}"));
+            }
+        }
+        if fastrand::f64() < self.spec.switch_room_chance {
+            if let Some(msg) = self.switch_room() {
+                return Some(msg);
+            }
+        }
+        if fastrand::f64() < self.spec.rename_chance {
+            return Some(format!("/name {}", random_valid_name()));
+        }
+        Some(self.spec.msgs.generate())
+    }
+}
+
+// ADVERSARIES //
+
+// bots which poke at the failure paths of the
+// server and report how it reacted, so reports
+// can be diffed between runs to catch regressions
+
+const ADVERSARY_TIMEOUT: Duration = Duration::from_secs(5);
+// server's max line length is 400
+const LONG_LINE_LEN: usize = 500;
+const SPAM_COMMANDS: usize = 20;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum AdversaryKind {
+    // disconnects halfway through typing a line
+    AbruptDisconnect,
+    // sends lines longer than the server allows
+    LongLine,
+    // sends bytes which aren't valid utf8
+    InvalidUtf8,
+    // joins a room and stops reading for a while
+    SlowConsumer,
+    // spams /join and /name as fast as possible
+    CommandSpam,
+    // connects and disconnects in a tight loop
+    ReconnectLoop,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct AdversarySpec {
+    kind: AdversaryKind,
+    #[serde(default = "one")]
+    count: usize,
+    // how many times each bot repeats its attack
+    #[serde(default = "one")]
+    repeat: usize,
+    // room to attack, defaults to main
+    room: Option<String>,
+    // how long a slow consumer stops reading for
+    #[serde(default = "five")]
+    stall_secs: u64,
+}
+
+fn five() -> u64 {
+    5
+}
+
+// what the server did in response to an attack
+type Observed = &'static str;
+
+const LEFT_BROADCAST: Observed = "broadcast user left";
+const NO_LEFT_BROADCAST: Observed = "never broadcast user left";
+const REJECTED_STAYED: Observed = "rejected line and stayed connected";
+const REJECTED_DISCONNECTED: Observed = "rejected line and disconnected";
+const ACCEPTED: Observed = "accepted line";
+const DISCONNECTED: Observed = "disconnected";
+const STAYED_CONNECTED: Observed = "stayed connected";
+const LAGGED_NOTIFIED: Observed = "dropped msgs and notified";
+const KEPT_UP: Observed = "kept up";
+const CONSISTENT: Observed = "consistent";
+const INCONSISTENT: Observed = "inconsistent";
+const GREETED: Observed = "greeted";
+const NOT_GREETED: Observed = "not greeted";
+const REFUSED: Observed = "refused";
+const TIMED_OUT: Observed = "timed out";
+
+impl AdversaryKind {
+    // what a healthy server should do, if
+    // there's a single right answer
+    fn expected(self) -> Option<Observed> {
+        match self {
+            AdversaryKind::AbruptDisconnect => Some(LEFT_BROADCAST),
+            AdversaryKind::LongLine => Some(REJECTED_STAYED),
+            AdversaryKind::InvalidUtf8 => Some(DISCONNECTED),
+            AdversaryKind::SlowConsumer => None,
+            AdversaryKind::CommandSpam => Some(CONSISTENT),
+            AdversaryKind::ReconnectLoop => Some(GREETED),
+        }
+    }
+}
+
+enum Wait {
+    Got(String),
+    Closed,
+    TimedOut,
+}
+
+// a raw connection to the server which can
+// misbehave in ways Bot can't
+struct Probe {
+    name: String,
+    writer: OwnedWriteHalf,
+    stream: FramedRead<OwnedReadHalf, LinesCodec>,
+}
+
+impl Probe {
+    async fn connect(addr: SocketAddr) -> Result<Self, Observed> {
+        let conn = match tokio::time::timeout(ADVERSARY_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(conn)) => conn,
+            Ok(Err(_)) => return Err(REFUSED),
+            Err(_) => return Err(TIMED_OUT),
+        };
+        let (reader, writer) = conn.into_split();
+        let mut probe = Self {
+            name: String::new(),
+            writer,
+            stream: FramedRead::new(reader, LinesCodec::new()),
+        };
+        match probe.wait_for(|line| line.starts_with("You are ")).await {
+            Wait::Got(line) => {
+                probe.name = line["You are ".len()..].to_owned();
+                Ok(probe)
+            },
+            Wait::Closed => Err(NOT_GREETED),
+            Wait::TimedOut => Err(TIMED_OUT),
+        }
+    }
+    async fn send_raw(&mut self, bytes: &[u8]) -> bool {
+        self.writer.write_all(bytes).await.is_ok()
+    }
+    async fn send(&mut self, line: &str) -> bool {
+        let mut bytes = Vec::with_capacity(line.len() + 1);
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.push(b'\n');
+        self.send_raw(&bytes).await
+    }
+    async fn join(&mut self, room: &str) -> bool {
+        self.send(&format!("/join {room}")).await
+            && matches!(
+                self.wait_for(|line| line == format!("You joined {room}")).await,
+                Wait::Got(_),
+            )
+    }
+    async fn wait_for(&mut self, mut matches: impl FnMut(&str) -> bool) -> Wait {
+        let deadline = Instant::now() + ADVERSARY_TIMEOUT;
+        loop {
+            match tokio::time::timeout_at(deadline, self.stream.next()).await {
+                Ok(Some(Ok(line))) if matches(&line) => return Wait::Got(line),
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(_)) | None) => return Wait::Closed,
+                Err(_) => return Wait::TimedOut,
+            }
+        }
+    }
+}
+
+struct Adversary {
+    addr: SocketAddr,
+    spec: AdversarySpec,
+    room: String,
+}
+
+impl Adversary {
+    fn new(addr: SocketAddr, spec: AdversarySpec, id: usize) -> Self {
+        // attacks which watch for broadcasts get a room
+        // of their own so they don't see each other
+        let room = match (&spec.room, spec.kind) {
+            (Some(room), _) => room.clone(),
+            (None, AdversaryKind::AbruptDisconnect | AdversaryKind::CommandSpam) => {
+                format!("adversary-{id}")
+            },
+            (None, _) => "main".to_owned(),
+        };
+        Self {
+            addr,
+            spec,
+            room,
+        }
+    }
+    async fn attack(self) -> Report {
+        let mut report = Report::new(self.spec.kind);
+        for _ in 0..self.spec.repeat {
+            let observed = match self.spec.kind {
+                AdversaryKind::AbruptDisconnect => self.abrupt_disconnect().await,
+                AdversaryKind::LongLine => self.long_line().await,
+                AdversaryKind::InvalidUtf8 => self.invalid_utf8().await,
+                AdversaryKind::SlowConsumer => self.slow_consumer().await,
+                AdversaryKind::CommandSpam => self.command_spam().await,
+                AdversaryKind::ReconnectLoop => self.reconnect().await,
+            };
+            report.observed(observed);
+        }
+        report
+    }
+    async fn connect_to_room(&self) -> Result<Probe, Observed> {
+        let mut probe = Probe::connect(self.addr).await?;
+        if self.room != "main" && !probe.join(&self.room).await {
+            return Err(DISCONNECTED);
+        }
+        Ok(probe)
+    }
+    async fn abrupt_disconnect(&self) -> Observed {
+        let mut watcher = match self.connect_to_room().await {
+            Ok(probe) => probe,
+            Err(observed) => return observed,
+        };
+        let mut probe = match self.connect_to_room().await {
+            Ok(probe) => probe,
+            Err(observed) => return observed,
+        };
+        probe.send_raw(b"this line never ends").await;
+        let left = format!("{} left", probe.name);
+        drop(probe);
+        match watcher.wait_for(|line| line == left).await {
+            Wait::Got(_) => LEFT_BROADCAST,
+            Wait::Closed | Wait::TimedOut => NO_LEFT_BROADCAST,
+        }
+    }
+    async fn long_line(&self) -> Observed {
+        let mut probe = match self.connect_to_room().await {
+            Ok(probe) => probe,
+            Err(observed) => return observed,
+        };
+        let long_line = "a".repeat(LONG_LINE_LEN);
+        if !probe.send(&long_line).await {
+            return DISCONNECTED;
+        }
+        let rejected = probe.wait_for(|line| {
+            line.starts_with("Messages can only be") || line.ends_with(&long_line)
+        }).await;
+        match rejected {
+            Wait::Got(line) if line.ends_with(&long_line) => ACCEPTED,
+            Wait::Got(_) => {
+                probe.send("/rooms").await;
+                match probe.wait_for(|line| line.starts_with("Rooms - ")).await {
+                    Wait::Got(_) => REJECTED_STAYED,
+                    Wait::Closed | Wait::TimedOut => REJECTED_DISCONNECTED,
+                }
+            },
+            Wait::Closed => DISCONNECTED,
+            Wait::TimedOut => TIMED_OUT,
+        }
+    }
+    async fn invalid_utf8(&self) -> Observed {
+        let mut probe = match self.connect_to_room().await {
+            Ok(probe) => probe,
+            Err(observed) => return observed,
+        };
+        // what telnet sends on ^C
+        probe.send_raw(&[0xff, 0xf4, 0xff, 0xfd, 0x06, b'\n']).await;
+        probe.send("/rooms").await;
+        match probe.wait_for(|line| line.starts_with("Rooms - ")).await {
+            Wait::Got(_) => STAYED_CONNECTED,
+            Wait::Closed => DISCONNECTED,
+            Wait::TimedOut => TIMED_OUT,
+        }
+    }
+    async fn slow_consumer(&self) -> Observed {
+        let mut probe = match self.connect_to_room().await {
+            Ok(probe) => probe,
+            Err(observed) => return observed,
+        };
+        tokio::time::sleep(Duration::from_secs(self.spec.stall_secs)).await;
+        // drain everything that piled up while we
+        // weren't reading, then make sure we're
+        // still connected and caught up
+        let mut lagged = false;
+        probe.send("/rooms").await;
+        let caught_up = probe.wait_for(|line| {
+            lagged |= dropped_notice(line).is_some();
+            line.starts_with("Rooms - ")
+        }).await;
+        match caught_up {
+            Wait::Got(_) if lagged => LAGGED_NOTIFIED,
+            Wait::Got(_) => KEPT_UP,
+            Wait::Closed => DISCONNECTED,
+            Wait::TimedOut => TIMED_OUT,
+        }
+    }
+    async fn command_spam(&self) -> Observed {
+        let mut probe = match self.connect_to_room().await {
+            Ok(probe) => probe,
+            Err(observed) => return observed,
+        };
+        // leave space for the suffix within the room name limit
+        let base = &self.room[..self.room.len().min(MAX_NAME_LEN - 2)];
+        let rooms = [self.room.clone(), format!("{base}-b")];
+        let mut last_name = probe.name.clone();
+        for idx in 0..SPAM_COMMANDS {
+            let sent = if idx % 2 == 0 {
+                probe.send(&format!("/join {}", rooms[(idx / 2 + 1) % 2])).await
+            } else {
+                last_name = random_valid_name();
+                probe.send(&format!("/name {last_name}")).await
+            };
+            if !sent {
+                return DISCONNECTED;
+            }
+        }
+        // the server handles commands in order so once
+        // /users answers all the spam has been handled
+        probe.send("/users").await;
+        let name_taken = format!("{last_name} is already taken");
+        let mut taken = false;
+        let users = probe.wait_for(|line| {
+            taken |= line == name_taken;
+            line.starts_with("Users - ")
+        }).await;
+        match users {
+            // a random name might already be taken, in
+            // which case we can't tell what our name is
+            Wait::Got(_) if taken => CONSISTENT,
+            Wait::Got(line) => {
+                let listed = line["Users - ".len()..]
+                    .split(", ")
+                    .any(|user| user == last_name);
+                if listed {
+                    CONSISTENT
+                } else {
+                    INCONSISTENT
+                }
+            },
+            Wait::Closed => DISCONNECTED,
+            Wait::TimedOut => TIMED_OUT,
+        }
+    }
+    async fn reconnect(&self) -> Observed {
+        match Probe::connect(self.addr).await {
+            Ok(_) => GREETED,
+            Err(observed) => observed,
+        }
     }
 }
 
+#[derive(Serialize)]
+struct Report {
+    kind: AdversaryKind,
+    runs: usize,
+    expected: Option<Observed>,
+    unexpected: usize,
+    observed: BTreeMap<Observed, usize>,
+}
+
+impl Report {
+    fn new(kind: AdversaryKind) -> Self {
+        Self {
+            kind,
+            runs: 0,
+            expected: kind.expected(),
+            unexpected: 0,
+            observed: BTreeMap::new(),
+        }
+    }
+    fn observed(&mut self, observed: Observed) {
+        self.runs += 1;
+        if self.expected.is_some_and(|expected| expected != observed) {
+            self.unexpected += 1;
+        }
+        *self.observed.entry(observed).or_default() += 1;
+    }
+}
+
+impl AddAssign for Report {
+    fn add_assign(&mut self, rhs: Self) {
+        self.runs += rhs.runs;
+        self.unexpected += rhs.unexpected;
+        for (observed, count) in rhs.observed {
+            *self.observed.entry(observed).or_default() += count;
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let addr = parse_socket_addr();
+    let cli = Cli::parse();
+    let addr = cli.addr.socket_addr();
+    let scenario = Scenario::load(cli.scenario.as_deref())?;
     stdout_logging();
     let conn = match TcpStream::connect(addr).await {
         Ok(conn) => conn,
@@ -199,24 +1037,35 @@ async fn main() -> anyhow::Result<()> {
 
     let mut stats = Stats::default();
     let mut set = JoinSet::new();
+    let mut bots = 0;
+    let started = Instant::now();
+    let deadline = scenario
+        .duration_secs
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut ramp_up = scenario
+        .ramp_up_per_sec
+        .filter(|per_sec| *per_sec > 0.0)
+        .map(|per_sec| tokio::time::interval(Duration::from_secs_f64(1.0 / per_sec)));
     tracing::info!("spawning bots");
 
-    // spawn 3 simple bots
-    for _ in 0..3 {
-        let bot = Bot::new(addr, Simple::new(), 2000..=4000).await?;
-        set.spawn(bot.chat());
-    }
-
-    // spawn 3 rusty bots
-    for _ in 0..3 {
-        let bot = Bot::new(addr, Rusty::new(), 2000..=4000).await?;
-        set.spawn(bot.chat());
+    for spec in &scenario.bots {
+        for _ in 0..spec.count {
+            if let Some(ramp_up) = &mut ramp_up {
+                ramp_up.tick().await;
+            }
+            let chatter = Chatter::new(spec.clone());
+            let bot = Bot::new(addr, bots, chatter, spec.delay.clone(), deadline).await?;
+            set.spawn(bot.chat());
+            bots += 1;
+        }
     }
 
-    // spawn 100 stress-test bots
-    for _ in 0..100 {
-        let bot = Bot::new(addr, StressTest::new(), 100..=200).await?;
-        set.spawn(bot.chat());
+    let mut adversaries = JoinSet::new();
+    for spec in &scenario.adversaries {
+        for _ in 0..spec.count {
+            let adversary = Adversary::new(addr, spec.clone(), adversaries.len());
+            adversaries.spawn(adversary.attack());
+        }
     }
 
     tracing::info!("waiting for all bots to join");
@@ -230,10 +1079,78 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    tracing::info!("sent bytes - {}", stats.sent_bytes);
-    tracing::info!("got bytes  - {}", stats.got_bytes);
-    tracing::info!("sent msgs  - {}", stats.sent_msgs);
-    tracing::info!("got msgs   - {}", stats.sent_msgs);
+    let mut reports: BTreeMap<AdversaryKind, Report> = BTreeMap::new();
+    while let Some(join_result) = adversaries.join_next().await {
+        let report = join_result?;
+        match reports.get_mut(&report.kind) {
+            Some(total) => *total += report,
+            None => {
+                reports.insert(report.kind, report);
+            },
+        }
+    }
+
+    let summary = Summary::new(bots, started.elapsed(), stats, reports.into_values().collect());
+    summary.log();
+    summary.write(&cli.summary)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_histogram() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.percentile(50.0), 0);
+        assert_eq!(histogram.percentile(99.0), 0);
+    }
+
+    #[test]
+    fn exact_percentiles() {
+        let mut histogram = Histogram::default();
+        for value in 1..=100 {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.percentile(50.0), 50);
+        assert_eq!(histogram.percentile(90.0), 90);
+        assert_eq!(histogram.percentile(99.0), 99);
+        assert_eq!(histogram.percentile(100.0), 100);
+    }
+
+    #[test]
+    fn bucketed_percentiles() {
+        let mut histogram = Histogram::default();
+        for value in 1..=100_000 {
+            histogram.record(value);
+        }
+        let close = |actual: u64, expected: u64| actual.abs_diff(expected) * 100 <= expected * 2;
+        assert!(close(histogram.percentile(50.0), 50_000));
+        assert!(close(histogram.percentile(99.0), 99_000));
+        assert_eq!(histogram.max, 100_000);
+    }
+
+    #[test]
+    fn buckets_round_down() {
+        for value in [0, 1, 127, 128, 129, 1000, 12_345, 1 << 40] {
+            let bucket = Histogram::bucket(value);
+            assert!(Histogram::bucket_value(bucket) <= value);
+            assert!(Histogram::bucket_value(bucket + 1) > value);
+        }
+    }
+
+    #[test]
+    fn merged_histograms() {
+        let (mut low, mut high) = (Histogram::default(), Histogram::default());
+        for value in 1..=50 {
+            low.record(value);
+            high.record(value + 50);
+        }
+        low += high;
+        assert_eq!(low.total, 100);
+        assert_eq!(low.percentile(50.0), 50);
+        assert_eq!(low.percentile(99.0), 99);
+    }
+}
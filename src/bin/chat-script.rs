@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context};
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use tokio::net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream};
+use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+use chat_server::{connection_refused, AddrArgs};
+use chat_server::protocol::{ClientEvent, ServerEvent};
+
+// runs a chat session non-interactively, useful
+// for smoke-testing a deployment from a shell
+// script, every line the server sends is printed
+// to stdout, script errors are printed to stderr
+// and make the process exit with a non-zero code,
+// after the last command the server's remaining lines
+// are printed until it closes or goes quiet
+//
+// script syntax, one command per line:
+//   # comment
+//   send {line}    - send line to server
+//   expect {text}  - wait for a server line containing text
+//   sleep {ms}     - wait while printing server lines
+//   timeout {ms}   - set timeout for following expects
+
+const HELP_SCRIPT: &str = "\
+script commands
+  send {line} - send line to server
+  expect {text} - wait for a server line containing text
+  sleep {ms} - wait while printing server lines
+  timeout {ms} - set timeout for following expects
+  # {comment} - ignored";
+
+// how long the server can go quiet after the last command
+const DRAIN_IDLE: Duration = Duration::from_millis(500);
+
+#[derive(Parser)]
+#[command(long_about = None, after_help = HELP_SCRIPT)]
+struct Cli {
+    #[command(flatten)]
+    addr: AddrArgs,
+
+    /// Script file to run, reads script from stdin if omitted
+    script: Option<PathBuf>,
+
+    /// Default timeout for expect commands in milliseconds
+    #[arg(short, long, default_value_t = 5000)]
+    timeout: u64,
+}
+
+enum Cmd {
+    Send(String),
+    Expect(String),
+    Sleep(Duration),
+    Timeout(Duration),
+}
+
+fn parse_script(script: &str) -> anyhow::Result<Vec<(usize, Cmd)>> {
+    let mut cmds = Vec::new();
+    for (idx, line) in script.lines().enumerate() {
+        let line_num = idx + 1;
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let (cmd, arg) = match trimmed.split_once(' ') {
+            Some((cmd, arg)) => (cmd, arg),
+            None => (trimmed, ""),
+        };
+        let millis = || -> anyhow::Result<Duration> {
+            let ms = arg
+                .trim()
+                .parse()
+                .with_context(|| format!("line {line_num}: expected milliseconds, got {arg:?}"))?;
+            Ok(Duration::from_millis(ms))
+        };
+        let cmd = match cmd {
+            "send" => Cmd::Send(arg.to_owned()),
+            "expect" if !arg.is_empty() => Cmd::Expect(arg.to_owned()),
+            "expect" => bail!("line {line_num}: expect needs some text to wait for"),
+            "sleep" => Cmd::Sleep(millis()?),
+            "timeout" => Cmd::Timeout(millis()?),
+            _ => bail!("line {line_num}: unrecognized command {cmd:?}"),
+        };
+        cmds.push((line_num, cmd));
+    }
+    Ok(cmds)
+}
+
+struct Session {
+    sink: FramedWrite<OwnedWriteHalf, LinesCodec>,
+    stream: FramedRead<OwnedReadHalf, LinesCodec>,
+    // server lines not yet consumed by an expect
+    unmatched: VecDeque<String>,
+    closed: bool,
+}
+
+impl Session {
+    fn new(conn: TcpStream) -> Self {
+        let (reader, writer) = conn.into_split();
+        Self {
+            sink: FramedWrite::new(writer, LinesCodec::new()),
+            stream: FramedRead::new(reader, LinesCodec::new()),
+            unmatched: VecDeque::new(),
+            closed: false,
+        }
+    }
+    // reads the next server line, printing it and
+    // remembering it for later expects, returns
+    // false if the server closed the connection
+    async fn read_line(&mut self) -> anyhow::Result<bool> {
+        match self.stream.next().await {
+            Some(line) => {
+                let line = line?;
+                println!("{line}");
+                let ping = serde_json::from_str(&line);
+                self.unmatched.push_back(line);
+                // answer pings for scripts which switched
+                // to the structured protocol
+                if let Ok(ServerEvent::Ping { id }) = ping {
+                    self.sink.send(ClientEvent::Pong { id }.to_json()).await?;
+                }
+                Ok(true)
+            },
+            None => {
+                self.closed = true;
+                Ok(false)
+            },
+        }
+    }
+    async fn sleep(&mut self, duration: Duration) -> anyhow::Result<()> {
+        let sleep = tokio::time::sleep(duration);
+        tokio::pin!(sleep);
+        loop {
+            if self.closed {
+                (&mut sleep).await;
+                return Ok(());
+            }
+            tokio::select! {
+                result = self.read_line() => {
+                    result?;
+                },
+                _ = &mut sleep => return Ok(()),
+            }
+        }
+    }
+    // an error here just means the server went away
+    // after the script was done, so it isn't reported
+    async fn drain(&mut self, idle: Duration) {
+        while !self.closed {
+            match tokio::time::timeout(idle, self.read_line()).await {
+                Ok(Ok(_)) => (),
+                Ok(Err(_)) | Err(_) => return,
+            }
+        }
+    }
+    // true if a line containing text was consumed
+    fn consume_until(&mut self, text: &str) -> bool {
+        while let Some(line) = self.unmatched.pop_front() {
+            if line.contains(text) {
+                return true;
+            }
+        }
+        false
+    }
+    async fn expect(&mut self, text: &str, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.consume_until(text) {
+                return Ok(());
+            }
+            if self.closed {
+                bail!("server closed connection");
+            }
+            match tokio::time::timeout_at(deadline, self.read_line()).await {
+                Ok(result) => {
+                    result?;
+                },
+                Err(_) => bail!("timed out after {}ms", timeout.as_millis()),
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let addr = cli.addr.socket_addr();
+    let script = match &cli.script {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?,
+        None => {
+            let mut script = String::new();
+            io::stdin().read_to_string(&mut script)?;
+            script
+        },
+    };
+    let cmds = parse_script(&script)?;
+    let conn = match TcpStream::connect(addr).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            match err.kind() {
+                io::ErrorKind::ConnectionRefused => {
+                    println!("{}", connection_refused(addr));
+                    std::process::exit(1)
+                }
+                // got unexpected err, re-throw
+                _ => Err(err)?,
+            }
+        }
+    };
+
+    let mut session = Session::new(conn);
+    let mut timeout = Duration::from_millis(cli.timeout);
+    for (line_num, cmd) in cmds {
+        let result = match cmd {
+            Cmd::Send(line) => session.sink.send(line).await.map_err(|err| anyhow!(err)),
+            Cmd::Expect(text) => session
+                .expect(&text, timeout)
+                .await
+                .with_context(|| format!("expected {text:?}")),
+            Cmd::Sleep(duration) => session.sleep(duration).await,
+            Cmd::Timeout(duration) => {
+                timeout = duration;
+                Ok(())
+            },
+        };
+        result.with_context(|| format!("line {line_num}"))?;
+    }
+    session.drain(DRAIN_IDLE).await;
+
+    Ok(())
+}
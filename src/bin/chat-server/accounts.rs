@@ -0,0 +1,132 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{LazyLock, Mutex};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize};
+use crate::config::AccountsConfig;
+use crate::storage::{Change, Store};
+
+// registered names, which nobody else can take and which
+// can receive msgs while offline, everything is kept in
+// memory and every change is recorded to storage
+
+#[derive(Clone)]
+pub struct Account {
+    pub password_hash: String,
+    pub inbox: VecDeque<OfflineMsg>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OfflineMsg {
+    pub from: String,
+    pub text: String,
+    // unix timestamp in secs
+    pub sent: u64,
+}
+
+pub enum InboxError {
+    NotRegistered,
+    Full,
+}
+
+pub struct Accounts {
+    accounts: Mutex<HashMap<CompactString, Account>>,
+    inbox_capacity: usize,
+    store: Store,
+}
+
+// hashing is deliberately slow so
+// call these with spawn_blocking
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+// checked against when logging in to a name that isn't
+// registered so it takes as long as a wrong password
+pub static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| hash_password("not a password"));
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+}
+
+impl Accounts {
+    pub fn new(accounts: HashMap<CompactString, Account>, config: &AccountsConfig, store: Store) -> Self {
+        // rather than during someone's first /login
+        LazyLock::force(&DUMMY_HASH);
+        Self {
+            accounts: Mutex::new(accounts),
+            inbox_capacity: config.inbox_capacity,
+            store,
+        }
+    }
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(name)
+    }
+    pub fn password_hash(&self, name: &str) -> Option<String> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|account| account.password_hash.clone())
+    }
+    // returns false if the name was already registered
+    pub fn register(&self, name: &str, password_hash: String) -> bool {
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(name) {
+            return false;
+        }
+        accounts.insert(name.into(), Account {
+            password_hash: password_hash.clone(),
+            inbox: VecDeque::new(),
+        });
+        self.store.record(Change::Registered {
+            name: name.into(),
+            password_hash,
+        });
+        true
+    }
+    pub fn queue(&self, to: &str, msg: OfflineMsg) -> Result<(), InboxError> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.get_mut(to).ok_or(InboxError::NotRegistered)?;
+        if account.inbox.len() >= self.inbox_capacity {
+            return Err(InboxError::Full);
+        }
+        account.inbox.push_back(msg.clone());
+        self.store.record(Change::Queued {
+            to: to.into(),
+            msg,
+        });
+        Ok(())
+    }
+    pub fn inbox_len(&self, name: &str) -> usize {
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(name)
+            .map_or(0, |account| account.inbox.len())
+    }
+    pub fn take_inbox(&self, name: &str) -> Vec<OfflineMsg> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.get_mut(name) else {
+            return Vec::new();
+        };
+        let inbox: Vec<_> = account.inbox.drain(..).collect();
+        if !inbox.is_empty() {
+            self.store.record(Change::InboxRead {
+                name: name.into(),
+            });
+        }
+        inbox
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::io::Write;
+use chat_server::{format_utc, rolling_appender, unix_secs};
+use compact_str::CompactString;
+use tokio::sync::mpsc;
+use tracing_appender::rolling::RollingFileAppender;
+use crate::config::AuditConfig;
+
+// opt-in record of everything said and done in every room,
+// each room gets a directory of rotating files under
+// audit.dir, written from a dedicated thread like storage
+
+// room names are alphanumeric so this can't clash with one
+const DIRECT_DIR: &str = "@direct";
+
+enum Entry {
+    Line {
+        dir: CompactString,
+        sent: u64,
+        line: String,
+    },
+    // room was removed, its file can be closed
+    Close(CompactString),
+}
+
+// cheap to clone handle, does nothing if auditing is off
+#[derive(Clone)]
+pub struct Audit {
+    tx: Option<mpsc::UnboundedSender<Entry>>,
+    direct_msgs: bool,
+}
+
+impl Audit {
+    pub fn start(config: &AuditConfig) -> anyhow::Result<Self> {
+        if !config.enabled {
+            return Ok(Self { tx: None, direct_msgs: false });
+        }
+        let (dir, rotation, max_files) = (config.dir.clone(), config.rotation.rotation(), config.max_files.max(1));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("audit".to_owned())
+            .spawn(move || {
+                let mut files: HashMap<CompactString, RollingFileAppender> = HashMap::new();
+                while let Some(entry) = rx.blocking_recv() {
+                    let (room, sent, line) = match entry {
+                        Entry::Line { dir, sent, line } => (dir, sent, line),
+                        Entry::Close(room) => {
+                            files.remove(&room);
+                            continue;
+                        },
+                    };
+                    if !files.contains_key(&room) {
+                        match rolling_appender(&dir.join(room.as_str()), rotation.clone(), "audit", max_files) {
+                            Ok(file) => files.insert(room.clone(), file),
+                            Err(err) => {
+                                tracing::error!("could not open audit log for {room}: {err:#}");
+                                continue;
+                            },
+                        };
+                    }
+                    let file = files.get_mut(&room).unwrap();
+                    if let Err(err) = writeln!(file, "{} {line}", format_utc(sent)) {
+                        tracing::error!("could not write audit log for {room}: {err}");
+                    }
+                }
+            })?;
+        tracing::info!("Auditing rooms to {}", config.dir.display());
+        Ok(Self {
+            tx: Some(tx),
+            direct_msgs: config.direct_msgs,
+        })
+    }
+    // line is only rendered if auditing is on
+    pub fn room(&self, room: &str, line: impl FnOnce() -> String) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(Entry::Line {
+                dir: room.into(),
+                sent: unix_secs(),
+                line: line(),
+            });
+        }
+    }
+    pub fn direct(&self, from: &str, to: &str, text: &str) {
+        if self.direct_msgs {
+            self.room(DIRECT_DIR, || format!("{from} -> {to}: {text}"));
+        }
+    }
+    pub fn close(&self, room: &str) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(Entry::Close(room.into()));
+        }
+    }
+}
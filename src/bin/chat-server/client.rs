@@ -0,0 +1,264 @@
+use chat_server::{format_secs, unix_secs};
+use chat_server::markup;
+use chat_server::protocol::{Poll, SearchHit, ServerEvent, Whois};
+use std::borrow::Cow;
+use crate::outbound::{self, Line, Outbound, MAX_EVENT_LEN};
+use crate::search::Results;
+use crate::schedule::Reminder;
+use crate::transcript::Format;
+use crate::{ConnError, RoomMsg, Typing};
+
+// renders everything we send a user in
+// whichever protocol their client speaks
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Text,
+    Json,
+}
+
+pub struct Client {
+    pub outbound: Outbound,
+    pub protocol: Protocol,
+}
+
+impl Client {
+    pub fn new(outbound: Outbound) -> Self {
+        Self {
+            outbound,
+            protocol: Protocol::Text,
+        }
+    }
+    pub fn structured(&self) -> bool {
+        self.protocol == Protocol::Json
+    }
+    // text meant only for this user
+    pub fn info(&self, text: impl Into<Line>) -> Result<(), ConnError> {
+        match self.protocol {
+            Protocol::Text => {
+                let text = text.into();
+                match outbound::wrap_long_lines(text.as_ref()) {
+                    Cow::Borrowed(_) => self.outbound.send(text),
+                    Cow::Owned(wrapped) => self.outbound.send(wrapped),
+                }
+            },
+            Protocol::Json => {
+                let text = text.into().as_ref().to_owned();
+                self.event(&ServerEvent::Info { text })
+            },
+        }
+    }
+    // events which only make sense to structured
+    // clients, plain text users never see them
+    pub fn event(&self, event: &ServerEvent) -> Result<(), ConnError> {
+        match self.protocol {
+            Protocol::Text => Ok(()),
+            Protocol::Json => self.outbound.send(event.to_json()),
+        }
+    }
+    // sent to both the sender and recipient
+    pub fn direct(&self, from: &str, to: &str, text: &str) -> Result<(), ConnError> {
+        match self.protocol {
+            Protocol::Text => self.outbound.send(format!("{from} -> {to}: {text}")),
+            Protocol::Json => self.event(&ServerEvent::Direct {
+                from: from.to_owned(),
+                to: to.to_owned(),
+                text: text.to_owned(),
+            }),
+        }
+    }
+    pub fn whois(&self, whois: Whois) -> Result<(), ConnError> {
+        match self.protocol {
+            Protocol::Text => self.outbound.send(whois.to_text()),
+            Protocol::Json => self.event(&ServerEvent::Whois(whois)),
+        }
+    }
+    pub fn reminder(&self, reminder: Reminder) -> Result<(), ConnError> {
+        match self.protocol {
+            Protocol::Text => {
+                let ago = format_secs(unix_secs().saturating_sub(reminder.created));
+                self.outbound.send(format!("Reminder - {} (set {ago} ago)", reminder.text))
+            },
+            Protocol::Json => self.event(&ServerEvent::Reminder {
+                id: reminder.id,
+                text: reminder.text,
+                created: reminder.created,
+            }),
+        }
+    }
+    // text clients never subscribe to these, a line
+    // would interrupt whatever telnet users are typing
+    pub fn typing(&self, typing: &Typing, room: &str) -> Result<(), ConnError> {
+        let (room, name) = (room.to_owned(), typing.name.to_string());
+        match typing.typing {
+            true => self.event(&ServerEvent::TypingStart { room, name }),
+            false => self.event(&ServerEvent::TypingStop { room, name }),
+        }
+    }
+    pub fn poll(&self, poll: Poll) -> Result<(), ConnError> {
+        match self.protocol {
+            Protocol::Text => self.outbound.send(poll.to_text()),
+            Protocol::Json => self.event(&ServerEvent::Poll(poll)),
+        }
+    }
+    // the current topic of a room, not a change to it
+    pub fn topic(&self, room: &str, topic: &str) -> Result<(), ConnError> {
+        match self.protocol {
+            Protocol::Text => self.info(format!("Topic of {room} - {topic}")),
+            Protocol::Json => self.event(&ServerEvent::Topic {
+                room: room.to_owned(),
+                by: None,
+                topic: Some(topic.to_owned()),
+            }),
+        }
+    }
+    pub fn search_results(&self, query: &str, room: Option<&str>, results: Results) -> Result<(), ConnError> {
+        if self.structured() {
+            let hits = results
+                .msgs
+                .iter()
+                .map(|msg| SearchHit {
+                    id: msg.id,
+                    room: msg.room.to_string(),
+                    from: msg.from.to_string(),
+                    text: msg.text.clone(),
+                    sent: msg.sent,
+                })
+                .collect();
+            return self.event(&ServerEvent::SearchResults {
+                query: query.to_owned(),
+                room: room.map(str::to_owned),
+                total: results.total,
+                hits,
+            });
+        }
+        let scope = room.unwrap_or("every room");
+        if results.total == 0 {
+            return self.info(format!("No messages in {scope} match \"{query}\""));
+        }
+        let mut text = format!(
+            "Search results for \"{query}\" in {scope} - showing {} of {}",
+            results.msgs.len(),
+            results.total,
+        );
+        let now = unix_secs();
+        for msg in &results.msgs {
+            let ago = format_secs(now.saturating_sub(msg.sent));
+            let from = match room {
+                Some(_) => msg.from.to_string(),
+                None => format!("{} in {}", msg.from, msg.room),
+            };
+            text.push_str(&format!("\n  [{}] {from} ({ago} ago): {}", msg.id, markup::strip(&msg.text)));
+        }
+        self.info(text)
+    }
+    pub fn transcript(&self, room: &str, format: Format, text: String) -> Result<(), ConnError> {
+        if !self.structured() {
+            return self.info(text);
+        }
+        // leave plenty of room for escaping
+        let mut parts = vec![String::new()];
+        for line in text.split_inclusive('\n') {
+            let part = parts.last_mut().unwrap();
+            if !part.is_empty() && part.len() + line.len() > MAX_EVENT_LEN / 4 {
+                parts.push(String::new());
+            }
+            parts.last_mut().unwrap().push_str(line);
+        }
+        let count = parts.len();
+        for (idx, part) in parts.into_iter().enumerate() {
+            self.event(&ServerEvent::Transcript {
+                room: room.to_owned(),
+                format: format.name().to_owned(),
+                part: idx + 1,
+                parts: count,
+                text: part,
+            })?;
+        }
+        Ok(())
+    }
+    pub fn room_msg(&self, msg: &RoomMsg, me: &str, room: &str) -> Result<(), ConnError> {
+        if let RoomMsg::Msg(msg) | RoomMsg::Edited(msg) = msg {
+            // rendered once by the sender and
+            // shared by everyone in the room
+            let line = match self.protocol {
+                Protocol::Text => msg.line(),
+                Protocol::Json => msg.json(),
+            };
+            return self.outbound.send(line);
+        }
+        if self.structured() {
+            let room = room.to_owned();
+            let event = match msg {
+                RoomMsg::Joined(peer) => ServerEvent::Joined { room, name: peer.to_string() },
+                RoomMsg::Left(peer) => ServerEvent::Left { room, name: peer.to_string() },
+                RoomMsg::Renamed { from, to } => ServerEvent::Renamed {
+                    room,
+                    from: from.to_string(),
+                    to: to.to_string(),
+                },
+                RoomMsg::Away { name, away } => ServerEvent::Away {
+                    room,
+                    name: name.to_string(),
+                    reason: away.reason.as_ref().map(|reason| reason.to_string()),
+                },
+                RoomMsg::Back(peer) => ServerEvent::Back { room, name: peer.to_string() },
+                RoomMsg::Deleted { id, by } => ServerEvent::Deleted {
+                    room,
+                    id: *id,
+                    by: by.to_string(),
+                },
+                RoomMsg::Reacted { id, reactions } => ServerEvent::Reactions {
+                    room,
+                    id: *id,
+                    reactions: reactions.to_vec(),
+                },
+                RoomMsg::Topic { by, topic } => ServerEvent::Topic {
+                    room,
+                    by: Some(by.to_string()),
+                    topic: topic.as_ref().map(|topic| topic.to_string()),
+                },
+                RoomMsg::Moderated(note) => ServerEvent::Moderated {
+                    room,
+                    note: note.to_string(),
+                },
+                RoomMsg::Poll(poll) => ServerEvent::Poll(Poll::clone(poll)),
+                RoomMsg::Msg(_) | RoomMsg::Edited(_) => unreachable!(),
+            };
+            return self.event(&event);
+        }
+        let text = match msg {
+            RoomMsg::Joined(peer) if *peer == me => format!("You joined {room}"),
+            RoomMsg::Joined(peer) => format!("{peer} joined"),
+            RoomMsg::Left(peer) if *peer == me => format!("You left {room}"),
+            RoomMsg::Left(peer) => format!("{peer} left"),
+            RoomMsg::Renamed { from, to } => format!("{from} is now {to}"),
+            RoomMsg::Away { name, away } if *name == me && away.auto => {
+                "You are away, send anything to come back".to_owned()
+            },
+            RoomMsg::Away { name, .. } if *name == me => "You are away, send /back to come back".to_owned(),
+            RoomMsg::Away { name, away } => format!("{name} is {}", away.describe()),
+            RoomMsg::Back(peer) if *peer == me => "You are back".to_owned(),
+            RoomMsg::Back(peer) => format!("{peer} is back"),
+            RoomMsg::Deleted { id, by } => format!("[{id}] was deleted by {by}"),
+            RoomMsg::Reacted { id, reactions } if reactions.is_empty() => format!("[{id}] has no reactions"),
+            RoomMsg::Reacted { id, reactions } => {
+                let counts: Vec<String> = reactions
+                    .iter()
+                    .map(|reaction| format!("{} {}", reaction.emoji, reaction.count))
+                    .collect();
+                format!("[{id}] reactions - {}", counts.join(", "))
+            },
+            RoomMsg::Topic { by, topic: Some(topic) } => format!("{by} set the topic - {topic}"),
+            RoomMsg::Topic { by, topic: None } => format!("{by} cleared the topic"),
+            RoomMsg::Moderated(note) => format!("Moderation - {note}"),
+            RoomMsg::Poll(poll) => poll.to_text(),
+            RoomMsg::Msg(_) | RoomMsg::Edited(_) => unreachable!(),
+        };
+        // polls and reaction lists can run long
+        match outbound::wrap_long_lines(&text) {
+            Cow::Borrowed(_) => self.outbound.send(text),
+            Cow::Owned(wrapped) => self.outbound.send(wrapped),
+        }
+    }
+}
@@ -0,0 +1,1652 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use chat_server::{format_secs, token_eq, unix_secs, valid_name};
+use compact_str::CompactString;
+use futures::future::BoxFuture;
+use crate::accounts::{self, InboxError, OfflineMsg};
+use crate::list::{self, ListArgs};
+use crate::polls::{self, PollError, MAX_OPEN_POLLS};
+use crate::schedule::{self, Kind, ScheduleError, MAX_PENDING};
+use crate::search;
+use crate::transcript::{self, Format};
+use crate::{Away, ChatMsg, ConnError, DirectError, EditError, ReactError, RoomMsg, Session, UserMsg};
+use crate::{MAIN, MAX_EMOJI_LEN, MAX_REACTIONS, MAX_REACTIONS_PER_USER, MIN_PASSWORD_LEN};
+
+// every slash command is a type implementing Command and
+// is looked up by its exact name or one of its aliases,
+// /help is generated from whatever is registered
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Permission {
+    Anyone,
+    // logged in to a registered name
+    Registered,
+    Admin,
+}
+
+impl Permission {
+    fn allows(self, logged_in: bool, admin: bool) -> bool {
+        match self {
+            Permission::Anyone => true,
+            Permission::Registered => logged_in,
+            Permission::Admin => admin,
+        }
+    }
+}
+
+pub enum Flow {
+    Continue,
+    // disconnect the user
+    Quit,
+}
+
+pub type CommandResult = Result<Flow, ConnError>;
+
+pub trait Command: Send + Sync + 'static {
+    type Args: Send;
+    fn name(&self) -> &str;
+    fn aliases(&self) -> &[&str] {
+        &[]
+    }
+    fn permission(&self) -> Permission {
+        Permission::Anyone
+    }
+    // what follows the name, e.g. "{name} {msg}"
+    fn usage(&self) -> &str {
+        ""
+    }
+    // a line for /help
+    fn summary(&self) -> &str;
+    // more for /help {command}
+    fn details(&self) -> &str {
+        ""
+    }
+    // Err(None) just shows the usage, Err(Some(reason))
+    // shows the reason followed by the usage
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>>;
+    fn run(&self, session: &mut Session, args: Self::Args) -> impl Future<Output = CommandResult> + Send;
+}
+
+// object safe side of Command so commands
+// with different args can share a registry
+trait DynCommand: Send + Sync {
+    fn name(&self) -> &str;
+    fn aliases(&self) -> &[&str];
+    fn permission(&self) -> Permission;
+    fn usage(&self) -> &str;
+    fn summary(&self) -> &str;
+    fn details(&self) -> &str;
+    fn run_line<'a>(&'a self, session: &'a mut Session, args: &'a str) -> BoxFuture<'a, CommandResult>;
+}
+
+impl<C: Command> DynCommand for C {
+    fn name(&self) -> &str {
+        Command::name(self)
+    }
+    fn aliases(&self) -> &[&str] {
+        Command::aliases(self)
+    }
+    fn permission(&self) -> Permission {
+        Command::permission(self)
+    }
+    fn usage(&self) -> &str {
+        Command::usage(self)
+    }
+    fn summary(&self) -> &str {
+        Command::summary(self)
+    }
+    fn details(&self) -> &str {
+        Command::details(self)
+    }
+    fn run_line<'a>(&'a self, session: &'a mut Session, args: &'a str) -> BoxFuture<'a, CommandResult> {
+        Box::pin(async move {
+            match self.parse(args) {
+                Ok(args) => self.run(session, args).await,
+                Err(reason) => {
+                    let usage = format!("/{} {}", Command::name(self), Command::usage(self));
+                    let usage = usage.trim_end();
+                    match reason {
+                        Some(reason) => session.client.info(format!("{reason}, usage: {usage}"))?,
+                        None => session.client.info(format!("Usage: {usage}"))?,
+                    }
+                    Ok(Flow::Continue)
+                },
+            }
+        })
+    }
+}
+
+pub struct Registry {
+    commands: Vec<Box<dyn DynCommand>>,
+    // names and aliases to indexes into commands
+    lookup: HashMap<String, usize>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            commands: Vec::new(),
+            lookup: HashMap::new(),
+        }
+    }
+    // listed by /help in the order they're added
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry.add(HelpCommand);
+        registry.add(NameCommand);
+        registry.add(RegisterCommand);
+        registry.add(LoginCommand);
+        registry.add(InboxCommand);
+        registry.add(RoomsCommand);
+        registry.add(JoinCommand);
+        registry.add(UsersCommand);
+        registry.add(TopicCommand);
+        registry.add(KickCommand);
+        registry.add(BanCommand);
+        registry.add(UnbanCommand);
+        registry.add(MsgCommand);
+        registry.add(MeCommand);
+        registry.add(ReplyCommand);
+        registry.add(ReactCommand);
+        registry.add(EditCommand);
+        registry.add(DeleteCommand);
+        registry.add(PollCommand);
+        registry.add(VoteCommand);
+        registry.add(RemindCommand);
+        registry.add(ScheduleCommand);
+        registry.add(SearchCommand);
+        registry.add(ExportCommand);
+        registry.add(AwayCommand);
+        registry.add(BackCommand);
+        registry.add(WhoCommand);
+        registry.add(WhoisCommand);
+        registry.add(AdminCommand);
+        registry.add(ArchiveCommand);
+        registry.add(QuitCommand);
+        registry
+    }
+    pub fn add(&mut self, command: impl Command) {
+        let idx = self.commands.len();
+        let names = std::iter::once(Command::name(&command)).chain(Command::aliases(&command).iter().copied());
+        for name in names {
+            let prev = self.lookup.insert(name.to_owned(), idx);
+            assert!(prev.is_none(), "/{name} was registered twice");
+        }
+        self.commands.push(Box::new(command));
+    }
+    fn get(&self, name: &str) -> Option<&dyn DynCommand> {
+        self.lookup.get(name).map(|&idx| &*self.commands[idx])
+    }
+    // admin commands are only listed for admins
+    pub fn help(&self, admin: bool) -> String {
+        let mut help = String::from("Server commands");
+        for command in &self.commands {
+            if command.permission() == Permission::Admin && !admin {
+                continue;
+            }
+            help.push_str("\n  ");
+            help.push_str(&usage_line(command.as_ref()));
+        }
+        help.push_str("\nMessages can use *bold*, _italic_ and `code`");
+        help
+    }
+    fn details(&self, name: &str) -> Option<String> {
+        let command = self.get(name)?;
+        let mut details = usage_line(command);
+        if !command.aliases().is_empty() {
+            let aliases: Vec<String> = command.aliases().iter().map(|alias| format!("/{alias}")).collect();
+            details.push_str(&format!("\n  aliases - {}", aliases.join(", ")));
+        }
+        match command.permission() {
+            Permission::Anyone => (),
+            Permission::Registered => details.push_str("\n  only for registered users"),
+            Permission::Admin => details.push_str("\n  only for admins"),
+        }
+        for line in command.details().lines() {
+            details.push_str("\n  ");
+            details.push_str(line);
+        }
+        Some(details)
+    }
+    // line is everything after the /
+    pub async fn run(&self, session: &mut Session, line: &str) -> CommandResult {
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        let Some(command) = self.get(name) else {
+            session.client.info(format!("Unrecognized command /{name}, try /help"))?;
+            return Ok(Flow::Continue);
+        };
+        if !command.permission().allows(session.logged_in, session.admin()) {
+            let denied = match command.permission() {
+                Permission::Registered => format!("Only registered users can use /{name}, see /register and /login"),
+                _ => format!("Only admins can use /{name}"),
+            };
+            session.client.info(denied)?;
+            return Ok(Flow::Continue);
+        }
+        command.run_line(session, args).await
+    }
+}
+
+fn usage_line(command: &dyn DynCommand) -> String {
+    match command.usage() {
+        "" => format!("/{} - {}", command.name(), command.summary()),
+        usage => format!("/{} {usage} - {}", command.name(), command.summary()),
+    }
+}
+
+// splits "{first} {rest}" where both are required
+fn split_arg(args: &str) -> Option<(&str, &str)> {
+    let (first, rest) = args.trim_start().split_once(' ')?;
+    let rest = rest.trim();
+    (!rest.is_empty()).then_some((first, rest))
+}
+
+fn parse_id(id: &str) -> Result<u64, Option<String>> {
+    id.parse().map_err(|_| Some(format!("{id} is not a message id")))
+}
+
+fn no_args(args: &str) -> Result<(), Option<String>> {
+    match args.trim().is_empty() {
+        true => Ok(()),
+        false => Err(None),
+    }
+}
+
+// ACCOUNTS //
+
+struct HelpCommand;
+
+impl Command for HelpCommand {
+    type Args = Option<String>;
+    fn name(&self) -> &str {
+        "help"
+    }
+    fn usage(&self) -> &str {
+        "{command}"
+    }
+    fn summary(&self) -> &str {
+        "print this message, or more about a command"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let command = args.trim().trim_start_matches('/');
+        Ok((!command.is_empty()).then(|| command.to_owned()))
+    }
+    async fn run(&self, session: &mut Session, command: Self::Args) -> CommandResult {
+        let commands = session.state.commands.clone();
+        let help = match command {
+            Some(command) => commands
+                .details(&command)
+                .unwrap_or_else(|| format!("No command /{command}, try /help")),
+            None => commands.help(session.admin()),
+        };
+        session.client.info(help)?;
+        Ok(Flow::Continue)
+    }
+}
+
+struct NameCommand;
+
+impl Command for NameCommand {
+    type Args = CompactString;
+    fn name(&self) -> &str {
+        "name"
+    }
+    fn usage(&self) -> &str {
+        "{name}"
+    }
+    fn summary(&self) -> &str {
+        "change name"
+    }
+    fn details(&self) -> &str {
+        "names are 2 - 20 alphanumeric chars, registered names need /login"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let name = args.split_ascii_whitespace().next();
+        match valid_name(name) {
+            true => Ok(name.unwrap().into()),
+            false => Err(Some("Name must be 2 - 20 alphanumeric chars".to_owned())),
+        }
+    }
+    async fn run(&self, session: &mut Session, new_name: Self::Args) -> CommandResult {
+        if session.state.bots.contains(&new_name) {
+            session.client.info(format!("{new_name} is a bot"))?;
+            return Ok(Flow::Continue);
+        }
+        if session.state.accounts.is_registered(&new_name) {
+            session.client.info(format!("{new_name} is registered, use /login {new_name} {{password}}"))?;
+            return Ok(Flow::Continue);
+        }
+        if session.rename(new_name.clone()) {
+            session.logged_in = false;
+        } else {
+            session.client.info(format!("{new_name} is already taken"))?;
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct RegisterCommand;
+
+impl Command for RegisterCommand {
+    type Args = String;
+    fn name(&self) -> &str {
+        "register"
+    }
+    fn usage(&self) -> &str {
+        "{password}"
+    }
+    fn summary(&self) -> &str {
+        "register your current name"
+    }
+    fn details(&self) -> &str {
+        "nobody else can take a registered name and it can receive\ndirect messages while you're offline, see /inbox"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let password = args.trim();
+        if password.is_empty() {
+            return Err(None);
+        }
+        // rather than quietly registering only the first word
+        if password.contains(char::is_whitespace) {
+            return Err(Some("Password can't contain spaces".to_owned()));
+        }
+        if password.len() < MIN_PASSWORD_LEN {
+            return Err(Some(format!("Password must be at least {MIN_PASSWORD_LEN} chars")));
+        }
+        Ok(password.to_owned())
+    }
+    async fn run(&self, session: &mut Session, password: Self::Args) -> CommandResult {
+        let Session { state, client, name, addr, .. } = session;
+        if state.bots.contains(name) {
+            client.info(format!("{name} is a bot"))?;
+            return Ok(Flow::Continue);
+        }
+        if state.accounts.is_registered(name) {
+            client.info(format!("{name} is already registered"))?;
+            return Ok(Flow::Continue);
+        }
+        let Ok(hash) = tokio::task::spawn_blocking(move || accounts::hash_password(&password)).await else {
+            client.info("Could not register, try again")?;
+            return Ok(Flow::Continue);
+        };
+        if state.accounts.register(name, hash) {
+            tracing::info!("{addr} registered {name}");
+            session.logged_in = true;
+            session.client.info(format!("Registered {0}, next time use /login {0} {{password}}", session.name))?;
+        } else {
+            client.info(format!("{name} is already registered"))?;
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct LoginCommand;
+
+impl Command for LoginCommand {
+    type Args = (CompactString, String);
+    fn name(&self) -> &str {
+        "login"
+    }
+    fn usage(&self) -> &str {
+        "{name} {password}"
+    }
+    fn summary(&self) -> &str {
+        "log in as registered name"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let mut args = args.split_ascii_whitespace();
+        match (args.next(), args.next()) {
+            (Some(name), Some(password)) => Ok((name.into(), password.to_owned())),
+            _ => Err(None),
+        }
+    }
+    async fn run(&self, session: &mut Session, (login_name, password): Self::Args) -> CommandResult {
+        if session.state.bots.contains(&login_name) {
+            session.client.info(format!("{login_name} is a bot"))?;
+            return Ok(Flow::Continue);
+        }
+        // names that aren't registered are hashed and
+        // counted like wrong passwords so guessing is slow
+        let hash = session.state.accounts.password_hash(&login_name);
+        let registered = hash.is_some();
+        let hash = hash.unwrap_or_else(|| accounts::DUMMY_HASH.clone());
+        let verified = tokio::task::spawn_blocking(move || accounts::verify_password(&password, &hash)).await;
+        if !registered || !verified.unwrap_or(false) {
+            tracing::warn!("{} failed to log in as {login_name}", session.addr);
+            return session.auth_failed("Wrong name or password").await;
+        }
+        if login_name != session.name && !session.rename(login_name.clone()) {
+            session.client.info(format!("{login_name} is already online"))?;
+            return Ok(Flow::Continue);
+        }
+        session.logged_in = true;
+        let unread = session.state.accounts.inbox_len(&session.name);
+        if unread > 0 {
+            session.client.info(format!("Logged in, you have {unread} offline messages, read them with /inbox"))?;
+        } else {
+            session.client.info("Logged in")?;
+        }
+        for reminder in session.state.scheduler.missed(&session.name) {
+            session.client.reminder(reminder)?;
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct InboxCommand;
+
+impl Command for InboxCommand {
+    type Args = ();
+    fn name(&self) -> &str {
+        "inbox"
+    }
+    fn permission(&self) -> Permission {
+        Permission::Registered
+    }
+    fn summary(&self) -> &str {
+        "read msgs sent while you were offline"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        no_args(args)
+    }
+    async fn run(&self, session: &mut Session, _: Self::Args) -> CommandResult {
+        let inbox = session.state.accounts.take_inbox(&session.name);
+        if inbox.is_empty() {
+            session.client.info("Inbox is empty")?;
+            return Ok(Flow::Continue);
+        }
+        let now = unix_secs();
+        let mut inbox_msg = format!("Inbox - {} messages", inbox.len());
+        for msg in inbox {
+            let ago = format_secs(now.saturating_sub(msg.sent));
+            inbox_msg.push_str(&format!("\n  {} ({ago} ago): {}", msg.from, msg.text));
+        }
+        session.client.info(inbox_msg)?;
+        Ok(Flow::Continue)
+    }
+}
+
+struct AdminCommand;
+
+impl Command for AdminCommand {
+    type Args = Option<String>;
+    fn name(&self) -> &str {
+        "admin"
+    }
+    fn usage(&self) -> &str {
+        "{password}"
+    }
+    fn summary(&self) -> &str {
+        "become an admin"
+    }
+    fn details(&self) -> &str {
+        "admins can delete any message, set any room's topic,\nsee users' addresses with /whois and use /archive"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        Ok(args.split_ascii_whitespace().next().map(str::to_owned))
+    }
+    async fn run(&self, session: &mut Session, password: Self::Args) -> CommandResult {
+        let Some(expected) = session.state.config.admin.password.as_deref() else {
+            session.client.info("Admin access is disabled")?;
+            return Ok(Flow::Continue);
+        };
+        let (addr, name) = (session.addr, &session.name);
+        if !password.is_some_and(|password| token_eq(&password, expected)) {
+            tracing::warn!("{addr} failed to become an admin, name {name}");
+            return session.auth_failed("Wrong password").await;
+        }
+        tracing::info!("{addr} became an admin, name {name}");
+        session.state.names.update(name, |user| user.admin = true);
+        session.client.info("You are now an admin")?;
+        Ok(Flow::Continue)
+    }
+}
+
+struct QuitCommand;
+
+impl Command for QuitCommand {
+    type Args = ();
+    fn name(&self) -> &str {
+        "quit"
+    }
+    fn aliases(&self) -> &[&str] {
+        &["exit"]
+    }
+    fn summary(&self) -> &str {
+        "quit server"
+    }
+    fn parse(&self, _args: &str) -> Result<Self::Args, Option<String>> {
+        Ok(())
+    }
+    async fn run(&self, _session: &mut Session, _: Self::Args) -> CommandResult {
+        Ok(Flow::Quit)
+    }
+}
+
+// ROOMS //
+
+struct RoomsCommand;
+
+impl Command for RoomsCommand {
+    type Args = ListArgs;
+    fn name(&self) -> &str {
+        "rooms"
+    }
+    fn usage(&self) -> &str {
+        "{pattern} {page}"
+    }
+    fn summary(&self) -> &str {
+        "list rooms, both are optional"
+    }
+    fn details(&self) -> &str {
+        "* in the pattern matches anything and ? any one char, e.g. /rooms rust* 2"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        ListArgs::parse(args).map_err(|err| Some(err.to_owned()))
+    }
+    async fn run(&self, session: &mut Session, args: Self::Args) -> CommandResult {
+        let rooms_list: Vec<String> = session
+            .state
+            .rooms
+            .list()
+            .into_iter()
+            .filter(|(room, _)| args.matches(room))
+            .map(|(room, count)| format!("{room} ({count})"))
+            .collect();
+        session.client.info(list::render_page("Rooms", &rooms_list, &args))?;
+        Ok(Flow::Continue)
+    }
+}
+
+struct JoinCommand;
+
+impl Command for JoinCommand {
+    type Args = CompactString;
+    fn name(&self) -> &str {
+        "join"
+    }
+    fn usage(&self) -> &str {
+        "{room}"
+    }
+    fn summary(&self) -> &str {
+        "joins room"
+    }
+    fn details(&self) -> &str {
+        "rooms are created when someone joins them, whoever\ncreates a room while logged in becomes its operator"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let room = args.split_ascii_whitespace().next();
+        match valid_name(room) {
+            true => Ok(room.unwrap().into()),
+            false => Err(Some("Room must be 2 - 20 alphanumeric chars".to_owned())),
+        }
+    }
+    async fn run(&self, session: &mut Session, room: Self::Args) -> CommandResult {
+        if room == session.room_name {
+            session.client.info(format!("You are in {room}"))?;
+            return Ok(Flow::Continue);
+        }
+        if session.state.rooms.is_banned(&room, &session.name) {
+            session.client.info(format!("You are banned from {room}"))?;
+            return Ok(Flow::Continue);
+        }
+        session.join(room)?;
+        Ok(Flow::Continue)
+    }
+}
+
+struct UsersCommand;
+
+impl Command for UsersCommand {
+    type Args = ListArgs;
+    fn name(&self) -> &str {
+        "users"
+    }
+    fn usage(&self) -> &str {
+        "{pattern} {page}"
+    }
+    fn summary(&self) -> &str {
+        "list users in room, both are optional"
+    }
+    fn details(&self) -> &str {
+        "* in the pattern matches anything and ? any one char, e.g. /users b*"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        ListArgs::parse(args).map_err(|err| Some(err.to_owned()))
+    }
+    async fn run(&self, session: &mut Session, args: Self::Args) -> CommandResult {
+        let Session { state, client, room_name, .. } = session;
+        let users_list: Vec<String> = state
+            .rooms
+            .list_users(room_name)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|user| args.matches(user))
+            .map(|user| match state.names.away(&user) {
+                Some(away) => format!("{user} ({})", away.describe()),
+                None => user.to_string(),
+            })
+            .collect();
+        client.info(list::render_page("Users", &users_list, &args))?;
+        Ok(Flow::Continue)
+    }
+}
+
+struct TopicCommand;
+
+impl Command for TopicCommand {
+    type Args = String;
+    fn name(&self) -> &str {
+        "topic"
+    }
+    fn usage(&self) -> &str {
+        "{text}"
+    }
+    fn summary(&self) -> &str {
+        "show room topic, operators can set it, - clears it"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        Ok(args.trim().to_owned())
+    }
+    async fn run(&self, session: &mut Session, topic: Self::Args) -> CommandResult {
+        let room_name = &session.room_name;
+        if topic.is_empty() {
+            match session.state.rooms.topic(room_name) {
+                Some(topic) => session.client.topic(room_name, &topic)?,
+                None => session.client.info(format!("{room_name} has no topic, operators can set one with /topic {{text}}"))?,
+            }
+            return Ok(Flow::Continue);
+        }
+        let admin = session.admin();
+        if !admin && !session.state.rooms.is_operator(room_name, &session.name) {
+            session.client.info("Only room operators can set the topic")?;
+            return Ok(Flow::Continue);
+        }
+        // "-" clears the topic
+        let topic = match topic.as_str() {
+            "-" => None,
+            _ => match session.moderate(topic)? {
+                Some(topic) => Some(topic),
+                None => return Ok(Flow::Continue),
+            },
+        };
+        match session.state.rooms.set_topic(room_name, &session.name, topic.as_deref(), admin) {
+            Ok(_) | Err(EditError::NotFound) => (),
+            Err(EditError::NotAllowed) => session.client.info("Only room operators can set the topic")?,
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct KickCommand;
+
+impl Command for KickCommand {
+    type Args = CompactString;
+    fn name(&self) -> &str {
+        "kick"
+    }
+    fn usage(&self) -> &str {
+        "{name}"
+    }
+    fn summary(&self) -> &str {
+        "send user back to main, for room operators"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        parse_name(args)
+    }
+    async fn run(&self, session: &mut Session, name: Self::Args) -> CommandResult {
+        let admin = session.admin();
+        let Session { state, client, room_name, name: by, .. } = session;
+        match state.rooms.kick(&state.names, room_name, by, &name, admin) {
+            Ok(true) => client.info(format!("Kicked {name} from {room_name}"))?,
+            Ok(false) => client.info(format!("{name} isn't in {room_name}"))?,
+            Err(_) => client.info(kick_not_allowed(room_name))?,
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct BanCommand;
+
+impl Command for BanCommand {
+    type Args = Option<CompactString>;
+    fn name(&self) -> &str {
+        "ban"
+    }
+    fn usage(&self) -> &str {
+        "{name}"
+    }
+    fn summary(&self) -> &str {
+        "kick user and keep them out, for room operators"
+    }
+    fn details(&self) -> &str {
+        "/ban on its own lists the room's bans, /unban {name} lifts one\nbans are by name so they're only lasting for registered names"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        match args.trim().is_empty() {
+            true => Ok(None),
+            false => parse_name(args).map(Some),
+        }
+    }
+    async fn run(&self, session: &mut Session, name: Self::Args) -> CommandResult {
+        let admin = session.admin();
+        let Session { state, client, room_name, name: by, .. } = session;
+        let Some(name) = name else {
+            let bans = state.rooms.bans(room_name);
+            match bans.is_empty() {
+                true => client.info(format!("Nobody is banned from {room_name}"))?,
+                false => client.info(format!("Banned from {room_name} - {}", bans.join(", ")))?,
+            }
+            return Ok(Flow::Continue);
+        };
+        match state.rooms.ban(&state.names, room_name, by, &name, true, admin) {
+            Ok(true) => client.info(format!("Banned {name} from {room_name}"))?,
+            Ok(false) => client.info(format!("{name} is already banned from {room_name}"))?,
+            Err(_) => client.info(kick_not_allowed(room_name))?,
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct UnbanCommand;
+
+impl Command for UnbanCommand {
+    type Args = CompactString;
+    fn name(&self) -> &str {
+        "unban"
+    }
+    fn usage(&self) -> &str {
+        "{name}"
+    }
+    fn summary(&self) -> &str {
+        "let a banned user back in, for room operators"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        parse_name(args)
+    }
+    async fn run(&self, session: &mut Session, name: Self::Args) -> CommandResult {
+        let admin = session.admin();
+        let Session { state, client, room_name, name: by, .. } = session;
+        match state.rooms.ban(&state.names, room_name, by, &name, false, admin) {
+            Ok(true) => client.info(format!("Unbanned {name} from {room_name}"))?,
+            Ok(false) => client.info(format!("{name} isn't banned from {room_name}"))?,
+            Err(_) => client.info(kick_not_allowed(room_name))?,
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+fn parse_name(args: &str) -> Result<CompactString, Option<String>> {
+    let name = args.split_ascii_whitespace().next().ok_or(None)?;
+    match valid_name(Some(name)) {
+        true => Ok(name.into()),
+        false => Err(Some(format!("{name} is not a valid name"))),
+    }
+}
+
+fn kick_not_allowed(room_name: &str) -> String {
+    match room_name {
+        MAIN => format!("Nobody can be kicked or banned from {MAIN}"),
+        _ => "Only room operators can kick or ban, and only admins can kick or ban operators".to_owned(),
+    }
+}
+
+struct WhoCommand;
+
+impl Command for WhoCommand {
+    type Args = ListArgs;
+    fn name(&self) -> &str {
+        "who"
+    }
+    fn usage(&self) -> &str {
+        "{pattern} {page}"
+    }
+    fn summary(&self) -> &str {
+        "list users in every room, both are optional"
+    }
+    fn details(&self) -> &str {
+        "* in the pattern matches anything and ? any one char, e.g. /who b*"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        ListArgs::parse(args).map_err(|err| Some(err.to_owned()))
+    }
+    async fn run(&self, session: &mut Session, args: Self::Args) -> CommandResult {
+        let users_list: Vec<String> = session
+            .state
+            .names
+            .list()
+            .into_iter()
+            .filter(|(user, _)| args.matches(user))
+            .map(|(user, room)| format!("{user} ({room})"))
+            .collect();
+        session.client.info(list::render_page("Users", &users_list, &args))?;
+        Ok(Flow::Continue)
+    }
+}
+
+struct WhoisCommand;
+
+impl Command for WhoisCommand {
+    type Args = CompactString;
+    fn name(&self) -> &str {
+        "whois"
+    }
+    fn usage(&self) -> &str {
+        "{name}"
+    }
+    fn summary(&self) -> &str {
+        "show details about user"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        args.split_ascii_whitespace().next().map(CompactString::from).ok_or(None)
+    }
+    async fn run(&self, session: &mut Session, target: Self::Args) -> CommandResult {
+        let admin = session.admin();
+        match session.state.names.get(&target) {
+            Some(user) => session.client.whois(user.whois(&target, admin))?,
+            None => session.client.info(format!("{target} is not online"))?,
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct AwayCommand;
+
+impl Command for AwayCommand {
+    type Args = Option<String>;
+    fn name(&self) -> &str {
+        "away"
+    }
+    fn aliases(&self) -> &[&str] {
+        &["afk"]
+    }
+    fn usage(&self) -> &str {
+        "{reason}"
+    }
+    fn summary(&self) -> &str {
+        "mark yourself as away, reason is optional"
+    }
+    fn details(&self) -> &str {
+        "you stay away until /back, anyone who messages\nor mentions you is told you're away"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let reason = args.trim();
+        Ok((!reason.is_empty()).then(|| reason.to_owned()))
+    }
+    async fn run(&self, session: &mut Session, reason: Self::Args) -> CommandResult {
+        let away = Away {
+            reason: reason.map(Arc::from),
+            auto: false,
+        };
+        session.state.names.set_away(&session.name, Some(away.clone()));
+        let _ = session.room_tx.send(RoomMsg::Away {
+            name: session.name.clone(),
+            away,
+        });
+        Ok(Flow::Continue)
+    }
+}
+
+struct BackCommand;
+
+impl Command for BackCommand {
+    type Args = ();
+    fn name(&self) -> &str {
+        "back"
+    }
+    fn summary(&self) -> &str {
+        "mark yourself as back"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        no_args(args)
+    }
+    async fn run(&self, session: &mut Session, _: Self::Args) -> CommandResult {
+        if session.state.names.away(&session.name).is_none() {
+            session.client.info("You are not away")?;
+            return Ok(Flow::Continue);
+        }
+        session.state.names.set_away(&session.name, None);
+        let _ = session.room_tx.send(RoomMsg::Back(session.name.clone()));
+        Ok(Flow::Continue)
+    }
+}
+
+// MESSAGES //
+
+struct MsgCommand;
+
+impl Command for MsgCommand {
+    type Args = (CompactString, String);
+    fn name(&self) -> &str {
+        "msg"
+    }
+    fn aliases(&self) -> &[&str] {
+        &["dm"]
+    }
+    fn usage(&self) -> &str {
+        "{name} {msg}"
+    }
+    fn summary(&self) -> &str {
+        "send direct message"
+    }
+    fn details(&self) -> &str {
+        "registered users who are offline get it when they log in"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let (to, text) = split_arg(args).ok_or(None)?;
+        Ok((to.into(), text.to_owned()))
+    }
+    async fn run(&self, session: &mut Session, (to, text): Self::Args) -> CommandResult {
+        let Session { state, client, name, .. } = session;
+        let text = Arc::<str>::from(text);
+        let direct = UserMsg::Direct {
+            from: name.clone(),
+            text: text.clone(),
+        };
+        match state.names.direct(&to, direct) {
+            Ok(away) => {
+                state.audit.direct(name, &to, &text);
+                client.direct(name, &to, &text)?;
+                if let Some(away) = away {
+                    client.info(format!("{to} is {}", away.describe()))?;
+                }
+            },
+            Err(DirectError::NotOnline) => {
+                let offline = OfflineMsg {
+                    from: name.to_string(),
+                    text: text.to_string(),
+                    sent: unix_secs(),
+                };
+                match state.accounts.queue(&to, offline) {
+                    Ok(_) => {
+                        state.audit.direct(name, &to, &text);
+                        client.info(format!("{to} is offline, they'll get your message when they log in"))?;
+                    },
+                    Err(InboxError::NotRegistered) => client.info(format!("{to} is not online"))?,
+                    Err(InboxError::Full) => client.info(format!("{to} is offline and their inbox is full"))?,
+                }
+            },
+            Err(DirectError::Full) => {
+                client.info(format!("{to} is not keeping up with their messages, try again later"))?;
+            },
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct MeCommand;
+
+impl Command for MeCommand {
+    type Args = String;
+    fn name(&self) -> &str {
+        "me"
+    }
+    fn usage(&self) -> &str {
+        "{action}"
+    }
+    fn summary(&self) -> &str {
+        "send action, e.g. /me waves"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let action = args.trim();
+        match action.is_empty() {
+            true => Err(None),
+            false => Ok(action.to_owned()),
+        }
+    }
+    async fn run(&self, session: &mut Session, action: Self::Args) -> CommandResult {
+        if let Some(action) = session.moderate(action)? {
+            let _ = session.post(ChatMsg::action(&session.room_name, &session.name, action));
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct ReplyCommand;
+
+impl Command for ReplyCommand {
+    type Args = (u64, String);
+    fn name(&self) -> &str {
+        "reply"
+    }
+    fn usage(&self) -> &str {
+        "{id} {msg}"
+    }
+    fn summary(&self) -> &str {
+        "reply to message"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let (id, text) = split_arg(args).ok_or(None)?;
+        Ok((parse_id(id)?, text.to_owned()))
+    }
+    async fn run(&self, session: &mut Session, (id, text): Self::Args) -> CommandResult {
+        let Some(text) = session.moderate(text)? else {
+            return Ok(Flow::Continue);
+        };
+        let reply = ChatMsg::reply(&session.room_name, &session.name, text, Some(id));
+        if session.post(reply).is_err() {
+            session.client.info(format!("No recent message {id} in {}", session.room_name))?;
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct ReactCommand;
+
+impl Command for ReactCommand {
+    type Args = (u64, CompactString);
+    fn name(&self) -> &str {
+        "react"
+    }
+    fn usage(&self) -> &str {
+        "{id} {emoji}"
+    }
+    fn summary(&self) -> &str {
+        "react to message, again to take it back"
+    }
+    fn details(&self) -> &str {
+        "messages can have up to 12 different emojis,\nand you can use up to 3 of them on each"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let mut args = args.split_ascii_whitespace();
+        let (Some(id), Some(emoji), None) = (args.next(), args.next(), args.next()) else {
+            return Err(None);
+        };
+        if emoji.len() > MAX_EMOJI_LEN {
+            return Err(Some(format!("Emoji can be up to {MAX_EMOJI_LEN} bytes")));
+        }
+        if emoji.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(Some("Emoji can't contain spaces or control characters".to_owned()));
+        }
+        Ok((parse_id(id)?, emoji.into()))
+    }
+    async fn run(&self, session: &mut Session, (id, emoji): Self::Args) -> CommandResult {
+        match session.state.rooms.react(&session.room_name, id, &session.name, &emoji) {
+            Ok(_) => (),
+            Err(ReactError::NotFound) => session.client.info(format!("No recent message {id} in {}", session.room_name))?,
+            Err(ReactError::TooManyEmojis) => session
+                .client
+                .info(format!("[{id}] already has {MAX_REACTIONS} different reactions, react with one of those"))?,
+            Err(ReactError::TooManyByUser) => session
+                .client
+                .info(format!("You can react to a message with up to {MAX_REACTIONS_PER_USER} emojis"))?,
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct EditCommand;
+
+impl Command for EditCommand {
+    type Args = (u64, String);
+    fn name(&self) -> &str {
+        "edit"
+    }
+    fn usage(&self) -> &str {
+        "{id} {msg}"
+    }
+    fn summary(&self) -> &str {
+        "edit your message"
+    }
+    fn details(&self) -> &str {
+        "only messages sent while logged in can be edited,\nguests' names can be taken by others once they leave"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let (id, text) = split_arg(args).ok_or(None)?;
+        Ok((parse_id(id)?, text.to_owned()))
+    }
+    async fn run(&self, session: &mut Session, (id, text): Self::Args) -> CommandResult {
+        let rooms = &session.state.rooms;
+        let result = match rooms.can_edit(&session.room_name, id, &session.name) {
+            Ok(_) => match session.moderate(text)? {
+                Some(text) => rooms.edit(&session.room_name, id, &session.name, text),
+                None => Ok(()),
+            },
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(_) => (),
+            Err(EditError::NotFound) => session.client.info(format!("No recent message {id} in {}", session.room_name))?,
+            Err(EditError::NotAllowed) => session.client.info("You can only edit messages you sent while logged in")?,
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct DeleteCommand;
+
+impl Command for DeleteCommand {
+    type Args = u64;
+    fn name(&self) -> &str {
+        "delete"
+    }
+    fn usage(&self) -> &str {
+        "{id}"
+    }
+    fn summary(&self) -> &str {
+        "delete your message, room operators can delete any"
+    }
+    fn details(&self) -> &str {
+        "only messages sent while logged in are yours to delete"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        parse_id(args.split_ascii_whitespace().next().ok_or(None)?)
+    }
+    async fn run(&self, session: &mut Session, id: Self::Args) -> CommandResult {
+        let admin = session.admin();
+        match session.state.rooms.delete(&session.room_name, id, &session.name, admin) {
+            Ok(_) => (),
+            Err(EditError::NotFound) => session.client.info(format!("No recent message {id} in {}", session.room_name))?,
+            Err(EditError::NotAllowed) => session.client.info("Only room operators can delete other users' messages")?,
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+pub enum PollArgs {
+    List,
+    // checked by parse but only split up after moderation
+    Create(String),
+    Close(Option<u64>),
+}
+
+struct PollCommand;
+
+impl Command for PollCommand {
+    type Args = PollArgs;
+    fn name(&self) -> &str {
+        "poll"
+    }
+    fn usage(&self) -> &str {
+        "{question} {options}"
+    }
+    fn summary(&self) -> &str {
+        "start a poll, /poll close {poll} ends it"
+    }
+    fn details(&self) -> &str {
+        "quote the question or options to include spaces,\ne.g. /poll \"lunch at?\" noon \"half past\"\n/poll on its own lists the room's open polls,\n/poll close ends your newest poll if there's no {poll}\nwhoever started a poll and room operators can close it"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let mut words = args.split_ascii_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (None, _, _) => Ok(PollArgs::List),
+            (Some("close"), None, _) => Ok(PollArgs::Close(None)),
+            (Some("close"), Some(id), None) => Ok(PollArgs::Close(Some(parse_poll_id(id)?))),
+            _ => {
+                polls::parse(args).map_err(Some)?;
+                Ok(PollArgs::Create(args.to_owned()))
+            },
+        }
+    }
+    async fn run(&self, session: &mut Session, args: Self::Args) -> CommandResult {
+        let result = match args {
+            PollArgs::List => {
+                let polls = session.state.rooms.polls(&session.room_name);
+                if polls.is_empty() {
+                    let room_name = &session.room_name;
+                    session.client.info(format!("No open polls in {room_name}, start one with /poll {{question}} {{options}}"))?;
+                }
+                for poll in polls {
+                    session.client.poll(poll)?;
+                }
+                return Ok(Flow::Continue);
+            },
+            PollArgs::Create(args) => {
+                let Some(args) = session.moderate(args)? else {
+                    return Ok(Flow::Continue);
+                };
+                // masking words can't break the quoting
+                let Ok((question, options)) = polls::parse(&args) else {
+                    return Ok(Flow::Continue);
+                };
+                session.state.rooms.create_poll(&session.room_name, &session.name, question, options)
+            },
+            PollArgs::Close(id) => {
+                let admin = session.admin();
+                session.state.rooms.close_poll(&session.room_name, id, &session.name, admin)
+            },
+        };
+        let room_name = &session.room_name;
+        match result {
+            Ok(_) => (),
+            // creating and closing never look up an option
+            Err(PollError::NotFound | PollError::NoSuchOption) => {
+                session.client.info(format!("No open poll like that in {room_name}, see /poll"))?
+            },
+            Err(PollError::NotAllowed) => session.client.info("Only whoever started the poll and room operators can close it")?,
+            Err(PollError::TooMany) => session
+                .client
+                .info(format!("{room_name} already has {MAX_OPEN_POLLS} open polls, close one with /poll close {{poll}}"))?,
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct VoteCommand;
+
+impl Command for VoteCommand {
+    type Args = (u64, String);
+    fn name(&self) -> &str {
+        "vote"
+    }
+    fn usage(&self) -> &str {
+        "{poll} {option}"
+    }
+    fn summary(&self) -> &str {
+        "vote in a poll, again to change your vote"
+    }
+    fn details(&self) -> &str {
+        "option is its text or its number, e.g. /vote 1 pizza or /vote 1 2"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let (id, option) = split_arg(args).ok_or(None)?;
+        Ok((parse_poll_id(id)?, option.trim_matches('"').to_owned()))
+    }
+    async fn run(&self, session: &mut Session, (id, option): Self::Args) -> CommandResult {
+        let Session { state, client, name, room_name, .. } = session;
+        match state.rooms.vote(room_name, id, name, &option) {
+            Ok(_) => (),
+            Err(PollError::NoSuchOption) => client.info(format!("Poll {id} has no option {option}"))?,
+            Err(_) => client.info(format!("No open poll {id} in {room_name}, see /poll"))?,
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+fn parse_poll_id(id: &str) -> Result<u64, Option<String>> {
+    id.parse().map_err(|_| Some(format!("{id} is not a poll id")))
+}
+
+// REMINDERS //
+
+pub enum ScheduleArgs {
+    List,
+    Cancel(u64),
+    Add(Duration, String),
+}
+
+fn parse_schedule(args: &str, parse_time: fn(&str) -> Option<Duration>, invalid: &str) -> Result<ScheduleArgs, Option<String>> {
+    let mut words = args.split_ascii_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (None, _, _) => Ok(ScheduleArgs::List),
+        (Some("cancel"), Some(id), None) => {
+            let id = id.parse().map_err(|_| Some(format!("{id} is not a reminder id")))?;
+            Ok(ScheduleArgs::Cancel(id))
+        },
+        _ => {
+            let (time, text) = split_arg(args).ok_or(None)?;
+            let delay = parse_time(time).ok_or_else(|| Some(format!("{time} {invalid}")))?;
+            Ok(ScheduleArgs::Add(delay, text.to_owned()))
+        },
+    }
+}
+
+fn schedule_error(session: &Session, error: ScheduleError, id: u64) -> Result<(), ConnError> {
+    match error {
+        ScheduleError::TooMany => session
+            .client
+            .info(format!("You already have {MAX_PENDING} reminders and posts waiting, cancel some first")),
+        ScheduleError::NotFound => session.client.info(format!("Nothing waiting with id {id}")),
+        ScheduleError::NotAllowed => session.client.info(format!("You can't cancel {id}")),
+    }
+}
+
+struct RemindCommand;
+
+impl Command for RemindCommand {
+    type Args = ScheduleArgs;
+    fn name(&self) -> &str {
+        "remind"
+    }
+    fn usage(&self) -> &str {
+        "{duration} {msg}"
+    }
+    fn summary(&self) -> &str {
+        "remind yourself later, e.g. /remind 10m tea"
+    }
+    fn details(&self) -> &str {
+        "durations are like 90s, 10m, 1h30m or 2d, up to 7d\n/remind on its own lists your reminders,\n/remind cancel {id} cancels one\nguests' reminders are forgotten when they leave,\nregistered names get reminders they missed at /login"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        parse_schedule(args, schedule::parse_duration, "is not a duration")
+    }
+    async fn run(&self, session: &mut Session, args: Self::Args) -> CommandResult {
+        match args {
+            ScheduleArgs::List => {
+                let name = &session.name;
+                let listed = session.state.scheduler.list(|owner, kind| matches!(kind, Kind::Remind) && owner == name);
+                if listed.is_empty() {
+                    session.client.info("No reminders, set one with /remind {duration} {msg}")?;
+                }
+                for listed in listed {
+                    session.client.info(format!("#{} in {} - {}", listed.id, format_secs(listed.due_in), listed.text))?;
+                }
+            },
+            ScheduleArgs::Cancel(id) => {
+                let name = &session.name;
+                let result = session.state.scheduler.cancel(id, |owner, kind| matches!(kind, Kind::Remind) && owner == name);
+                match result {
+                    Ok(()) => session.client.info(format!("Cancelled reminder {id}"))?,
+                    // others' reminders aren't anyone's business
+                    Err(_) => schedule_error(session, ScheduleError::NotFound, id)?,
+                }
+            },
+            ScheduleArgs::Add(delay, text) => {
+                let Some(text) = session.moderate(text)? else {
+                    return Ok(Flow::Continue);
+                };
+                match session.state.scheduler.add(&session.state, &session.name, Kind::Remind, text, delay) {
+                    Ok(id) => session.client.info(format!("Reminder {id} set for {} from now", format_secs(delay.as_secs())))?,
+                    Err(error) => schedule_error(session, error, 0)?,
+                }
+            },
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct ScheduleCommand;
+
+impl Command for ScheduleCommand {
+    type Args = ScheduleArgs;
+    fn name(&self) -> &str {
+        "schedule"
+    }
+    fn usage(&self) -> &str {
+        "{time} {msg}"
+    }
+    fn summary(&self) -> &str {
+        "post to the room later, for room operators"
+    }
+    fn details(&self) -> &str {
+        "time is a duration like 10m or 1h30m, or a utc time\nof day like 17:30, up to 7d ahead\n/schedule on its own lists the room's scheduled posts,\n/schedule cancel {id} cancels one, whoever scheduled\na post and room operators can cancel it,\nguests' posts are forgotten when they leave"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        parse_schedule(args, schedule::parse_time, "is not a duration or HH:MM time")
+    }
+    async fn run(&self, session: &mut Session, args: Self::Args) -> CommandResult {
+        let room_name = &session.room_name;
+        let in_room = |kind: &Kind| matches!(kind, Kind::Post { room, .. } if room == room_name);
+        let operator = session.state.rooms.is_operator(room_name, &session.name) || session.admin();
+        match args {
+            ScheduleArgs::List => {
+                let listed = session.state.scheduler.list(|_, kind| in_room(kind));
+                if listed.is_empty() {
+                    session.client.info(format!("No posts scheduled in {room_name}"))?;
+                }
+                for listed in listed {
+                    let due_in = format_secs(listed.due_in);
+                    session.client.info(format!("#{} in {due_in} by {} - {}", listed.id, listed.owner, listed.text))?;
+                }
+            },
+            ScheduleArgs::Cancel(id) => {
+                // reminders and other rooms' posts are treated as not found
+                let listed = session.state.scheduler.list(|_, kind| in_room(kind));
+                if !listed.iter().any(|listed| listed.id == id) {
+                    schedule_error(session, ScheduleError::NotFound, id)?;
+                    return Ok(Flow::Continue);
+                }
+                let name = &session.name;
+                let result = session.state.scheduler.cancel(id, |owner, kind| in_room(kind) && (operator || owner == name));
+                match result {
+                    Ok(()) => session.client.info(format!("Cancelled scheduled post {id}"))?,
+                    Err(error) => schedule_error(session, error, id)?,
+                }
+            },
+            ScheduleArgs::Add(delay, text) => {
+                if !operator {
+                    session.client.info(format!("Only operators of {room_name} can schedule posts"))?;
+                    return Ok(Flow::Continue);
+                }
+                let Some(text) = session.moderate(text)? else {
+                    return Ok(Flow::Continue);
+                };
+                let kind = Kind::Post {
+                    room: session.room_name.clone(),
+                    registered: session.logged_in,
+                };
+                match session.state.scheduler.add(&session.state, &session.name, kind, text, delay) {
+                    Ok(id) => {
+                        let due_in = format_secs(delay.as_secs());
+                        session.client.info(format!("Post {id} scheduled in {} for {due_in} from now", session.room_name))?;
+                    },
+                    Err(error) => schedule_error(session, error, 0)?,
+                }
+            },
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+pub struct SearchArgs {
+    all: bool,
+    query: String,
+    terms: HashSet<CompactString>,
+}
+
+struct SearchCommand;
+
+impl Command for SearchCommand {
+    type Args = SearchArgs;
+    fn name(&self) -> &str {
+        "search"
+    }
+    fn usage(&self) -> &str {
+        "{terms}"
+    }
+    fn summary(&self) -> &str {
+        "search room history, --all searches every room"
+    }
+    fn details(&self) -> &str {
+        "finds msgs containing every term, ignoring case,\ne.g. /search --all release notes"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let (all, query) = match args.trim_start().strip_prefix("--all") {
+            Some(query) => (true, query.trim()),
+            None => (false, args.trim()),
+        };
+        let terms = search::terms(query);
+        if terms.is_empty() {
+            return Err(None);
+        }
+        Ok(SearchArgs {
+            all,
+            query: query.to_owned(),
+            terms,
+        })
+    }
+    async fn run(&self, session: &mut Session, args: Self::Args) -> CommandResult {
+        let room = if args.all { None } else { Some(session.room_name.as_str()) };
+        let results = session.state.rooms.search(&args.terms, room);
+        session.client.search_results(&args.query, room, results)?;
+        Ok(Flow::Continue)
+    }
+}
+
+fn parse_format(format: Option<&str>) -> Result<Format, Option<String>> {
+    match format {
+        None => Ok(Format::Text),
+        Some(format) => Format::parse(format).ok_or_else(|| Some("Format must be text, jsonl or markdown".to_owned())),
+    }
+}
+
+struct ExportCommand;
+
+impl Command for ExportCommand {
+    type Args = Format;
+    fn name(&self) -> &str {
+        "export"
+    }
+    fn usage(&self) -> &str {
+        "{format}"
+    }
+    fn summary(&self) -> &str {
+        "export room history as text, jsonl or markdown"
+    }
+    fn details(&self) -> &str {
+        "includes recent msgs along with joins, leaves and renames,\nformat is text if left out"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let mut args = args.split_ascii_whitespace();
+        let format = parse_format(args.next())?;
+        match args.next() {
+            Some(_) => Err(None),
+            None => Ok(format),
+        }
+    }
+    async fn run(&self, session: &mut Session, format: Self::Args) -> CommandResult {
+        let room_name = &session.room_name;
+        let Some(transcript) = session.state.rooms.transcript(room_name) else {
+            session.client.info(format!("No history for {room_name}"))?;
+            return Ok(Flow::Continue);
+        };
+        let text = transcript.render(room_name, format);
+        session.client.transcript(room_name, format, text)?;
+        Ok(Flow::Continue)
+    }
+}
+
+struct ArchiveCommand;
+
+impl Command for ArchiveCommand {
+    type Args = (CompactString, Format);
+    fn name(&self) -> &str {
+        "archive"
+    }
+    fn permission(&self) -> Permission {
+        Permission::Admin
+    }
+    fn usage(&self) -> &str {
+        "{room} {format}"
+    }
+    fn summary(&self) -> &str {
+        "save any room's history on the server"
+    }
+    fn details(&self) -> &str {
+        "written to export.dir from the server config,\nformat is text if left out"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let mut args = args.split_ascii_whitespace();
+        let room = args.next().filter(|room| valid_name(Some(room))).ok_or(None)?;
+        let format = parse_format(args.next())?;
+        match args.next() {
+            Some(_) => Err(None),
+            None => Ok((room.into(), format)),
+        }
+    }
+    async fn run(&self, session: &mut Session, (room, format): Self::Args) -> CommandResult {
+        let Some(transcript) = session.state.rooms.transcript(&room) else {
+            session.client.info(format!("No history for {room}"))?;
+            return Ok(Flow::Continue);
+        };
+        let text = transcript.render(&room, format);
+        match transcript::save(&session.state.config.export.dir, &room, format, &text).await {
+            Ok(path) => {
+                tracing::info!("{} saved a transcript of {room} to {}", session.name, path.display());
+                session.client.info(format!("Saved transcript of {room} to {}", path.display()))?;
+            },
+            Err(err) => {
+                tracing::error!("could not save a transcript of {room}: {err}");
+                session.client.info("Could not save transcript, see the server logs")?;
+            },
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_exact_names() {
+        let registry = Registry::builtin();
+        assert_eq!(registry.get("name").map(|command| command.name()), Some("name"));
+        assert!(registry.get("names").is_none());
+        assert!(registry.get("joinx").is_none());
+        assert!(registry.get("jo").is_none());
+        assert!(registry.get("JOIN").is_none());
+    }
+
+    #[test]
+    fn looks_up_aliases() {
+        let registry = Registry::builtin();
+        assert_eq!(registry.get("exit").map(|command| command.name()), Some("quit"));
+        assert_eq!(registry.get("afk").map(|command| command.name()), Some("away"));
+        assert_eq!(registry.get("dm").map(|command| command.name()), Some("msg"));
+    }
+
+    #[test]
+    #[should_panic(expected = "/quit was registered twice")]
+    fn rejects_duplicate_names() {
+        let mut registry = Registry::new();
+        registry.add(QuitCommand);
+        registry.add(QuitCommand);
+    }
+
+    #[test]
+    fn gates_permissions() {
+        assert!(Permission::Anyone.allows(false, false));
+        assert!(!Permission::Registered.allows(false, false));
+        assert!(Permission::Registered.allows(true, false));
+        assert!(!Permission::Admin.allows(true, false));
+        assert!(Permission::Admin.allows(false, true));
+        let registry = Registry::builtin();
+        assert_eq!(registry.get("inbox").map(|command| command.permission()), Some(Permission::Registered));
+        assert_eq!(registry.get("archive").map(|command| command.permission()), Some(Permission::Admin));
+    }
+
+    #[test]
+    fn hides_admin_commands() {
+        let registry = Registry::builtin();
+        assert!(!registry.help(false).contains("/archive"));
+        assert!(registry.help(true).contains("/archive"));
+        assert!(registry.help(false).contains("/inbox"));
+    }
+
+    #[test]
+    fn parses_passwords() {
+        assert_eq!(RegisterCommand.parse(" hunter22 "), Ok("hunter22".to_owned()));
+        assert_eq!(RegisterCommand.parse("correct horse battery"), Err(Some("Password can't contain spaces".to_owned())));
+        assert!(matches!(RegisterCommand.parse("short"), Err(Some(_))));
+        assert_eq!(RegisterCommand.parse(""), Err(None));
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert_eq!(ReplyCommand.parse("3 hi there"), Ok((3, "hi there".to_owned())));
+        assert_eq!(ReplyCommand.parse("3"), Err(None));
+        assert_eq!(ReplyCommand.parse("x hi"), Err(Some("x is not a message id".to_owned())));
+    }
+
+    #[test]
+    fn parses_reactions() {
+        assert_eq!(ReactCommand.parse("3 👍"), Ok((3, "👍".into())));
+        assert_eq!(ReactCommand.parse("3 👍 👎"), Err(None));
+        assert!(matches!(ReactCommand.parse(&format!("3 {}", "x".repeat(MAX_EMOJI_LEN + 1))), Err(Some(_))));
+        // non-ascii spaces and control chars aren't split on
+        assert!(matches!(ReactCommand.parse("3 a\u{a0}b"), Err(Some(_))));
+        assert!(matches!(ReactCommand.parse("3 \u{1b}[2J"), Err(Some(_))));
+    }
+
+    #[test]
+    fn parses_searches() {
+        let args = SearchCommand.parse("--all Hello, world").unwrap();
+        assert!(args.all);
+        assert_eq!(args.query, "Hello, world");
+        assert_eq!(args.terms, HashSet::from(["hello".into(), "world".into()]));
+        assert!(!SearchCommand.parse("hello").unwrap().all);
+        assert!(SearchCommand.parse("--all ...").is_err());
+    }
+
+    #[test]
+    fn parses_archives() {
+        assert!(matches!(ArchiveCommand.parse("den"), Ok((room, Format::Text)) if room == "den"));
+        assert!(matches!(ArchiveCommand.parse("den markdown"), Ok((_, Format::Markdown))));
+        assert!(matches!(ArchiveCommand.parse("den pdf"), Err(Some(_))));
+        assert!(matches!(ArchiveCommand.parse("den text extra"), Err(None)));
+        assert!(matches!(ArchiveCommand.parse(""), Err(None)));
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Context;
+use serde::Deserialize;
+use tracing_appender::rolling::Rotation;
+
+// every field has a default so a config file
+// only needs to list what it wants to change,
+// see config.toml for what every option does
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub outbound: OutboundConfig,
+    pub idle: IdleConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub accounts: AccountsConfig,
+    pub storage: StorageConfig,
+    pub export: ExportConfig,
+    pub audit: AuditConfig,
+    pub moderation: ModerationConfig,
+    pub plugins: PluginsConfig,
+    pub webhooks: WebhooksConfig,
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let config = fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        let config = toml::from_str(&config)
+            .with_context(|| format!("could not parse {}", path.display()))?;
+        Ok(config)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowPolicy {
+    // drop the oldest queued msgs and tell
+    // the user how many were dropped
+    DropOldest,
+    // drop new msgs and disconnect the user once
+    // too many were dropped since they last caught up
+    Disconnect,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+    pub queue_capacity: usize,
+    pub policy: SlowPolicy,
+    pub disconnect_after: usize,
+    pub write_timeout_secs: u64,
+}
+
+impl OutboundConfig {
+    pub fn write_timeout(&self) -> Duration {
+        Duration::from_secs(self.write_timeout_secs)
+    }
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 256,
+            policy: SlowPolicy::DropOldest,
+            disconnect_after: 1024,
+            write_timeout_secs: 10,
+        }
+    }
+}
+
+// durations of 0 disable the respective feature
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdleConfig {
+    pub timeout_secs: u64,
+    pub away_after_secs: u64,
+    pub ping_interval_secs: u64,
+    pub ping_timeout_secs: u64,
+    pub keepalive_secs: u64,
+}
+
+fn secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+impl IdleConfig {
+    pub fn timeout(&self) -> Option<Duration> {
+        secs(self.timeout_secs)
+    }
+    pub fn away_after(&self) -> Option<Duration> {
+        secs(self.away_after_secs)
+    }
+    pub fn ping_interval(&self) -> Option<Duration> {
+        secs(self.ping_interval_secs)
+    }
+    pub fn ping_timeout(&self) -> Option<Duration> {
+        secs(self.ping_timeout_secs)
+    }
+    pub fn keepalive(&self) -> Option<Duration> {
+        secs(self.keepalive_secs)
+    }
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 3600,
+            away_after_secs: 300,
+            ping_interval_secs: 30,
+            ping_timeout_secs: 90,
+            keepalive_secs: 60,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub log_interval_secs: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            log_interval_secs: 60,
+        }
+    }
+}
+
+// nobody can become an admin without a password
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    pub inbox_capacity: usize,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        Self {
+            inbox_capacity: 100,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackend {
+    // nothing survives a restart
+    Memory,
+    // append-only log of json lines
+    Log,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub path: PathBuf,
+    pub history_per_room: usize,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Memory,
+            path: PathBuf::from("data/chat.log"),
+            history_per_room: 256,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    pub dir: PathBuf,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("exports"),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditRotation {
+    Hourly,
+    Daily,
+}
+
+impl AuditRotation {
+    pub fn rotation(self) -> Rotation {
+        match self {
+            Self::Hourly => Rotation::HOURLY,
+            Self::Daily => Rotation::DAILY,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    pub rotation: AuditRotation,
+    pub max_files: usize,
+    pub direct_msgs: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("logs/audit"),
+            rotation: AuditRotation::Daily,
+            max_files: 30,
+            direct_msgs: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum WordAction {
+    // replace them with *s
+    Mask,
+    // drop the msg
+    Block,
+    // post the msg and warn the sender
+    Warn,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WordListConfig {
+    pub action: WordAction,
+    pub words: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationConfig {
+    pub word_lists: Vec<WordListConfig>,
+    pub block_links_in: Vec<String>,
+    pub repeat_limit: usize,
+    pub repeat_window_secs: u64,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            word_lists: Vec::new(),
+            block_links_in: Vec::new(),
+            repeat_limit: 5,
+            repeat_window_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginsConfig {
+    // built-in plugins to load, by name
+    pub enabled: Vec<String>,
+    // titles the links plugin previews, by url
+    pub links: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    pub timeout_secs: u64,
+    pub outgoing: Vec<OutgoingHookConfig>,
+    pub incoming: IncomingConfig,
+}
+
+impl WebhooksConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.max(1))
+    }
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 5,
+            outgoing: Vec::new(),
+            incoming: IncomingConfig::default(),
+        }
+    }
+}
+
+fn every_room() -> Vec<String> {
+    vec!["*".to_owned()]
+}
+
+fn any_text() -> String {
+    "*".to_owned()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutgoingHookConfig {
+    pub url: String,
+    #[serde(default = "every_room")]
+    pub rooms: Vec<String>,
+    #[serde(default = "any_text")]
+    pub pattern: String,
+}
+
+// no addr means nothing is listening
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IncomingConfig {
+    pub addr: Option<SocketAddr>,
+    pub bots: Vec<IncomingBotConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IncomingBotConfig {
+    pub name: String,
+    pub token: String,
+}
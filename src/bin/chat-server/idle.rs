@@ -0,0 +1,133 @@
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::config::IdleConfig;
+
+// tracks how long it's been since we heard from a user,
+// rather than resetting a timer on every line the user
+// sends we let the timer fire and then check what's due
+
+pub struct Idle {
+    // last time the user sent anything, pongs included
+    last_seen: Instant,
+    // last time the user sent a line
+    last_active: Instant,
+    last_ping: Instant,
+    pings_sent: u64,
+    pub away: bool,
+}
+
+#[derive(Default)]
+pub struct Due {
+    pub ping: Option<u64>,
+    pub away: bool,
+    pub timed_out: bool,
+}
+
+impl Idle {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            last_seen: now,
+            last_active: now,
+            last_ping: now,
+            pings_sent: 0,
+            away: false,
+        }
+    }
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+    // returns true if the user was away
+    pub fn active(&mut self) -> bool {
+        let now = Instant::now();
+        self.last_seen = now;
+        self.last_active = now;
+        std::mem::replace(&mut self.away, false)
+    }
+    fn timeout(config: &IdleConfig, pings: bool) -> Option<Duration> {
+        if pings {
+            config.ping_timeout()
+        } else {
+            config.timeout()
+        }
+    }
+    // pings are only sent to structured protocol clients,
+    // returns what's due now and when to check again
+    pub fn tick(&mut self, config: &IdleConfig, pings: bool) -> (Due, Instant) {
+        let now = Instant::now();
+        let mut due = Due::default();
+        if let Some(timeout) = Self::timeout(config, pings) {
+            due.timed_out = now >= self.last_seen + timeout;
+        }
+        if let Some(away_after) = config.away_after() {
+            if !self.away && now >= self.last_active + away_after {
+                self.away = true;
+                due.away = true;
+            }
+        }
+        let ping_interval = config.ping_interval().filter(|_| pings);
+        if let Some(ping_interval) = ping_interval {
+            if now >= self.last_ping + ping_interval {
+                self.last_ping = now;
+                self.pings_sent += 1;
+                due.ping = Some(self.pings_sent);
+            }
+        }
+        (due, self.next_tick(config, pings))
+    }
+    pub fn next_tick(&self, config: &IdleConfig, pings: bool) -> Instant {
+        let timeout = Self::timeout(config, pings)
+            .map(|timeout| self.last_seen + timeout);
+        let away = config
+            .away_after()
+            .filter(|_| !self.away)
+            .map(|away_after| self.last_active + away_after);
+        let ping = config
+            .ping_interval()
+            .filter(|_| pings)
+            .map(|ping_interval| self.last_ping + ping_interval);
+        [timeout, away, ping]
+            .into_iter()
+            .flatten()
+            .min()
+            // nothing to do, check back in an hour
+            .unwrap_or_else(|| Instant::now() + Duration::from_secs(3600))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn goes_away_again_after_coming_back() {
+        let config = IdleConfig::default();
+        let away_after = config.away_after().unwrap();
+        let mut idle = Idle::new();
+        tokio::time::advance(away_after).await;
+        let (due, next_tick) = idle.tick(&config, false);
+        assert!(due.away);
+        // only the timeout is left while they're away
+        assert_eq!(next_tick, idle.last_seen + config.timeout().unwrap());
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(idle.active());
+        assert!(!idle.active());
+        assert_eq!(idle.next_tick(&config, false), Instant::now() + away_after);
+        tokio::time::advance(away_after).await;
+        let (due, _) = idle.tick(&config, false);
+        assert!(due.away && !due.timed_out);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_without_pongs() {
+        let config = IdleConfig::default();
+        let mut idle = Idle::new();
+        tokio::time::advance(config.ping_interval().unwrap()).await;
+        let (due, _) = idle.tick(&config, true);
+        assert_eq!(due.ping, Some(1));
+        idle.seen();
+        tokio::time::advance(config.ping_timeout().unwrap()).await;
+        let (due, _) = idle.tick(&config, true);
+        assert!(due.timed_out);
+    }
+}
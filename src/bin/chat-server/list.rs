@@ -0,0 +1,158 @@
+// filtering and pagination for commands which list
+// things, like /who and /rooms, so their output stays
+// readable and fits in a single line however many
+// users or rooms there are
+
+pub const PAGE_SIZE: usize = 10;
+
+// args are an optional glob pattern followed
+// by an optional page number, e.g. "/who b*n 2"
+pub struct ListArgs {
+    pub pattern: Option<String>,
+    pub page: usize,
+}
+
+impl ListArgs {
+    pub fn parse(args: &str) -> Result<Self, &'static str> {
+        let mut parsed = ListArgs {
+            pattern: None,
+            page: 1,
+        };
+        let mut args = args.split_ascii_whitespace();
+        let mut next = args.next();
+        if let Some(pattern) = next.filter(|arg| arg.parse::<usize>().is_err()) {
+            parsed.pattern = Some(pattern.to_owned());
+            next = args.next();
+        }
+        if let Some(page) = next {
+            parsed.page = match page.parse() {
+                Ok(page) if page > 0 => page,
+                _ => return Err("Page must be a positive number"),
+            };
+        }
+        if args.next().is_some() {
+            return Err("Too many arguments");
+        }
+        Ok(parsed)
+    }
+    pub fn matches(&self, text: &str) -> bool {
+        match &self.pattern {
+            Some(pattern) => glob_match(pattern, text),
+            None => true,
+        }
+    }
+}
+
+// * matches any number of chars and ? matches
+// exactly one, comparison is case insensitive
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    let text: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+    let (mut p, mut t) = (0, 0);
+    // where to resume if what followed the last * didn't match
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// items are rendered as a comma separated list after the title,
+// e.g. "Rooms - main (3), rust (2) - page 1 of 2"
+pub fn render_page(title: &str, items: &[String], args: &ListArgs) -> String {
+    if items.is_empty() {
+        return match &args.pattern {
+            Some(pattern) => format!("{title} - nothing matches {pattern}"),
+            None => format!("{title} - none"),
+        };
+    }
+    let pages = items.len().div_ceil(PAGE_SIZE);
+    if args.page > pages {
+        return format!("{title} - there's no page {}, only {pages}", args.page);
+    }
+    let start = (args.page - 1) * PAGE_SIZE;
+    let end = (start + PAGE_SIZE).min(items.len());
+    let mut msg = format!("{title} - {}", items[start..end].join(", "));
+    if pages > 1 {
+        msg.push_str(&format!(" - page {} of {pages}", args.page));
+    }
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stars() {
+        assert!(glob_match("rust*", "rustaceans"));
+        assert!(glob_match("*ust", "rust"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("**", "anything"));
+        assert!(glob_match("b*n", "bobn"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("rust*", "trust"));
+        assert!(!glob_match("*ust", "rusty"));
+        assert!(!glob_match("b*n", "bob"));
+    }
+
+    #[test]
+    fn question_marks() {
+        assert!(glob_match("b?b", "bob"));
+        assert!(glob_match("??", "ab"));
+        assert!(!glob_match("b?b", "bb"));
+        assert!(!glob_match("??", "abc"));
+        assert!(glob_match("?*", "a"));
+        assert!(!glob_match("?*", ""));
+    }
+
+    #[test]
+    fn case_folding() {
+        assert!(glob_match("BOB", "bob"));
+        assert!(glob_match("b*", "Bob"));
+        assert!(glob_match("ÉCOLE", "école"));
+    }
+
+    #[test]
+    fn empty_pattern() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+        let args = ListArgs::parse("").unwrap();
+        assert_eq!(args.pattern, None);
+        assert_eq!(args.page, 1);
+        assert!(args.matches("anything"));
+    }
+
+    #[test]
+    fn pattern_or_page() {
+        let args = ListArgs::parse("room2").unwrap();
+        assert_eq!(args.pattern.as_deref(), Some("room2"));
+        assert_eq!(args.page, 1);
+        let args = ListArgs::parse("2").unwrap();
+        assert_eq!(args.pattern, None);
+        assert_eq!(args.page, 2);
+        let args = ListArgs::parse("r* 3").unwrap();
+        assert_eq!(args.pattern.as_deref(), Some("r*"));
+        assert_eq!(args.page, 3);
+        assert!(ListArgs::parse("r* 0").is_err());
+        assert!(ListArgs::parse("r* x").is_err());
+        assert!(ListArgs::parse("r* 1 2").is_err());
+    }
+}
@@ -0,0 +1,1486 @@
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use clap::Parser;
+use socket2::{SockRef, TcpKeepalive};
+use compact_str::CompactString;
+use dashmap::{DashMap, mapref::entry::Entry};
+use futures::StreamExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, Sender, error::RecvError};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+use tracing_appender::rolling::Rotation;
+use chat_server::{b, markup, single_line, unix_secs, AddrArgs, NameGenerator, stdout_logging, file_logging};
+use chat_server::protocol::{ClientEvent, Poll, Presence, ReactionCount, ServerEvent, Whois, SWITCH_TO_JSON};
+
+mod accounts;
+mod audit;
+mod client;
+mod commands;
+mod config;
+mod idle;
+mod list;
+mod moderation;
+mod outbound;
+mod plugins;
+mod polls;
+mod schedule;
+mod search;
+mod storage;
+mod transcript;
+mod webhooks;
+
+use accounts::Accounts;
+use audit::Audit;
+use client::{Client, Protocol};
+use commands::{CommandResult, Flow, Registry};
+use config::Config;
+use idle::Idle;
+use moderation::{Moderation, Outcome};
+use outbound::{Metrics, Outbound, MAX_EVENT_LEN};
+use plugins::Plugins;
+use polls::{PollError, Polls};
+use schedule::{Reminder, Scheduler};
+use search::Index;
+use storage::{Change, RoomRecord, Store, StoredMsg};
+use transcript::{Event, Logged, Transcript};
+use webhooks::Webhooks;
+
+#[cfg(not(target_env = "msvc"))]
+use tikv_jemallocator::Jemalloc;
+
+#[cfg(not(target_env = "msvc"))]
+#[global_allocator]
+static GLOBAL: Jemalloc = Jemalloc;
+
+const MAIN: &str = "main";
+pub const MAX_MSG_LEN: usize = 400;
+// lines from structured clients are json events whose
+// text can be up to MAX_MSG_LEN once decoded, and every
+// char of it could be escaped as \uXXXX
+const MAX_CLIENT_EVENT_LEN: usize = MAX_MSG_LEN * 6 + 100;
+const ROOM_CHANNEL_CAPACITY: usize = 1024;
+// typing events are only useful while they're fresh
+const TYPING_CHANNEL_CAPACITY: usize = 64;
+const USER_CHANNEL_CAPACITY: usize = 64;
+const MIN_PASSWORD_LEN: usize = 6;
+// wrong passwords before being disconnected
+const MAX_AUTH_FAILURES: u32 = 5;
+const AUTH_FAILURE_DELAY: Duration = Duration::from_secs(1);
+// newest matches returned by /search
+const MAX_SEARCH_RESULTS: usize = 20;
+// typing-start events forwarded to the room per user
+const MIN_TYPING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct Away {
+    pub reason: Option<Arc<str>>,
+    // set automatically after being idle,
+    // rather than by the user with /away
+    pub auto: bool,
+}
+
+impl Away {
+    pub fn describe(&self) -> String {
+        match &self.reason {
+            Some(reason) => format!("away: {reason}"),
+            None => "away".to_owned(),
+        }
+    }
+}
+
+// msgs for a single user, wherever they are
+pub enum UserMsg {
+    Direct {
+        from: CompactString,
+        text: Arc<str>,
+    },
+    Reminder(Reminder),
+    // by a room operator, ignored if they've already left
+    Kicked {
+        room: CompactString,
+        by: CompactString,
+    },
+}
+
+#[derive(Clone)]
+struct User {
+    tx: mpsc::Sender<UserMsg>,
+    addr: SocketAddr,
+    connected: Instant,
+    // last time the user sent a line
+    last_active: Instant,
+    room: CompactString,
+    away: Option<Away>,
+    admin: bool,
+}
+
+impl User {
+    fn new(tx: mpsc::Sender<UserMsg>, addr: SocketAddr) -> Self {
+        let now = Instant::now();
+        Self {
+            tx,
+            addr,
+            connected: now,
+            last_active: now,
+            room: MAIN.into(),
+            away: None,
+            admin: false,
+        }
+    }
+    // only admins get to see the address
+    fn whois(&self, name: &str, admin: bool) -> Whois {
+        Whois {
+            name: name.to_owned(),
+            room: self.room.to_string(),
+            connected_secs: self.connected.elapsed().as_secs(),
+            idle_secs: self.last_active.elapsed().as_secs(),
+            away: self.away.is_some(),
+            reason: self.away.as_ref().and_then(|away| away.reason.as_ref()).map(|reason| reason.to_string()),
+            addr: admin.then(|| self.addr.to_string()),
+        }
+    }
+}
+
+enum DirectError {
+    NotOnline,
+    Full,
+}
+
+#[derive(Clone)]
+#[repr(transparent)]
+struct Names(Arc<DashMap<CompactString, User>>);
+
+impl Names {
+    fn new() -> Self {
+        Self(Arc::new(DashMap::with_capacity(32)))
+    }
+    // returns false if the new name is taken
+    fn rename(&self, prev_name: &str, new_name: CompactString) -> bool {
+        let Some(user) = self.0.get(prev_name).map(|user| user.clone()) else {
+            return false;
+        };
+        match self.0.entry(new_name) {
+            Entry::Occupied(_) => return false,
+            Entry::Vacant(entry) => {
+                entry.insert(user);
+            },
+        }
+        self.0.remove(prev_name);
+        true
+    }
+    fn remove(&self, name: &str) -> bool {
+        self.0.remove(name).is_some()
+    }
+    fn get_unique(&self, name_generator: &mut NameGenerator, user: User, accounts: &Accounts) -> CompactString {
+        loop {
+            let name = name_generator.next();
+            if accounts.is_registered(&name) {
+                continue;
+            }
+            if let Entry::Vacant(entry) = self.0.entry(name.clone()) {
+                entry.insert(user);
+                break name;
+            }
+        }
+    }
+    fn get(&self, name: &str) -> Option<User> {
+        self.0.get(name).map(|user| user.clone())
+    }
+    fn update(&self, name: &str, update: impl FnOnce(&mut User)) {
+        if let Some(mut user) = self.0.get_mut(name) {
+            update(&mut user);
+        }
+    }
+    fn set_away(&self, name: &str, away: Option<Away>) {
+        self.update(name, |user| user.away = away);
+    }
+    fn away(&self, name: &str) -> Option<Away> {
+        self.0.get(name).and_then(|user| user.away.clone())
+    }
+    // everyone online along with their room, sorted by name
+    fn list(&self) -> Vec<(CompactString, CompactString)> {
+        let mut list: Vec<_> = self
+            .0
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().room.clone()))
+            .collect();
+        list.sort();
+        list
+    }
+    fn presence(&self, users: Vec<CompactString>) -> Vec<Presence> {
+        users
+            .into_iter()
+            .map(|name| {
+                let away = self.away(&name);
+                Presence {
+                    name: name.to_string(),
+                    away: away.is_some(),
+                    reason: away.and_then(|away| away.reason).map(|reason| reason.to_string()),
+                }
+            })
+            .collect()
+    }
+    // returns the recipient's away status so the
+    // sender can be told they might not answer
+    fn direct(&self, to: &str, msg: UserMsg) -> Result<Option<Away>, DirectError> {
+        let Some(user) = self.0.get(to) else {
+            return Err(DirectError::NotOnline);
+        };
+        match user.tx.try_send(msg) {
+            Ok(_) => Ok(user.away.clone()),
+            Err(TrySendError::Full(_)) => Err(DirectError::Full),
+            Err(TrySendError::Closed(_)) => Err(DirectError::NotOnline),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum RoomMsg {
+    Joined(CompactString),
+    Left(CompactString),
+    Renamed {
+        from: CompactString,
+        to: CompactString,
+    },
+    Away {
+        name: CompactString,
+        away: Away,
+    },
+    Back(CompactString),
+    Msg(Arc<ChatMsg>),
+    // replaces the msg with the same id
+    Edited(Arc<ChatMsg>),
+    Deleted {
+        id: u64,
+        by: CompactString,
+    },
+    // every reaction to the msg so far
+    Reacted {
+        id: u64,
+        reactions: Arc<[ReactionCount]>,
+    },
+    Topic {
+        by: CompactString,
+        topic: Option<Arc<str>>,
+    },
+    // what the moderation filters did to someone's msg
+    Moderated(Arc<str>),
+    // a new poll or its latest tally
+    Poll(Arc<Poll>),
+}
+
+// kept apart from RoomMsg on a channel only structured
+// clients subscribe to, so text clients never queue them
+#[derive(Clone)]
+pub struct Typing {
+    name: CompactString,
+    typing: bool,
+}
+
+pub struct ChatMsg {
+    id: u64,
+    room: CompactString,
+    from: CompactString,
+    text: String,
+    reply_to: Option<u64>,
+    // sent with /me
+    action: bool,
+    edited: bool,
+    // sent while logged in to a registered name, only
+    // then is it the author's to edit or delete later
+    // since guests' names can be taken once they leave
+    registered: bool,
+    // unix timestamp in secs
+    sent: u64,
+    // lazily rendered the first time a user in the
+    // room needs it, then shared with everyone else
+    line: OnceLock<Arc<str>>,
+    json: OnceLock<Arc<str>>,
+}
+
+// ids are unique across all rooms so a msg can't be
+// mistaken for one in another room, and carry on from
+// the stored history after a restart
+static NEXT_MSG_ID: AtomicU64 = AtomicU64::new(1);
+
+fn next_msg_id() -> u64 {
+    NEXT_MSG_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl ChatMsg {
+    fn new(room: &str, from: &str, text: String) -> Arc<Self> {
+        Self::reply(room, from, text, None)
+    }
+    fn action(room: &str, from: &str, text: String) -> Arc<Self> {
+        let mut msg = Self::reply(room, from, text, None);
+        // can't fail, nobody else has seen it yet
+        Arc::get_mut(&mut msg).unwrap().action = true;
+        msg
+    }
+    fn reply(room: &str, from: &str, text: String, reply_to: Option<u64>) -> Arc<Self> {
+        Arc::new(Self {
+            id: next_msg_id(),
+            room: room.into(),
+            from: from.into(),
+            text,
+            reply_to,
+            action: false,
+            edited: false,
+            registered: false,
+            sent: unix_secs(),
+            line: OnceLock::new(),
+            json: OnceLock::new(),
+        })
+    }
+    fn restore(msg: StoredMsg) -> Arc<Self> {
+        Arc::new(Self {
+            id: msg.id,
+            room: msg.room,
+            from: msg.from,
+            text: msg.text,
+            reply_to: msg.reply_to,
+            action: msg.action,
+            edited: msg.edited,
+            registered: msg.registered,
+            sent: msg.sent,
+            line: OnceLock::new(),
+            json: OnceLock::new(),
+        })
+    }
+    fn to_stored(&self) -> StoredMsg {
+        StoredMsg {
+            id: self.id,
+            room: self.room.clone(),
+            from: self.from.clone(),
+            text: self.text.clone(),
+            reply_to: self.reply_to,
+            action: self.action,
+            edited: self.edited,
+            registered: self.registered,
+            sent: self.sent,
+        }
+    }
+    fn edit(&self, text: String) -> Arc<Self> {
+        Arc::new(Self {
+            id: self.id,
+            room: self.room.clone(),
+            from: self.from.clone(),
+            text,
+            reply_to: self.reply_to,
+            action: self.action,
+            edited: true,
+            registered: self.registered,
+            sent: self.sent,
+            line: OnceLock::new(),
+            json: OnceLock::new(),
+        })
+    }
+    fn owned_by(&self, name: &str) -> bool {
+        self.registered && self.from == name
+    }
+    fn render(&self, text: &str) -> String {
+        match (self.edited, self.action, self.reply_to) {
+            (true, _, _) => format!("[{}] {} edited: {text}", self.id, self.from),
+            (false, true, _) => format!("[{}] * {} {text}", self.id, self.from),
+            (false, false, Some(parent)) => format!("[{}] {} replied to [{parent}]: {text}", self.id, self.from),
+            (false, false, None) => format!("[{}] {}: {text}", self.id, self.from),
+        }
+    }
+    fn line(&self) -> Arc<str> {
+        self.line
+            .get_or_init(|| Arc::from(self.render(&markup::strip(&self.text))))
+            .clone()
+    }
+    // audit logs keep the markup
+    fn audit_line(&self) -> String {
+        self.render(&self.text)
+    }
+    fn json(&self) -> Arc<str> {
+        self.json
+            .get_or_init(|| {
+                let (id, room, from, text) = (self.id, self.room.to_string(), self.from.to_string(), self.text.clone());
+                let event = match (self.edited, self.action) {
+                    (true, _) => ServerEvent::Edited { id, room, from, text },
+                    (false, true) => ServerEvent::Action { id, room, from, text },
+                    (false, false) => ServerEvent::Msg { id, room, from, text, reply_to: self.reply_to },
+                };
+                Arc::from(event.to_json())
+            })
+            .clone()
+    }
+}
+
+struct Room {
+    tx: Sender<RoomMsg>,
+    typing: Sender<Typing>,
+    users: HashSet<CompactString>,
+    // up to storage.history_per_room msgs, only
+    // these can be edited, deleted or reacted to
+    recent: VecDeque<Arc<ChatMsg>>,
+    // joins, leaves and renames for /export
+    log: VecDeque<Logged>,
+    topic: Option<Arc<str>>,
+    // whoever created the room, they can delete anyone's
+    // msgs and set the topic, the main room has none
+    operators: HashSet<CompactString>,
+    // names which can't join, set by operators
+    bans: HashSet<CompactString>,
+    // only for recent msgs, in the order
+    // each emoji was first used
+    reactions: HashMap<u64, Vec<Reaction>>,
+    // never stored, gone once the room is removed
+    polls: Polls,
+}
+
+struct Reaction {
+    emoji: CompactString,
+    users: HashSet<CompactString>,
+}
+
+const MAX_EMOJI_LEN: usize = 16;
+// distinct emojis on a msg, and per user on a msg, every
+// reaction sends the whole list so it has to stay short
+const MAX_REACTIONS: usize = 12;
+const MAX_REACTIONS_PER_USER: usize = 3;
+
+impl Room {
+    fn new(record: RoomRecord) -> Self {
+        let (tx, _) = broadcast::channel(ROOM_CHANNEL_CAPACITY);
+        let (typing, _) = broadcast::channel(TYPING_CHANNEL_CAPACITY);
+        let users = HashSet::with_capacity(8);
+        Self {
+            tx,
+            typing,
+            users,
+            recent: record.history.into_iter().map(ChatMsg::restore).collect(),
+            log: record.log,
+            topic: record.topic.map(Arc::from),
+            operators: record.operators,
+            bans: record.bans,
+            reactions: HashMap::new(),
+            polls: Polls::new(),
+        }
+    }
+    fn into_record(self) -> RoomRecord {
+        RoomRecord {
+            topic: self.topic.map(|topic| topic.to_string()),
+            operators: self.operators,
+            bans: self.bans,
+            history: self.recent.iter().map(|msg| msg.to_stored()).collect(),
+            log: self.log,
+        }
+    }
+    fn log(&mut self, event: Event, history_len: usize) {
+        if self.log.len() >= history_len {
+            self.log.pop_front();
+        }
+        self.log.push_back(Logged {
+            after: NEXT_MSG_ID.load(Ordering::Relaxed) - 1,
+            sent: unix_secs(),
+            event,
+        });
+    }
+    fn find(&self, id: u64) -> Option<usize> {
+        self.recent.iter().position(|msg| msg.id == id)
+    }
+}
+
+enum EditError {
+    NotFound,
+    NotAllowed,
+}
+
+enum ReactError {
+    NotFound,
+    // the msg has MAX_REACTIONS emojis
+    TooManyEmojis,
+    // the user used MAX_REACTIONS_PER_USER on the msg
+    TooManyByUser,
+}
+
+// a room that was just opened along with a receiver for it
+type Opened = (CompactString, broadcast::Receiver<RoomMsg>);
+
+#[derive(Clone)]
+struct Rooms {
+    live: Arc<DashMap<CompactString, Room>>,
+    // rooms nobody is in, kept for their
+    // history, topic and operators
+    dormant: Arc<DashMap<CompactString, RoomRecord>>,
+    store: Store,
+    history_len: usize,
+    index: Arc<Index>,
+    audit: Audit,
+    moderation: Arc<Moderation>,
+    // plugins and webhooks, told about every room opened
+    watchers: Arc<Mutex<Vec<mpsc::UnboundedSender<Opened>>>>,
+}
+
+impl Rooms {
+    fn new(
+        dormant: HashMap<CompactString, RoomRecord>,
+        store: Store,
+        audit: Audit,
+        moderation: Moderation,
+        history_len: usize,
+    ) -> Self {
+        let index = Index::default();
+        for msg in dormant.values().flat_map(|record| &record.history) {
+            index.insert(ChatMsg::restore(msg.clone()));
+        }
+        Self {
+            live: Arc::new(DashMap::with_capacity(8)),
+            dormant: Arc::new(dormant.into_iter().collect()),
+            store,
+            history_len: history_len.max(1),
+            index: Arc::new(index),
+            audit,
+            moderation: Arc::new(moderation),
+            watchers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+    // every room opened from now on along with a
+    // receiver for it, so call it before users connect
+    fn watch(&self) -> mpsc::UnboundedReceiver<Opened> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.watchers.lock().unwrap().push(tx);
+        rx
+    }
+    // runs a msg through the moderation filters, letting
+    // the room's operators know about anything they did
+    fn moderate(&self, room_name: &str, from: &str, text: String) -> Outcome {
+        let outcome = self.moderation.check(room_name, from, text);
+        if !outcome.reasons.is_empty() {
+            let verb = if outcome.text.is_some() { "flagged" } else { "blocked" };
+            let note = format!("{verb} {from}'s message - {}", outcome.reasons.join(", "));
+            self.audit.room(room_name, || format!("moderation {note}"));
+            if let Some(room) = self.live.get(room_name) {
+                let _ = room.tx.send(RoomMsg::Moderated(note.into()));
+            }
+        }
+        outcome
+    }
+    fn watch_typing(&self, room_name: &str) -> Option<broadcast::Receiver<Typing>> {
+        self.live.get(room_name).map(|room| room.typing.subscribe())
+    }
+    fn typing(&self, room_name: &str, typing: Typing) {
+        if let Some(room) = self.live.get(room_name) {
+            let _ = room.typing.send(typing);
+        }
+    }
+    fn is_operator(&self, room_name: &str, user_name: &str) -> bool {
+        self.live
+            .get(room_name)
+            .is_some_and(|room| room.operators.contains(user_name))
+    }
+    fn search(&self, query: &HashSet<CompactString>, room: Option<&str>) -> search::Results {
+        self.index.search(query, room, MAX_SEARCH_RESULTS)
+    }
+    // operators are only ever registered names, guests'
+    // names can be taken by anyone once they leave
+    fn join(&self, room_name: &str, user_name: &str, logged_in: bool) -> Sender<RoomMsg> {
+        let mut room = self.live.entry(room_name.into()).or_insert_with(|| {
+            let record = self
+                .dormant
+                .remove(room_name)
+                .map(|(_, record)| record)
+                .unwrap_or_default();
+            let created = record.is_empty();
+            let mut room = Room::new(record);
+            if created && logged_in && room_name != MAIN {
+                room.operators.insert(user_name.into());
+                self.store.record(Change::Operator {
+                    room: room_name.into(),
+                    name: user_name.into(),
+                    added: true,
+                });
+            }
+            for watcher in self.watchers.lock().unwrap().iter() {
+                let _ = watcher.send((room_name.into(), room.tx.subscribe()));
+            }
+            room
+        });
+        room.users.insert(user_name.into());
+        room.log(Event::Joined(user_name.into()), self.history_len);
+        self.audit.room(room_name, || format!("{user_name} joined"));
+        room.tx.clone()
+    }
+    // history of a live or dormant room
+    fn transcript(&self, room_name: &str) -> Option<Transcript> {
+        if let Some(room) = self.live.get(room_name) {
+            return Some(Transcript {
+                msgs: room.recent.iter().cloned().collect(),
+                events: room.log.iter().cloned().collect(),
+            });
+        }
+        self.dormant.get(room_name).map(|record| Transcript {
+            msgs: record.history.iter().cloned().map(ChatMsg::restore).collect(),
+            events: record.log.iter().cloned().collect(),
+        })
+    }
+    fn topic(&self, room_name: &str) -> Option<Arc<str>> {
+        self.live.get(room_name).and_then(|room| room.topic.clone())
+    }
+    // room operators and admins can set the topic
+    fn set_topic(&self, room_name: &str, by: &str, topic: Option<&str>, admin: bool) -> Result<(), EditError> {
+        let Some(mut room) = self.live.get_mut(room_name) else {
+            return Err(EditError::NotFound);
+        };
+        if !admin && !room.operators.contains(by) {
+            return Err(EditError::NotAllowed);
+        }
+        room.topic = topic.map(Arc::from);
+        self.store.record(Change::Topic {
+            room: room_name.into(),
+            topic: topic.map(str::to_owned),
+        });
+        self.audit.room(room_name, || match topic {
+            Some(topic) => format!("{by} set the topic - {topic}"),
+            None => format!("{by} cleared the topic"),
+        });
+        let _ = room.tx.send(RoomMsg::Topic {
+            by: by.into(),
+            topic: room.topic.clone(),
+        });
+        Ok(())
+    }
+    // remembers the msg so it can be edited later, sends
+    // while holding the room so ids arrive in order,
+    // replies can only be to recent msgs in the room
+    fn post(&self, msg: Arc<ChatMsg>) -> Result<(), EditError> {
+        let Some(mut room) = self.live.get_mut(&msg.room) else {
+            return Err(EditError::NotFound);
+        };
+        if let Some(parent) = msg.reply_to {
+            room.find(parent).ok_or(EditError::NotFound)?;
+        }
+        if room.recent.len() >= self.history_len {
+            if let Some(oldest) = room.recent.pop_front() {
+                room.reactions.remove(&oldest.id);
+                self.index.remove(oldest.id);
+            }
+        }
+        room.recent.push_back(msg.clone());
+        self.store.record(Change::Msg(msg.to_stored()));
+        self.index.insert(msg.clone());
+        self.audit.room(&msg.room, || msg.audit_line());
+        let _ = room.tx.send(RoomMsg::Msg(msg));
+        Ok(())
+    }
+    // reacting with the same emoji twice takes it back
+    // taking a reaction back is always allowed
+    fn react(&self, room_name: &str, id: u64, user_name: &str, emoji: &str) -> Result<(), ReactError> {
+        let Some(mut room) = self.live.get_mut(room_name) else {
+            return Err(ReactError::NotFound);
+        };
+        room.find(id).ok_or(ReactError::NotFound)?;
+        let reactions = room.reactions.entry(id).or_default();
+        let existing = reactions.iter().position(|reaction| reaction.emoji == emoji);
+        let reacted = existing.is_some_and(|idx| reactions[idx].users.contains(user_name));
+        if !reacted {
+            if reactions.iter().filter(|reaction| reaction.users.contains(user_name)).count() >= MAX_REACTIONS_PER_USER {
+                return Err(ReactError::TooManyByUser);
+            }
+            if existing.is_none() && reactions.len() >= MAX_REACTIONS {
+                return Err(ReactError::TooManyEmojis);
+            }
+        }
+        match existing {
+            Some(idx) if reacted => {
+                reactions[idx].users.remove(user_name);
+            },
+            Some(idx) => {
+                reactions[idx].users.insert(user_name.into());
+            },
+            None => reactions.push(Reaction {
+                emoji: emoji.into(),
+                users: HashSet::from([user_name.into()]),
+            }),
+        }
+        reactions.retain(|reaction| !reaction.users.is_empty());
+        let counts: Arc<[ReactionCount]> = reactions
+            .iter()
+            .map(|reaction| ReactionCount {
+                emoji: reaction.emoji.to_string(),
+                count: reaction.users.len(),
+            })
+            .collect();
+        let _ = room.tx.send(RoomMsg::Reacted {
+            id,
+            reactions: counts,
+        });
+        Ok(())
+    }
+    fn create_poll(&self, room_name: &str, by: &str, question: String, options: Vec<String>) -> Result<(), PollError> {
+        let Some(mut room) = self.live.get_mut(room_name) else {
+            return Err(PollError::NotFound);
+        };
+        let id = room.polls.create(by, question, options)?;
+        let poll = room.polls.tally(room_name, id).unwrap();
+        self.audit.room(room_name, || format!("{by} created poll {id} - {}", poll.question));
+        let _ = room.tx.send(RoomMsg::Poll(Arc::new(poll)));
+        Ok(())
+    }
+    // everyone gets one vote per poll, voting
+    // again replaces their previous vote
+    fn vote(&self, room_name: &str, id: u64, voter: &str, option: &str) -> Result<(), PollError> {
+        let Some(mut room) = self.live.get_mut(room_name) else {
+            return Err(PollError::NotFound);
+        };
+        if room.polls.vote(id, voter, option)? {
+            let poll = room.polls.tally(room_name, id).unwrap();
+            let _ = room.tx.send(RoomMsg::Poll(Arc::new(poll)));
+        }
+        Ok(())
+    }
+    // whoever created the poll, room operators and admins
+    // can close it, id defaults to the newest poll
+    fn close_poll(&self, room_name: &str, id: Option<u64>, closer: &str, admin: bool) -> Result<(), PollError> {
+        let Some(mut room) = self.live.get_mut(room_name) else {
+            return Err(PollError::NotFound);
+        };
+        let can_close_any = admin || room.operators.contains(closer);
+        let poll = room.polls.close(room_name, id, closer, can_close_any)?;
+        self.audit.room(room_name, || format!("{closer} closed poll {} - {}", poll.id, poll.to_text()));
+        let _ = room.tx.send(RoomMsg::Poll(Arc::new(poll)));
+        Ok(())
+    }
+    // operators can't be kicked or banned by other operators
+    fn check_kick(&self, room_name: &str, by: &str, name: &str, admin: bool) -> Result<(), EditError> {
+        let room = self.live.get(room_name).ok_or(EditError::NotFound)?;
+        if room_name == MAIN || !(admin || room.operators.contains(by)) || (!admin && room.operators.contains(name)) {
+            return Err(EditError::NotAllowed);
+        }
+        Ok(())
+    }
+    // the kicked user's own task does the leaving, returns
+    // false if they weren't in the room
+    fn kick(&self, names: &Names, room_name: &str, by: &str, name: &str, admin: bool) -> Result<bool, EditError> {
+        self.check_kick(room_name, by, name, admin)?;
+        if !self.live.get(room_name).is_some_and(|room| room.users.contains(name)) {
+            return Ok(false);
+        }
+        let kicked = UserMsg::Kicked {
+            room: room_name.into(),
+            by: by.into(),
+        };
+        match names.direct(name, kicked) {
+            Ok(_) => {
+                self.audit.room(room_name, || format!("{name} was kicked by {by}"));
+                Ok(true)
+            },
+            Err(_) => Ok(false),
+        }
+    }
+    // returns false if nothing changed, banning
+    // someone also kicks them if they're in the room
+    fn ban(&self, names: &Names, room_name: &str, by: &str, name: &str, banned: bool, admin: bool) -> Result<bool, EditError> {
+        self.check_kick(room_name, by, name, admin)?;
+        let changed = {
+            let mut room = self.live.get_mut(room_name).ok_or(EditError::NotFound)?;
+            match banned {
+                true => room.bans.insert(name.into()),
+                false => room.bans.remove(name),
+            }
+        };
+        if changed {
+            self.audit.room(room_name, || match banned {
+                true => format!("{name} was banned by {by}"),
+                false => format!("{name} was unbanned by {by}"),
+            });
+            self.store.record(Change::Ban {
+                room: room_name.into(),
+                name: name.into(),
+                banned,
+            });
+        }
+        if banned {
+            self.kick(names, room_name, by, name, admin)?;
+        }
+        Ok(changed)
+    }
+    fn is_banned(&self, room_name: &str, name: &str) -> bool {
+        match self.live.get(room_name) {
+            Some(room) => room.bans.contains(name),
+            None => self.dormant.get(room_name).is_some_and(|record| record.bans.contains(name)),
+        }
+    }
+    fn bans(&self, room_name: &str) -> Vec<CompactString> {
+        let mut bans: Vec<CompactString> = self
+            .live
+            .get(room_name)
+            .map(|room| room.bans.iter().cloned().collect())
+            .unwrap_or_default();
+        bans.sort();
+        bans
+    }
+    fn polls(&self, room_name: &str) -> Vec<Poll> {
+        self.live
+            .get(room_name)
+            .map(|room| room.polls.tallies(room_name))
+            .unwrap_or_default()
+    }
+    // only the author can edit a msg, checked before the
+    // new text goes through moderation
+    fn can_edit(&self, room_name: &str, id: u64, editor: &str) -> Result<(), EditError> {
+        let room = self.live.get(room_name).ok_or(EditError::NotFound)?;
+        let idx = room.find(id).ok_or(EditError::NotFound)?;
+        if !room.recent[idx].owned_by(editor) {
+            return Err(EditError::NotAllowed);
+        }
+        Ok(())
+    }
+    // rechecks since the msg can go while the text is moderated
+    fn edit(&self, room_name: &str, id: u64, editor: &str, text: String) -> Result<(), EditError> {
+        let Some(mut room) = self.live.get_mut(room_name) else {
+            return Err(EditError::NotFound);
+        };
+        let idx = room.find(id).ok_or(EditError::NotFound)?;
+        if !room.recent[idx].owned_by(editor) {
+            return Err(EditError::NotAllowed);
+        }
+        self.store.record(Change::Edited {
+            room: room_name.into(),
+            id,
+            text: text.clone(),
+        });
+        let edited = room.recent[idx].edit(text);
+        room.recent[idx] = edited.clone();
+        self.index.insert(edited.clone());
+        self.audit.room(room_name, || edited.audit_line());
+        let _ = room.tx.send(RoomMsg::Edited(edited));
+        Ok(())
+    }
+    // the author, room operators and admins can delete a msg
+    fn delete(&self, room_name: &str, id: u64, deleter: &str, admin: bool) -> Result<(), EditError> {
+        let Some(mut room) = self.live.get_mut(room_name) else {
+            return Err(EditError::NotFound);
+        };
+        let idx = room.find(id).ok_or(EditError::NotFound)?;
+        let allowed = admin || room.recent[idx].owned_by(deleter) || room.operators.contains(deleter);
+        if !allowed {
+            return Err(EditError::NotAllowed);
+        }
+        room.recent.remove(idx);
+        self.index.remove(id);
+        room.reactions.remove(&id);
+        self.store.record(Change::Deleted {
+            room: room_name.into(),
+            id,
+        });
+        self.audit.room(room_name, || format!("[{id}] was deleted by {deleter}"));
+        let _ = room.tx.send(RoomMsg::Deleted {
+            id,
+            by: deleter.into(),
+        });
+        Ok(())
+    }
+    fn leave(&self, room_name: &str, user_name: &str) {
+        let mut delete_room = false;
+        if let Some(mut room) = self.live.get_mut(room_name) {
+            room.users.remove(user_name);
+            room.log(Event::Left(user_name.into()), self.history_len);
+            self.audit.room(room_name, || format!("{user_name} left"));
+            delete_room = room.users.is_empty();
+        }
+        if delete_room {
+            self.audit.close(room_name);
+            if let Some((room_name, room)) = self.live.remove(room_name) {
+                let record = room.into_record();
+                if !record.is_empty() {
+                    self.dormant.insert(room_name, record);
+                }
+            }
+        }
+    }
+    fn change(&self, prev_room: &str, next_room: &str, user_name: &str, logged_in: bool) -> Sender<RoomMsg> {
+        self.leave(prev_room, user_name);
+        self.join(next_room, user_name, logged_in)
+    }
+    fn change_name(&self, room_name: &str, prev_name: &str, new_name: &str) {
+        if let Some(mut room) = self.live.get_mut(room_name) {
+            room.users.remove(prev_name);
+            room.users.insert(CompactString::from(new_name));
+            let renamed = Event::Renamed {
+                from: prev_name.into(),
+                to: new_name.into(),
+            };
+            room.log(renamed, self.history_len);
+            room.polls.rename(prev_name, new_name);
+            self.audit.room(room_name, || format!("{prev_name} is now {new_name}"));
+        }
+    }
+    fn list(&self) -> Vec<(CompactString, usize)> {
+        let mut list: Vec<_> = self
+            .live
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().users.len()))
+            .collect();
+        list.sort_by(|a, b| {
+            use std::cmp::Ordering;
+            match b.1.cmp(&a.1) {
+                Ordering::Equal => a.0.cmp(&b.0),
+                ordering => ordering,
+            }
+        });
+        list
+    }
+    fn list_users(&self, room_name: &str) -> Option<Vec<CompactString>> {
+        self
+            .live
+            .get(room_name)
+            .map(|room| {
+                let mut users = room
+                    .users
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>();
+                users.sort();
+                users
+            })
+    }
+}
+
+#[derive(Parser)]
+#[command(long_about = None)]
+struct Cli {
+    #[command(flatten)]
+    addr: AddrArgs,
+
+    /// Config file, see src/bin/chat-server/config.toml
+    /// for all options and their defaults
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let addr = cli.addr.socket_addr();
+    let config = Arc::new(Config::load(cli.config.as_deref())?);
+    let _guard = if cfg!(debug_assertions) {
+        stdout_logging();
+        tracing::info!("Running debug build");
+        None
+    } else {
+        let guard = file_logging(Rotation::DAILY, "chat-server.log");
+        tracing::info!("Running release build");
+        Some(guard)
+    };
+    if cfg!(not(target_env = "msvc")) {
+        tracing::info!("Using jemalloc as global allocator");
+    }
+    let server = TcpListener::bind(addr).await?;
+    tracing::info!("Listening on {addr}");
+    let (store, snapshot) = Store::open(&config.storage)?;
+    NEXT_MSG_ID.store(snapshot.next_msg_id.max(1), Ordering::Relaxed);
+    tracing::info!(
+        "Loaded {} accounts and {} rooms from storage",
+        snapshot.accounts.len(),
+        snapshot.rooms.len(),
+    );
+    let audit = Audit::start(&config.audit)?;
+    let mut name_generator = NameGenerator::new();
+    let rooms = Rooms::new(
+        snapshot.rooms,
+        store.clone(),
+        audit.clone(),
+        Moderation::new(&config.moderation),
+        config.storage.history_per_room,
+    );
+    let mut commands = Registry::builtin();
+    let plugins = Plugins::start(&config.plugins, &rooms, &mut commands)?;
+    let webhooks = Webhooks::start(&config.webhooks, &rooms).await?;
+    // users can't take a bot's name, and bots can't take
+    // each other's or a registered user's
+    let mut bots = plugins.bots;
+    for bot in webhooks.bots {
+        if bots.contains(&bot) {
+            anyhow::bail!("webhook bot {bot} has the same name as a plugin bot");
+        }
+        bots.insert(bot);
+    }
+    if let Some(bot) = bots.iter().find(|bot| snapshot.accounts.contains_key(*bot)) {
+        anyhow::bail!("bot name {bot} is already registered by a user");
+    }
+    let state = State {
+        names: Names::new(),
+        rooms,
+        accounts: Arc::new(Accounts::new(snapshot.accounts, &config.accounts, store)),
+        audit,
+        config: config.clone(),
+        metrics: Arc::new(Metrics::default()),
+        commands: Arc::new(commands),
+        bots: Arc::new(bots),
+        scheduler: Arc::new(Scheduler::default()),
+    };
+    let log_interval = Duration::from_secs(config.metrics.log_interval_secs.max(1));
+    tokio::spawn(state.metrics.clone().log_every(log_interval));
+    loop {
+        let (tcp, addr) = server.accept().await?;
+        if let Some(keepalive) = config.idle.keepalive() {
+            let keepalive = TcpKeepalive::new()
+                .with_time(keepalive)
+                .with_interval(keepalive);
+            if let Err(err) = SockRef::from(&tcp).set_tcp_keepalive(&keepalive) {
+                tracing::warn!("could not enable tcp keepalive for {addr}: {err}");
+            }
+        }
+        let (user_tx, user_rx) = mpsc::channel(USER_CHANNEL_CAPACITY);
+        let user = User::new(user_tx, addr);
+        let unique_name = state.names.get_unique(&mut name_generator, user, &state.accounts);
+        tracing::debug!("{addr} connected, name {unique_name}");
+        tokio::spawn(handle_user(tcp, state.clone(), unique_name, user_rx, addr));
+    }
+}
+
+// everything shared by all connections
+#[derive(Clone)]
+struct State {
+    names: Names,
+    rooms: Rooms,
+    accounts: Arc<Accounts>,
+    audit: Audit,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    commands: Arc<Registry>,
+    bots: Arc<HashSet<CompactString>>,
+    scheduler: Arc<Scheduler>,
+}
+
+// one connected user, commands get this to act on
+pub struct Session {
+    state: State,
+    addr: SocketAddr,
+    name: CompactString,
+    room_name: CompactString,
+    room_tx: Sender<RoomMsg>,
+    room_rx: broadcast::Receiver<RoomMsg>,
+    // whether this user owns their registered name
+    logged_in: bool,
+    // wrong passwords so far, see auth_failed
+    auth_failures: u32,
+    client: Client,
+}
+
+impl Session {
+    fn admin(&self) -> bool {
+        self.state.names.get(&self.name).is_some_and(|user| user.admin)
+    }
+    fn join(&mut self, new_room: CompactString) -> Result<(), ConnError> {
+        let Self { state, name, .. } = self;
+        let _ = self.room_tx.send(RoomMsg::Left(name.clone()));
+        self.room_tx = state.rooms.change(&self.room_name, &new_room, name, self.logged_in);
+        self.room_rx = self.room_tx.subscribe();
+        self.room_name = new_room;
+        state.names.update(name, |user| user.room = self.room_name.clone());
+        let _ = self.room_tx.send(RoomMsg::Joined(name.clone()));
+        if self.client.structured() {
+            self.client.event(&users_event(&state.names, &state.rooms, &self.room_name))?;
+        }
+        if let Some(topic) = state.rooms.topic(&self.room_name) {
+            self.client.topic(&self.room_name, &topic)?;
+        }
+        Ok(())
+    }
+    // returns false if the new name is taken
+    fn rename(&mut self, new_name: CompactString) -> bool {
+        if !self.state.names.rename(&self.name, new_name.clone()) {
+            return false;
+        }
+        self.state.rooms.change_name(&self.room_name, &self.name, &new_name);
+        self.state.scheduler.rename(&self.name, &new_name);
+        let _ = self.room_tx.send(RoomMsg::Renamed {
+            from: self.name.clone(),
+            to: new_name.clone(),
+        });
+        self.name = new_name;
+        true
+    }
+    // msgs sent while logged in stay the author's
+    fn post(&self, mut msg: Arc<ChatMsg>) -> Result<(), EditError> {
+        // can't fail, nobody else has seen it yet
+        Arc::get_mut(&mut msg).unwrap().registered = self.logged_in;
+        self.state.rooms.post(msg)
+    }
+    // every wrong password is answered slowly and
+    // too many of them end the connection
+    async fn auth_failed(&mut self, msg: &'static str) -> CommandResult {
+        self.auth_failures += 1;
+        tokio::time::sleep(AUTH_FAILURE_DELAY).await;
+        if self.auth_failures >= MAX_AUTH_FAILURES {
+            tracing::warn!("{} disconnected after {} wrong passwords", self.addr, self.auth_failures);
+            self.client.info(format!("{msg}, disconnecting after too many attempts"))?;
+            return Ok(Flow::Quit);
+        }
+        self.client.info(msg)?;
+        Ok(Flow::Continue)
+    }
+    // runs text through moderation, telling the sender if
+    // any filter acted, returns none if it was blocked
+    fn moderate(&self, text: String) -> Result<Option<String>, ConnError> {
+        let outcome = self.state.rooms.moderate(&self.room_name, &self.name, text);
+        if !outcome.reasons.is_empty() {
+            self.client.info(outcome.notice())?;
+        }
+        Ok(outcome.text)
+    }
+}
+
+// none once the room is gone or for text clients, which
+// disables the branch until the next time round the loop
+async fn recv_typing(typing_rx: &mut Option<broadcast::Receiver<Typing>>) -> Option<Typing> {
+    let typing_rx = typing_rx.as_mut()?;
+    loop {
+        match typing_rx.recv().await {
+            Ok(typing) => return Some(typing),
+            // stale typing events aren't worth reporting
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+async fn handle_user(
+    tcp: TcpStream,
+    state: State,
+    name: CompactString,
+    mut user_rx: mpsc::Receiver<UserMsg>,
+    addr: SocketAddr,
+) {
+    let outbound = Outbound::new(&state.config.outbound, state.metrics.clone());
+    let (reader, writer) = tcp.into_split();
+    let mut stream = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_MSG_LEN));
+    let write_timeout = state.config.outbound.write_timeout();
+    let mut writer = tokio::spawn(outbound.clone().write_loop(writer, write_timeout));
+    let mut writer_exited = false;
+    let client = Client::new(outbound);
+    let _ = client.info(format!("{}\nYou are {name}", state.commands.help(false)));
+    let room_name = CompactString::from(MAIN);
+    let room_tx = state.rooms.join(&room_name, &name, false);
+    let room_rx = room_tx.subscribe();
+    let _ = room_tx.send(RoomMsg::Joined(name.clone()));
+    let config = state.config.clone();
+    let commands = state.commands.clone();
+    let mut session = Session {
+        state,
+        addr,
+        name,
+        room_name,
+        room_tx,
+        room_rx,
+        logged_in: false,
+        auth_failures: 0,
+        client,
+    };
+    let mut discarding_long_msg = false;
+    // when we last told the room they're typing
+    let mut typing_since: Option<Instant> = None;
+    // only subscribed to by structured clients, and
+    // again whenever they move to another room
+    let mut typing_rx: Option<broadcast::Receiver<Typing>> = None;
+    let mut typing_room = CompactString::default();
+    let mut idle = Idle::new();
+    let idle_timer = tokio::time::sleep_until(idle.next_tick(&config.idle, false));
+    tokio::pin!(idle_timer);
+    let exit_result = loop {
+        if session.client.structured() && typing_room != session.room_name {
+            typing_rx = session.state.rooms.watch_typing(&session.room_name);
+            typing_room = session.room_name.clone();
+            typing_since = None;
+        }
+        tokio::select! {
+            user_msg = stream.next() => {
+                let user_msg = match user_msg {
+                    Some(msg) => match msg {
+                        Ok(ok) => ok,
+                        Err(LinesCodecError::MaxLineLengthExceeded) => {
+                            b!(match session.client.protocol {
+                                Protocol::Text => session.client.info(format!("Messages can only be {MAX_MSG_LEN} chars long")),
+                                Protocol::Json => session.client.info(format!("Events can only be {MAX_CLIENT_EVENT_LEN} chars long")),
+                            });
+                            discarding_long_msg = true;
+                            continue;
+                        },
+                        Err(LinesCodecError::Io(io_err)) => {
+                            match io_err.kind() {
+                                // user typed invalid utf8 like ^C or ^D
+                                // and is probably trying to quit
+                                ErrorKind::InvalidData | ErrorKind::InvalidInput => {
+                                    break Ok(());
+                                },
+                                // user disconnected
+                                ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {
+                                    break Ok(());
+                                },
+                                // unexpected err, re-throw it
+                                _ => break Err(LinesCodecError::Io(io_err).into()),
+                            }
+                        }
+                    },
+                    // user disconnected or sent a
+                    // msg that was too long in their
+                    // previous submission
+                    None => {
+                        if !discarding_long_msg {
+                            break Ok(());
+                        }
+                        discarding_long_msg = false;
+                        continue;
+                    }
+                };
+                let user_msg = match session.client.protocol {
+                    Protocol::Text => user_msg,
+                    Protocol::Json => match serde_json::from_str(&user_msg) {
+                        Ok(ClientEvent::Line { text }) if text.len() > MAX_MSG_LEN => {
+                            b!(session.client.info(format!("Messages can only be {MAX_MSG_LEN} chars long")));
+                            continue;
+                        },
+                        Ok(ClientEvent::Line { text }) if !single_line(&text) => {
+                            b!(session.client.info("Messages can't contain control characters like line breaks"));
+                            continue;
+                        },
+                        Ok(ClientEvent::Line { text }) => text,
+                        Ok(ClientEvent::Pong { .. }) => {
+                            idle.seen();
+                            continue;
+                        },
+                        // straight to the room, nothing keeps them, and
+                        // they don't count as activity since a client
+                        // could send them forever without a user
+                        Ok(ClientEvent::TypingStart) => {
+                            if typing_since.is_none_or(|since| since.elapsed() >= MIN_TYPING_INTERVAL) {
+                                typing_since = Some(Instant::now());
+                                let typing = Typing { name: session.name.clone(), typing: true };
+                                session.state.rooms.typing(&session.room_name, typing);
+                            }
+                            continue;
+                        },
+                        Ok(ClientEvent::TypingStop) => {
+                            if typing_since.take().is_some() {
+                                let typing = Typing { name: session.name.clone(), typing: false };
+                                session.state.rooms.typing(&session.room_name, typing);
+                            }
+                            continue;
+                        },
+                        Err(err) => {
+                            b!(session.client.info(format!("Could not parse event: {err}")));
+                            continue;
+                        },
+                    },
+                };
+                let Session { state, name, room_name, room_tx, client, .. } = &mut session;
+                // only being away automatically ends on activity,
+                // users who set /away stay away until /back
+                state.names.update(name, |user| user.last_active = Instant::now());
+                if idle.active() {
+                    // the timer was left on the timeout while they were away
+                    idle_timer.as_mut().reset(idle.next_tick(&config.idle, client.structured()));
+                    // /back brings them back itself, so it's left to say so
+                    let back = user_msg.split_ascii_whitespace().next() == Some("/back");
+                    if !back && state.names.away(name).is_some_and(|away| away.auto) {
+                        state.names.set_away(name, None);
+                        let _ = room_tx.send(RoomMsg::Back(name.clone()));
+                    }
+                }
+                if user_msg == SWITCH_TO_JSON {
+                    client.protocol = Protocol::Json;
+                    client.outbound.set_max_line_len(MAX_EVENT_LEN);
+                    // we're between lines so nothing buffered is lost
+                    *stream.decoder_mut() = LinesCodec::new_with_max_length(MAX_CLIENT_EVENT_LEN);
+                    b!(client.event(&ServerEvent::Welcome {
+                        name: name.to_string(),
+                        room: room_name.to_string(),
+                    }));
+                    idle_timer.as_mut().reset(idle.next_tick(&config.idle, true));
+                    b!(client.event(&users_event(&state.names, &state.rooms, room_name)));
+                } else if let Some(command) = user_msg.strip_prefix('/') {
+                    match b!(commands.run(&mut session, command).await) {
+                        Flow::Continue => (),
+                        Flow::Quit => break Ok(()),
+                    }
+                } else {
+                    // let the sender know if anyone
+                    // they mentioned won't see it soon
+                    let mut away_notes = Vec::new();
+                    for mention in user_msg.split_ascii_whitespace().filter_map(|word| word.strip_prefix('@')) {
+                        let mention = mention.trim_end_matches(|c: char| !c.is_ascii_alphanumeric());
+                        if mention == *name {
+                            continue;
+                        }
+                        if let Some(away) = state.names.away(mention) {
+                            away_notes.push(format!("{mention} is {}", away.describe()));
+                        }
+                    }
+                    let Some(text) = b!(session.moderate(user_msg)) else {
+                        continue;
+                    };
+                    let _ = session.post(ChatMsg::new(&session.room_name, &session.name, text));
+                    if !away_notes.is_empty() {
+                        b!(session.client.info(away_notes.join("\n")));
+                    }
+                }
+            },
+            peer_msg = session.room_rx.recv() => {
+                let peer_msg = match peer_msg {
+                    Ok(ok) => ok,
+                    // we would get this error if all tx
+                    // were dropped for this rx, which is not
+                    // possible since we're holding a tx,
+                    // but if this were to somehow ever happen
+                    // we just put the user back into the main
+                    // room
+                    Err(RecvError::Closed) => {
+                        b!(session.join(MAIN.into()));
+                        continue;
+                    },
+                    // under high load we might not deliver all msgs
+                    // to all users in a room, in which case we let
+                    // them know that we dropped some msgs
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!("Server dropped {n} messages for {} with {} users", session.room_name, session.room_tx.receiver_count());
+                        b!(session.client.info(format!("Server is very busy and dropped {n} messages, sorry!")));
+                        continue;
+                    }
+                };
+                // only operators and admins see what moderation did
+                if let RoomMsg::Moderated(_) = &peer_msg {
+                    if !session.admin() && !session.state.rooms.is_operator(&session.room_name, &session.name) {
+                        continue;
+                    }
+                }
+                b!(session.client.room_msg(&peer_msg, &session.name, &session.room_name));
+            },
+            Some(typing) = recv_typing(&mut typing_rx) => {
+                // nobody needs to be told they're typing
+                if typing.name != session.name {
+                    b!(session.client.typing(&typing, &session.room_name));
+                }
+            },
+            Some(user_msg) = user_rx.recv() => {
+                match user_msg {
+                    UserMsg::Direct { from, text } => {
+                        b!(session.client.direct(&from, &session.name, &text));
+                    },
+                    UserMsg::Reminder(reminder) => {
+                        b!(session.client.reminder(reminder));
+                    },
+                    UserMsg::Kicked { room, by } => {
+                        if room == session.room_name {
+                            b!(session.client.info(format!("You were kicked from {room} by {by}")));
+                            b!(session.join(MAIN.into()));
+                        }
+                    },
+                }
+            },
+            _ = &mut idle_timer => {
+                let Session { state, name, room_tx, client, .. } = &session;
+                let (due, next_tick) = idle.tick(&config.idle, client.structured());
+                if due.timed_out {
+                    tracing::debug!("{addr} timed out, name {name}");
+                    let _ = client.info("Disconnected for being idle");
+                    break Ok(());
+                }
+                // users who set /away themselves are already away
+                if due.away && state.names.away(name).is_none() {
+                    let away = Away {
+                        reason: None,
+                        auto: true,
+                    };
+                    state.names.set_away(name, Some(away.clone()));
+                    let _ = room_tx.send(RoomMsg::Away {
+                        name: name.clone(),
+                        away,
+                    });
+                }
+                if let Some(id) = due.ping {
+                    b!(client.event(&ServerEvent::Ping { id }));
+                }
+                idle_timer.as_mut().reset(next_tick);
+            },
+            write_result = &mut writer => {
+                writer_exited = true;
+                // writer task only panics if something
+                // went very wrong, so treat it like
+                // the user disconnected
+                break write_result.unwrap_or(Ok(()));
+            },
+        }
+    };
+    let Session { state, name, room_name, room_tx, client, logged_in, .. } = session;
+    if !logged_in {
+        state.scheduler.forget(&name);
+    }
+    if !writer_exited {
+        // give the writer a chance to flush what's queued
+        client.outbound.close();
+        if tokio::time::timeout(write_timeout, &mut writer).await.is_err() {
+            writer.abort();
+        }
+    }
+    let _ = room_tx.send(RoomMsg::Left(name.clone()));
+    tracing::debug!("{addr} disconnected, name {name}");
+    state.rooms.leave(&room_name, &name);
+    state.names.remove(&name);
+    should_exit(exit_result);
+}
+
+// everyone in the room along with whether they're away,
+// sent to structured clients whenever they join a room
+fn users_event(names: &Names, rooms: &Rooms, room_name: &str) -> ServerEvent {
+    let users = rooms.list_users(room_name).unwrap_or_default();
+    ServerEvent::Users {
+        room: room_name.to_owned(),
+        users: names.presence(users),
+    }
+}
+
+pub enum ConnError {
+    Codec(LinesCodecError),
+    // user read too slowly and the
+    // disconnect policy kicked in
+    SlowConsumer,
+    // user's socket stopped accepting writes
+    WriteTimeout,
+}
+
+impl From<LinesCodecError> for ConnError {
+    fn from(err: LinesCodecError) -> Self {
+        ConnError::Codec(err)
+    }
+}
+
+// kinds of io errors we don't care about logging
+const IGNORE_KINDS: [ErrorKind; 2] = [ErrorKind::BrokenPipe, ErrorKind::ConnectionReset];
+
+// returns true if caller should exit, false if not
+fn should_exit(result: Result<(), ConnError>) -> bool {
+    fn ignore(io_err: &io::Error) -> bool {
+        IGNORE_KINDS.contains(&io_err.kind())
+    }
+    match result {
+        Ok(_) => false,
+        Err(ConnError::SlowConsumer) => {
+            tracing::debug!("disconnected slow consumer");
+            true
+        },
+        Err(ConnError::WriteTimeout) => {
+            tracing::debug!("disconnected user after write timed out");
+            true
+        },
+        Err(ConnError::Codec(LinesCodecError::MaxLineLengthExceeded)) => true,
+        Err(ConnError::Codec(LinesCodecError::Io(err))) if ignore(&err) => true,
+        // something actually unexpected happened, log it
+        Err(ConnError::Codec(LinesCodecError::Io(err))) => {
+            tracing::error!("unexpected error: {err}");
+            true
+        },
+    }
+}
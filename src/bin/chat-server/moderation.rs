@@ -0,0 +1,316 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+use compact_str::CompactString;
+use tokio::time::Instant;
+use crate::config::{ModerationConfig, WordAction};
+
+// every msg passes through these filters before it's posted
+// or an edit is applied, anything a filter does is told to
+// the sender and shown to the room's operators, custom
+// filters implement Filter and are added with Moderation::add
+
+pub struct Post<'a> {
+    pub room: &'a str,
+    pub from: &'a str,
+    pub text: &'a str,
+}
+
+pub enum Action {
+    Pass,
+    // post this text instead, later filters see it too
+    Rewrite {
+        text: String,
+        reason: String,
+    },
+    // post it anyway but tell the sender
+    Warn(String),
+    // don't post it
+    Block(String),
+}
+
+pub trait Filter: Send + Sync {
+    fn check(&self, post: &Post<'_>) -> Action;
+}
+
+pub struct Outcome {
+    // none if the msg was blocked
+    pub text: Option<String>,
+    // why each filter acted, empty if none did
+    pub reasons: Vec<String>,
+}
+
+impl Outcome {
+    // what the sender is told
+    pub fn notice(&self) -> String {
+        let reasons = self.reasons.join(", ");
+        match self.text {
+            Some(_) => format!("Your message was flagged - {reasons}"),
+            None => format!("Your message was not sent - {reasons}"),
+        }
+    }
+}
+
+pub struct Moderation {
+    filters: Vec<Box<dyn Filter>>,
+}
+
+impl Moderation {
+    pub fn new(config: &ModerationConfig) -> Self {
+        let mut moderation = Self { filters: Vec::new() };
+        for list in &config.word_lists {
+            moderation.add(WordFilter::new(list.action, &list.words));
+        }
+        if !config.block_links_in.is_empty() {
+            moderation.add(LinkFilter {
+                rooms: config.block_links_in.iter().map(CompactString::from).collect(),
+            });
+        }
+        if config.repeat_limit > 0 {
+            moderation.add(RepeatFilter {
+                limit: config.repeat_limit,
+                window: Duration::from_secs(config.repeat_window_secs),
+                last: Mutex::new(HashMap::new()),
+            });
+        }
+        moderation
+    }
+    pub fn add(&mut self, filter: impl Filter + 'static) {
+        self.filters.push(Box::new(filter));
+    }
+    pub fn check(&self, room: &str, from: &str, text: String) -> Outcome {
+        let mut text = text;
+        let mut reasons = Vec::new();
+        for filter in &self.filters {
+            let post = Post { room, from, text: &text };
+            match filter.check(&post) {
+                Action::Pass => (),
+                Action::Rewrite { text: rewritten, reason } => {
+                    text = rewritten;
+                    reasons.push(reason);
+                },
+                Action::Warn(reason) => reasons.push(reason),
+                Action::Block(reason) => {
+                    reasons.push(reason);
+                    return Outcome { text: None, reasons };
+                },
+            }
+        }
+        Outcome { text: Some(text), reasons }
+    }
+}
+
+// byte ranges of the alphanumeric runs in text
+fn words(text: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut start = None;
+    text.char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+        .filter_map(move |(idx, c)| match (c.is_alphanumeric(), start) {
+            (true, None) => {
+                start = Some(idx);
+                None
+            },
+            (false, Some(word_start)) => {
+                start = None;
+                Some((word_start, idx))
+            },
+            _ => None,
+        })
+}
+
+// banned words, matched as whole words ignoring case
+struct WordFilter {
+    action: WordAction,
+    words: HashSet<String>,
+}
+
+impl WordFilter {
+    fn new(action: WordAction, words: &[String]) -> Self {
+        Self {
+            action,
+            words: words.iter().map(|word| word.to_lowercase()).collect(),
+        }
+    }
+}
+
+impl Filter for WordFilter {
+    fn check(&self, post: &Post<'_>) -> Action {
+        let banned: Vec<(usize, usize)> = words(post.text)
+            .filter(|&(start, end)| self.words.contains(&post.text[start..end].to_lowercase()))
+            .collect();
+        if banned.is_empty() {
+            return Action::Pass;
+        }
+        match self.action {
+            WordAction::Block => Action::Block("contains a banned word".to_owned()),
+            WordAction::Warn => Action::Warn("contains a banned word".to_owned()),
+            WordAction::Mask => {
+                let mut text = String::with_capacity(post.text.len());
+                let mut copied = 0;
+                for (start, end) in banned {
+                    text.push_str(&post.text[copied..start]);
+                    text.extend(post.text[start..end].chars().map(|_| '*'));
+                    copied = end;
+                }
+                text.push_str(&post.text[copied..]);
+                Action::Rewrite {
+                    text,
+                    reason: "banned words were masked".to_owned(),
+                }
+            },
+        }
+    }
+}
+
+// blocks links in some rooms, or all with "*"
+struct LinkFilter {
+    rooms: HashSet<CompactString>,
+}
+
+impl Filter for LinkFilter {
+    fn check(&self, post: &Post<'_>) -> Action {
+        if !self.rooms.contains(post.room) && !self.rooms.contains("*") {
+            return Action::Pass;
+        }
+        let has_link = post.text.split_whitespace().any(|word| {
+            let word = word.to_ascii_lowercase();
+            word.contains("://") || word.starts_with("www.")
+        });
+        match has_link {
+            true => Action::Block(format!("links aren't allowed in {}", post.room)),
+            false => Action::Pass,
+        }
+    }
+}
+
+struct Repeated {
+    text: String,
+    count: usize,
+    since: Instant,
+}
+
+// blocks the same msg sent over and over by the same user
+struct RepeatFilter {
+    limit: usize,
+    window: Duration,
+    last: Mutex<HashMap<CompactString, Repeated>>,
+}
+
+impl Filter for RepeatFilter {
+    fn check(&self, post: &Post<'_>) -> Action {
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        // forget users who stopped repeating themselves
+        if last.len() >= 1024 {
+            last.retain(|_, repeated| now - repeated.since < self.window);
+        }
+        let repeated = last.entry(post.from.into()).or_insert_with(|| Repeated {
+            text: String::new(),
+            count: 0,
+            since: now,
+        });
+        if repeated.text == post.text && now - repeated.since < self.window {
+            repeated.count += 1;
+        } else {
+            repeated.text = post.text.to_owned();
+            repeated.count = 1;
+            repeated.since = now;
+        }
+        match repeated.count > self.limit {
+            true => Action::Block(format!("sent the same message more than {} times in a row", self.limit)),
+            false => Action::Pass,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word_filter(action: WordAction) -> Moderation {
+        let mut moderation = Moderation { filters: Vec::new() };
+        moderation.add(WordFilter::new(action, &["Darn".to_owned(), "heck".to_owned()]));
+        moderation
+    }
+
+    fn repeat_filter(limit: usize) -> Moderation {
+        let mut moderation = Moderation { filters: Vec::new() };
+        moderation.add(RepeatFilter {
+            limit,
+            window: Duration::from_secs(30),
+            last: Mutex::new(HashMap::new()),
+        });
+        moderation
+    }
+
+    fn passes(moderation: &Moderation, room: &str, from: &str, text: &str) -> bool {
+        moderation.check(room, from, text.to_owned()).text.is_some()
+    }
+
+    #[test]
+    fn splits_words() {
+        let text = "hi, wörld-2 !";
+        let found: Vec<&str> = words(text).map(|(start, end)| &text[start..end]).collect();
+        assert_eq!(found, ["hi", "wörld", "2"]);
+        assert_eq!(words("").count(), 0);
+    }
+
+    #[test]
+    fn masks_banned_words() {
+        let outcome = word_filter(WordAction::Mask).check("den", "al", "oh DARN, what the heck!".to_owned());
+        assert_eq!(outcome.text.as_deref(), Some("oh ****, what the ****!"));
+        assert_eq!(outcome.reasons, ["banned words were masked"]);
+    }
+
+    #[test]
+    fn matches_whole_words_only() {
+        let outcome = word_filter(WordAction::Mask).check("den", "al", "darning hecks".to_owned());
+        assert_eq!(outcome.text.as_deref(), Some("darning hecks"));
+        assert!(outcome.reasons.is_empty());
+    }
+
+    #[test]
+    fn blocks_or_warns_on_banned_words() {
+        let outcome = word_filter(WordAction::Block).check("den", "al", "Heck no".to_owned());
+        assert_eq!(outcome.text, None);
+        assert_eq!(outcome.notice(), "Your message was not sent - contains a banned word");
+        let outcome = word_filter(WordAction::Warn).check("den", "al", "Heck no".to_owned());
+        assert_eq!(outcome.text.as_deref(), Some("Heck no"));
+        assert_eq!(outcome.notice(), "Your message was flagged - contains a banned word");
+    }
+
+    #[test]
+    fn blocks_links_in_some_rooms() {
+        let mut moderation = Moderation { filters: Vec::new() };
+        moderation.add(LinkFilter { rooms: HashSet::from(["den".into()]) });
+        assert!(!passes(&moderation, "den", "al", "see HTTPS://example.com"));
+        assert!(!passes(&moderation, "den", "al", "see www.example.com"));
+        assert!(passes(&moderation, "den", "al", "see example.com"));
+        assert!(passes(&moderation, "main", "al", "see https://example.com"));
+        let mut moderation = Moderation { filters: Vec::new() };
+        moderation.add(LinkFilter { rooms: HashSet::from(["*".into()]) });
+        assert!(!passes(&moderation, "main", "al", "see https://example.com"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn blocks_repeats_past_the_limit() {
+        let moderation = repeat_filter(2);
+        assert!(passes(&moderation, "den", "al", "spam"));
+        assert!(passes(&moderation, "den", "al", "spam"));
+        assert!(!passes(&moderation, "den", "al", "spam"));
+        // others aren't affected
+        assert!(passes(&moderation, "den", "bo", "spam"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resets_repeats() {
+        let moderation = repeat_filter(2);
+        assert!(passes(&moderation, "den", "al", "spam"));
+        assert!(passes(&moderation, "den", "al", "spam"));
+        assert!(passes(&moderation, "den", "al", "eggs"));
+        assert!(passes(&moderation, "den", "al", "spam"));
+        assert!(passes(&moderation, "den", "al", "spam"));
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(passes(&moderation, "den", "al", "spam"));
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::VecDeque;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::SinkExt;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::Notify;
+use tokio_util::codec::{FramedWrite, LinesCodec};
+use crate::config::{OutboundConfig, SlowPolicy};
+use crate::{ConnError, MAX_MSG_LEN};
+
+// every user gets a bounded queue of outbound msgs
+// which a separate writer task drains into their
+// socket, so a user who reads slowly never stalls
+// the task reading their input and room msgs
+
+// longest line we write to plain text users, anything
+// longer has to be split with wrap_long_lines first
+pub const MAX_LINE_LEN: usize = MAX_MSG_LEN + 100;
+// structured clients parse what we send rather than
+// read it, so a whole users list fits in one event
+pub const MAX_EVENT_LEN: usize = 64 * 1024;
+
+pub enum Line {
+    Shared(Arc<str>),
+    Owned(String),
+    Static(&'static str),
+}
+
+impl AsRef<str> for Line {
+    fn as_ref(&self) -> &str {
+        match self {
+            Line::Shared(line) => line,
+            Line::Owned(line) => line,
+            Line::Static(line) => line,
+        }
+    }
+}
+
+impl From<Arc<str>> for Line {
+    fn from(line: Arc<str>) -> Self {
+        Line::Shared(line)
+    }
+}
+
+impl From<String> for Line {
+    fn from(line: String) -> Self {
+        Line::Owned(line)
+    }
+}
+
+impl From<&'static str> for Line {
+    fn from(line: &'static str) -> Self {
+        Line::Static(line)
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    // msgs dropped from full queues
+    dropped_msgs: AtomicU64,
+    // times users were told msgs were dropped
+    drop_notices: AtomicU64,
+    // users disconnected by the disconnect policy
+    slow_disconnects: AtomicU64,
+    // users disconnected because a write timed out
+    write_timeouts: AtomicU64,
+    // lines not written for being too long
+    oversized_lines: AtomicU64,
+}
+
+impl Metrics {
+    fn snapshot(&self) -> [u64; 5] {
+        [
+            self.dropped_msgs.load(Ordering::Relaxed),
+            self.drop_notices.load(Ordering::Relaxed),
+            self.slow_disconnects.load(Ordering::Relaxed),
+            self.write_timeouts.load(Ordering::Relaxed),
+            self.oversized_lines.load(Ordering::Relaxed),
+        ]
+    }
+    pub async fn log_every(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        let mut prev = self.snapshot();
+        loop {
+            interval.tick().await;
+            let next = self.snapshot();
+            if next == prev {
+                continue;
+            }
+            let [dropped, notices, disconnects, timeouts, oversized] = next;
+            tracing::info!(
+                "slow consumers - dropped msgs {dropped}, drop notices {notices}, \
+                slow disconnects {disconnects}, write timeouts {timeouts}, \
+                oversized lines {oversized}"
+            );
+            prev = next;
+        }
+    }
+}
+
+struct State {
+    lines: VecDeque<Line>,
+    // dropped msgs the user hasn't been told about yet
+    unreported: u64,
+    // dropped msgs since the queue was last empty
+    overflowed: usize,
+    closed: bool,
+}
+
+struct Queue {
+    state: Mutex<State>,
+    notify: Notify,
+    capacity: usize,
+    policy: SlowPolicy,
+    disconnect_after: usize,
+    max_line_len: AtomicUsize,
+    metrics: Arc<Metrics>,
+}
+
+enum Next {
+    Line(Line),
+    Dropped(u64),
+    Closed,
+}
+
+#[derive(Clone)]
+#[repr(transparent)]
+pub struct Outbound(Arc<Queue>);
+
+impl Outbound {
+    pub fn new(config: &OutboundConfig, metrics: Arc<Metrics>) -> Self {
+        let state = State {
+            lines: VecDeque::with_capacity(config.queue_capacity.min(64)),
+            unreported: 0,
+            overflowed: 0,
+            closed: false,
+        };
+        Self(Arc::new(Queue {
+            state: Mutex::new(state),
+            notify: Notify::new(),
+            capacity: config.queue_capacity.max(1),
+            policy: config.policy,
+            disconnect_after: config.disconnect_after,
+            max_line_len: AtomicUsize::new(MAX_LINE_LEN),
+            metrics,
+        }))
+    }
+    // raised once a user switches to the structured protocol
+    pub fn set_max_line_len(&self, len: usize) {
+        self.0.max_line_len.store(len, Ordering::Relaxed);
+    }
+    // queues a line for the writer task, only fails if
+    // the user should be disconnected for reading too slowly
+    pub fn send(&self, line: impl Into<Line>) -> Result<(), ConnError> {
+        let queue = &self.0;
+        let mut state = queue.state.lock().unwrap();
+        if state.closed {
+            return Ok(());
+        }
+        if state.lines.len() >= queue.capacity {
+            queue.metrics.dropped_msgs.fetch_add(1, Ordering::Relaxed);
+            state.unreported += 1;
+            state.overflowed += 1;
+            match queue.policy {
+                SlowPolicy::DropOldest => {
+                    state.lines.pop_front();
+                },
+                SlowPolicy::Disconnect => {
+                    if state.overflowed >= queue.disconnect_after {
+                        queue.metrics.slow_disconnects.fetch_add(1, Ordering::Relaxed);
+                        state.closed = true;
+                        state.lines.clear();
+                        queue.notify.notify_one();
+                        return Err(ConnError::SlowConsumer);
+                    }
+                    return Ok(());
+                },
+            }
+        }
+        state.lines.push_back(line.into());
+        queue.notify.notify_one();
+        Ok(())
+    }
+    // writer task exits after writing what's already queued
+    pub fn close(&self) {
+        self.0.state.lock().unwrap().closed = true;
+        self.0.notify.notify_one();
+    }
+    async fn next(&self) -> Next {
+        let queue = &self.0;
+        loop {
+            {
+                let mut state = queue.state.lock().unwrap();
+                if state.unreported > 0 && !state.lines.is_empty() {
+                    let dropped = state.unreported;
+                    state.unreported = 0;
+                    return Next::Dropped(dropped);
+                }
+                if let Some(line) = state.lines.pop_front() {
+                    if state.lines.is_empty() {
+                        state.overflowed = 0;
+                    }
+                    return Next::Line(line);
+                }
+                if state.closed {
+                    return Next::Closed;
+                }
+            }
+            // notify_one stores a permit if nobody is waiting
+            // so we can't miss a line sent after we unlocked
+            queue.notify.notified().await;
+        }
+    }
+    // multi-line msgs like /help are checked line by line
+    fn too_long(&self, line: &Line) -> bool {
+        let max_len = self.0.max_line_len.load(Ordering::Relaxed);
+        let too_long = line.as_ref().split('\n').find(|line| line.len() > max_len);
+        if let Some(too_long) = too_long {
+            tracing::warn!("not writing {} byte line: {}...", too_long.len(), truncate(too_long, 80));
+        }
+        too_long.is_some()
+    }
+    // drains the queue into the socket until the queue
+    // is closed or a write fails or times out
+    pub async fn write_loop(self, writer: OwnedWriteHalf, write_timeout: Duration) -> Result<(), ConnError> {
+        let mut sink = FramedWrite::new(writer, LinesCodec::new());
+        loop {
+            let line = match self.next().await {
+                Next::Line(line) => line,
+                Next::Dropped(dropped) => {
+                    self.0.metrics.drop_notices.fetch_add(1, Ordering::Relaxed);
+                    Line::Owned(format!("You're reading too slowly, server dropped {dropped} messages"))
+                },
+                Next::Closed => return Ok(()),
+            };
+            // our own bug rather than the user's
+            // fault, so tell them and carry on
+            let line = match self.too_long(&line) {
+                false => line,
+                true => {
+                    self.0.metrics.oversized_lines.fetch_add(1, Ordering::Relaxed);
+                    Line::Static("Server tried to send you a message that was too long, sorry!")
+                },
+            };
+            match tokio::time::timeout(write_timeout, sink.send(line)).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    self.0.metrics.write_timeouts.fetch_add(1, Ordering::Relaxed);
+                    self.close();
+                    return Err(ConnError::WriteTimeout);
+                },
+            }
+        }
+    }
+}
+
+// largest prefix of text no longer than max_len bytes
+fn truncate(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+// splits lines longer than MAX_LINE_LEN, preferably after
+// a comma or space so list responses split between items
+pub fn wrap_long_lines(text: &str) -> Cow<'_, str> {
+    if text.split('\n').all(|line| line.len() <= MAX_LINE_LEN) {
+        return Cow::Borrowed(text);
+    }
+    let mut wrapped = String::with_capacity(text.len() + text.len() / MAX_LINE_LEN + 1);
+    for (idx, mut line) in text.split('\n').enumerate() {
+        if idx > 0 {
+            wrapped.push('\n');
+        }
+        while line.len() > MAX_LINE_LEN {
+            let chunk = truncate(line, MAX_LINE_LEN);
+            let end = match chunk.rfind(", ") {
+                Some(end) if end > 0 => end + 1,
+                _ => match chunk.rfind(' ') {
+                    Some(end) if end > 0 => end,
+                    _ => chunk.len(),
+                },
+            };
+            wrapped.push_str(&line[..end]);
+            wrapped.push('\n');
+            line = line[end..].trim_start();
+        }
+        wrapped.push_str(line);
+    }
+    Cow::Owned(wrapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbound(capacity: usize, policy: SlowPolicy, disconnect_after: usize) -> Outbound {
+        let config = OutboundConfig {
+            queue_capacity: capacity,
+            policy,
+            disconnect_after,
+            ..OutboundConfig::default()
+        };
+        Outbound::new(&config, Arc::new(Metrics::default()))
+    }
+
+    // what the writer task would be handed next
+    async fn next(outbound: &Outbound) -> String {
+        match outbound.next().await {
+            Next::Line(line) => line.as_ref().to_owned(),
+            Next::Dropped(dropped) => format!("dropped {dropped}"),
+            Next::Closed => "closed".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_oldest_lines() {
+        let outbound = outbound(3, SlowPolicy::DropOldest, 1);
+        for idx in 0..5 {
+            assert!(outbound.send(format!("line {idx}")).is_ok());
+        }
+        outbound.close();
+        assert_eq!(next(&outbound).await, "dropped 2");
+        assert_eq!(next(&outbound).await, "line 2");
+        assert_eq!(next(&outbound).await, "line 3");
+        assert_eq!(next(&outbound).await, "line 4");
+        assert_eq!(next(&outbound).await, "closed");
+        assert_eq!(outbound.0.metrics.dropped_msgs.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn disconnects_slow_readers() {
+        let outbound = outbound(2, SlowPolicy::Disconnect, 2);
+        assert!(outbound.send("line 0").is_ok());
+        assert!(outbound.send("line 1").is_ok());
+        // the newest line is dropped until too many were
+        assert!(outbound.send("line 2").is_ok());
+        assert!(matches!(outbound.send("line 3"), Err(ConnError::SlowConsumer)));
+        assert_eq!(next(&outbound).await, "closed");
+        // nothing more is queued once it's closed
+        assert!(outbound.send("line 4").is_ok());
+        assert_eq!(next(&outbound).await, "closed");
+        assert_eq!(outbound.0.metrics.slow_disconnects.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn forgives_overflows_once_caught_up() {
+        let outbound = outbound(1, SlowPolicy::Disconnect, 2);
+        assert!(outbound.send("line 0").is_ok());
+        assert!(outbound.send("line 1").is_ok());
+        assert_eq!(next(&outbound).await, "dropped 1");
+        assert_eq!(next(&outbound).await, "line 0");
+        // a second overflow would disconnect them without the reset
+        assert!(outbound.send("line 2").is_ok());
+        assert!(outbound.send("line 3").is_ok());
+        assert_eq!(next(&outbound).await, "dropped 1");
+        assert_eq!(next(&outbound).await, "line 2");
+    }
+
+    #[test]
+    fn leaves_short_lines() {
+        let text = "line 1\nline 2";
+        assert!(matches!(wrap_long_lines(text), Cow::Borrowed(borrowed) if borrowed == text));
+    }
+
+    #[test]
+    fn wraps_between_list_items() {
+        let names: Vec<String> = (0..100).map(|idx| format!("user{idx:02}")).collect();
+        let text = names.join(", ");
+        let wrapped = wrap_long_lines(&text);
+        let lines: Vec<&str> = wrapped.split('\n').collect();
+        assert!(lines.len() > 1);
+        for line in &lines[..lines.len() - 1] {
+            assert!(line.len() <= MAX_LINE_LEN);
+            assert!(line.ends_with(','));
+        }
+        assert_eq!(wrapped.replace('\n', " "), text);
+    }
+
+    #[test]
+    fn wraps_at_spaces_without_commas() {
+        let text = "word ".repeat(200);
+        let wrapped = wrap_long_lines(text.trim_end());
+        assert!(wrapped.split('\n').all(|line| line.len() <= MAX_LINE_LEN && line.ends_with("word")));
+    }
+
+    #[test]
+    fn splits_multibyte_text_on_char_boundaries() {
+        // 3 byte chars which don't divide MAX_LINE_LEN evenly
+        let text = "€".repeat(MAX_LINE_LEN);
+        let wrapped = wrap_long_lines(&text);
+        assert!(wrapped.split('\n').all(|line| line.len() <= MAX_LINE_LEN));
+        assert_eq!(wrapped.replace('\n', ""), text);
+        assert_eq!(truncate("a€", 2), "a");
+    }
+
+    #[test]
+    fn wraps_each_line_of_multiline_text() {
+        let long = "x ".repeat(MAX_LINE_LEN);
+        let text = format!("short\n{}\nshort", long.trim_end());
+        let wrapped = wrap_long_lines(&text);
+        assert!(wrapped.starts_with("short\n"));
+        assert!(wrapped.ends_with("\nshort"));
+        assert!(wrapped.split('\n').all(|line| line.len() <= MAX_LINE_LEN));
+    }
+}
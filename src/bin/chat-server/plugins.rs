@@ -0,0 +1,209 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use anyhow::bail;
+use compact_str::CompactString;
+use tokio::sync::broadcast::{self, error::RecvError};
+use crate::commands::{Command, CommandResult, Flow, Registry};
+use crate::config::PluginsConfig;
+use crate::{ChatMsg, EditError, RoomMsg, Rooms, Session};
+
+// bots that run inside the server, every plugin gets a bot
+// named after it which posts into rooms like users do, it
+// sees everything sent to every room and can add commands,
+// plugins are loaded by name from plugins.enabled
+
+pub trait Plugin: Send + Sync + 'static {
+    // also the name its bot posts as
+    fn name(&self) -> &str;
+    // commands can keep a clone of the bot to post with
+    fn commands(&self, _bot: &Bot, _registry: &mut Registry) {}
+    // everything sent to every room, including the bot's
+    // own msgs, called from the room's plugin task so
+    // anything slow should be spawned
+    fn room_msg(&self, _bot: &Bot, _room: &str, _msg: &RoomMsg) {}
+}
+
+#[derive(Clone)]
+pub struct Bot {
+    name: CompactString,
+    rooms: Rooms,
+}
+
+impl Bot {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    // bots are moderated too since what they post often comes
+    // from users or other sites, blocked msgs are dropped quietly,
+    // rooms nobody is in can't be posted to
+    pub fn say(&self, room: &str, text: String) -> Result<(), EditError> {
+        match self.rooms.moderate(room, &self.name, text).text {
+            Some(text) => self.rooms.post(ChatMsg::new(room, &self.name, text)),
+            None => Ok(()),
+        }
+    }
+    pub fn reply(&self, room: &str, id: u64, text: String) -> Result<(), EditError> {
+        match self.rooms.moderate(room, &self.name, text).text {
+            Some(text) => self.rooms.post(ChatMsg::reply(room, &self.name, text, Some(id))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn builtin(name: &str, config: &PluginsConfig) -> Option<Box<dyn Plugin>> {
+    match name {
+        "dice" => Some(Box::new(DicePlugin)),
+        "links" => Some(Box::new(LinksPlugin {
+            titles: config.links.clone(),
+        })),
+        _ => None,
+    }
+}
+
+pub struct Plugins {
+    // names plugins' bots post as
+    pub bots: HashSet<CompactString>,
+}
+
+impl Plugins {
+    // loads every enabled plugin, adding their commands
+    // to the registry and watching rooms as they open
+    pub fn start(config: &PluginsConfig, rooms: &Rooms, registry: &mut Registry) -> anyhow::Result<Self> {
+        let mut plugins = Vec::with_capacity(config.enabled.len());
+        let mut bots = HashSet::new();
+        for name in &config.enabled {
+            let Some(plugin) = builtin(name, config) else {
+                bail!("unknown plugin {name}");
+            };
+            let bot = Bot {
+                name: plugin.name().into(),
+                rooms: rooms.clone(),
+            };
+            if !bots.insert(bot.name.clone()) {
+                bail!("plugin {name} is enabled twice");
+            }
+            plugin.commands(&bot, registry);
+            tracing::info!("Loaded plugin {name}");
+            plugins.push((plugin, bot));
+        }
+        if !plugins.is_empty() {
+            let plugins = Arc::new(plugins);
+            let mut opened = rooms.watch();
+            tokio::spawn(async move {
+                while let Some((room, room_rx)) = opened.recv().await {
+                    tokio::spawn(forward(plugins.clone(), room, room_rx));
+                }
+            });
+        }
+        Ok(Self { bots })
+    }
+}
+
+// runs until everyone has left the room and it's removed
+async fn forward(plugins: Arc<Vec<(Box<dyn Plugin>, Bot)>>, room: CompactString, mut room_rx: broadcast::Receiver<RoomMsg>) {
+    loop {
+        match room_rx.recv().await {
+            Ok(msg) => {
+                for (plugin, bot) in plugins.iter() {
+                    plugin.room_msg(bot, &room, &msg);
+                }
+            },
+            Err(RecvError::Lagged(n)) => tracing::warn!("plugins missed {n} messages in {room}"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+// DICE //
+
+const MAX_DICE: u32 = 20;
+const MAX_SIDES: u32 = 1000;
+
+struct DicePlugin;
+
+impl Plugin for DicePlugin {
+    fn name(&self) -> &str {
+        "dice"
+    }
+    fn commands(&self, bot: &Bot, registry: &mut Registry) {
+        registry.add(RollCommand { bot: bot.clone() });
+    }
+}
+
+struct RollCommand {
+    bot: Bot,
+}
+
+impl Command for RollCommand {
+    type Args = (u32, u32);
+    fn name(&self) -> &str {
+        "roll"
+    }
+    fn usage(&self) -> &str {
+        "{dice}"
+    }
+    fn summary(&self) -> &str {
+        "roll dice for the room, e.g. /roll 2d6"
+    }
+    fn details(&self) -> &str {
+        "dice are {count}d{sides}, 1d6 if left out"
+    }
+    fn parse(&self, args: &str) -> Result<Self::Args, Option<String>> {
+        let dice = args.trim();
+        if dice.is_empty() {
+            return Ok((1, 6));
+        }
+        let (count, sides) = dice.split_once(['d', 'D']).ok_or(None)?;
+        let count = if count.is_empty() { Ok(1) } else { count.parse() };
+        match (count, sides.parse()) {
+            (Ok(count @ 1..=MAX_DICE), Ok(sides @ 2..=MAX_SIDES)) => Ok((count, sides)),
+            _ => Err(Some(format!("Can roll up to {MAX_DICE} dice with 2 - {MAX_SIDES} sides"))),
+        }
+    }
+    async fn run(&self, session: &mut Session, (count, sides): Self::Args) -> CommandResult {
+        let rolls: Vec<u32> = (0..count).map(|_| fastrand::u32(1..=sides)).collect();
+        let total: u32 = rolls.iter().sum();
+        let text = match rolls.len() {
+            1 => format!("{} rolled a d{sides}: {total}", session.name),
+            _ => {
+                let rolls: Vec<String> = rolls.iter().map(u32::to_string).collect();
+                format!("{} rolled {count}d{sides}: {} = {total}", session.name, rolls.join(" + "))
+            },
+        };
+        let _ = self.bot.say(&session.room_name, text);
+        Ok(Flow::Continue)
+    }
+}
+
+// LINKS //
+
+// previews links from a local list of titles
+struct LinksPlugin {
+    titles: HashMap<String, String>,
+}
+
+impl Plugin for LinksPlugin {
+    fn name(&self) -> &str {
+        "links"
+    }
+    fn room_msg(&self, bot: &Bot, room: &str, msg: &RoomMsg) {
+        let RoomMsg::Msg(msg) = msg else {
+            return;
+        };
+        if msg.from == bot.name() {
+            return;
+        }
+        let previews: Vec<String> = msg
+            .text
+            .split_whitespace()
+            .map(|word| word.trim_end_matches([',', '.', ')', '!', '?']))
+            .filter_map(|url| {
+                let title = self.titles.get(url).or_else(|| self.titles.get(url.trim_end_matches('/')))?;
+                Some(format!("{title} - {url}"))
+            })
+            .collect();
+        if !previews.is_empty() {
+            let _ = bot.reply(room, msg.id, previews.join(" | "));
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::{BTreeMap, HashMap};
+use chat_server::protocol::{Poll, PollOption};
+use compact_str::CompactString;
+
+// polls live with their room and are gone once everyone
+// has left it, every voter gets one vote which they can
+// change until the poll is closed
+
+pub const MAX_POLL_OPTIONS: usize = 10;
+// open polls per room
+pub const MAX_OPEN_POLLS: usize = 5;
+
+#[derive(Debug)]
+pub enum PollError {
+    NotFound,
+    NotAllowed,
+    NoSuchOption,
+    TooMany,
+}
+
+struct OpenPoll {
+    by: CompactString,
+    question: String,
+    options: Vec<String>,
+    // voter to the index of their option
+    votes: HashMap<CompactString, usize>,
+}
+
+impl OpenPoll {
+    fn tally(&self, room: &str, id: u64, closed: bool) -> Poll {
+        let mut counts = vec![0; self.options.len()];
+        for &option in self.votes.values() {
+            counts[option] += 1;
+        }
+        Poll {
+            room: room.to_owned(),
+            id,
+            by: self.by.to_string(),
+            question: self.question.clone(),
+            options: self
+                .options
+                .iter()
+                .zip(counts)
+                .map(|(text, votes)| PollOption {
+                    text: text.clone(),
+                    votes,
+                })
+                .collect(),
+            closed,
+        }
+    }
+    // by text ignoring case, or by number from 1
+    fn find(&self, option: &str) -> Option<usize> {
+        self.options
+            .iter()
+            .position(|text| text.eq_ignore_ascii_case(option))
+            .or_else(|| {
+                let number = option.parse::<usize>().ok()?;
+                (1..=self.options.len()).contains(&number).then(|| number - 1)
+            })
+    }
+}
+
+pub struct Polls {
+    next_id: u64,
+    open: BTreeMap<u64, OpenPoll>,
+}
+
+impl Polls {
+    pub fn new() -> Self {
+        Self {
+            next_id: 1,
+            open: BTreeMap::new(),
+        }
+    }
+    pub fn create(&mut self, by: &str, question: String, options: Vec<String>) -> Result<u64, PollError> {
+        if self.open.len() >= MAX_OPEN_POLLS {
+            return Err(PollError::TooMany);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.open.insert(id, OpenPoll {
+            by: by.into(),
+            question,
+            options,
+            votes: HashMap::new(),
+        });
+        Ok(id)
+    }
+    // returns false if they already voted for that option
+    pub fn vote(&mut self, id: u64, voter: &str, option: &str) -> Result<bool, PollError> {
+        let poll = self.open.get_mut(&id).ok_or(PollError::NotFound)?;
+        let option = poll.find(option).ok_or(PollError::NoSuchOption)?;
+        Ok(poll.votes.insert(voter.into(), option) != Some(option))
+    }
+    // the newest poll if id is none, only whoever
+    // created a poll can close it unless can_close_any
+    pub fn close(&mut self, room: &str, id: Option<u64>, closer: &str, can_close_any: bool) -> Result<Poll, PollError> {
+        let id = match id {
+            Some(id) => id,
+            None => *self.open.keys().next_back().ok_or(PollError::NotFound)?,
+        };
+        let poll = self.open.get(&id).ok_or(PollError::NotFound)?;
+        if !can_close_any && poll.by != closer {
+            return Err(PollError::NotAllowed);
+        }
+        Ok(self.open.remove(&id).unwrap().tally(room, id, true))
+    }
+    pub fn tally(&self, room: &str, id: u64) -> Option<Poll> {
+        self.open.get(&id).map(|poll| poll.tally(room, id, false))
+    }
+    pub fn tallies(&self, room: &str) -> Vec<Poll> {
+        self.open.iter().map(|(&id, poll)| poll.tally(room, id, false)).collect()
+    }
+    // votes and polls follow their user
+    pub fn rename(&mut self, prev_name: &str, new_name: &str) {
+        for poll in self.open.values_mut() {
+            if poll.by == prev_name {
+                poll.by = new_name.into();
+            }
+            if let Some(option) = poll.votes.remove(prev_name) {
+                poll.votes.insert(new_name.into(), option);
+            }
+        }
+    }
+}
+
+// a question followed by its options, any of which can
+// be quoted to include spaces, e.g. "lunch?" pizza "pad thai"
+pub fn parse(args: &str) -> Result<(String, Vec<String>), String> {
+    let mut words = Vec::new();
+    let mut rest = args.trim_start();
+    while !rest.is_empty() {
+        let (word, after) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').ok_or("Missing closing quote")?,
+            None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+        };
+        let word = word.trim();
+        if !word.is_empty() {
+            words.push(word.to_owned());
+        }
+        rest = after.trim_start();
+    }
+    let mut words = words.into_iter();
+    let question = words.next().ok_or("Missing question")?;
+    let options: Vec<String> = words.collect();
+    if options.len() < 2 || options.len() > MAX_POLL_OPTIONS {
+        return Err(format!("Polls need 2 - {MAX_POLL_OPTIONS} options"));
+    }
+    for (idx, option) in options.iter().enumerate() {
+        if options[..idx].iter().any(|prev| prev.eq_ignore_ascii_case(option)) {
+            return Err(format!("Option {option} is listed twice"));
+        }
+    }
+    Ok((question, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_quoted_words() {
+        let (question, options) = parse(r#""lunch today?" pizza "pad thai""#).unwrap();
+        assert_eq!(question, "lunch today?");
+        assert_eq!(options, words(&["pizza", "pad thai"]));
+        assert_eq!(parse(r#"lunch? pizza "pad thai"#), Err("Missing closing quote".to_owned()));
+        assert_eq!(parse("  "), Err("Missing question".to_owned()));
+    }
+
+    #[test]
+    fn rejects_duplicate_options() {
+        assert_eq!(parse("lunch? pizza Pizza"), Err("Option Pizza is listed twice".to_owned()));
+    }
+
+    #[test]
+    fn bounds_options() {
+        let too_few = format!("Polls need 2 - {MAX_POLL_OPTIONS} options");
+        assert_eq!(parse("lunch? pizza"), Err(too_few.clone()));
+        let options: Vec<String> = (1..=MAX_POLL_OPTIONS).map(|n| format!("o{n}")).collect();
+        assert!(parse(&format!("q {}", options.join(" "))).is_ok());
+        assert_eq!(parse(&format!("q {} o0", options.join(" "))), Err(too_few));
+    }
+
+    #[test]
+    fn votes_by_text_or_number() {
+        let mut polls = Polls::new();
+        let id = polls.create("al", "lunch?".to_owned(), words(&["pizza", "soup"])).unwrap();
+        assert!(matches!(polls.vote(id, "bob", "PIZZA"), Ok(true)));
+        assert!(matches!(polls.vote(id, "bob", "1"), Ok(false)));
+        assert!(matches!(polls.vote(id, "bob", "3"), Err(PollError::NoSuchOption)));
+        assert!(matches!(polls.close("main", None, "bob", false), Err(PollError::NotAllowed)));
+        let poll = polls.close("main", None, "al", false).unwrap();
+        assert_eq!(poll.options[0].votes, 1);
+        assert!(matches!(polls.vote(id, "bob", "soup"), Err(PollError::NotFound)));
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chat_server::unix_secs;
+use compact_str::CompactString;
+use tokio::task::AbortHandle;
+use crate::{ChatMsg, DirectError, State, UserMsg};
+
+// reminders and scheduled room posts, each waits in its
+// own task on the runtime, nothing here survives a restart
+// but reminders for registered users who are offline
+// when they're due wait until they next log in
+
+// pending items per user
+pub const MAX_PENDING: usize = 20;
+pub const MAX_DELAY_SECS: u64 = 7 * 24 * 3600;
+
+pub enum Kind {
+    // sent to its owner
+    Remind,
+    // posted into the room as its owner, and
+    // theirs to edit if they were logged in
+    Post {
+        room: CompactString,
+        registered: bool,
+    },
+}
+
+struct Pending {
+    owner: CompactString,
+    kind: Kind,
+    text: String,
+    created: u64,
+    due: u64,
+    task: AbortHandle,
+}
+
+// what /remind and /schedule list
+pub struct Listed {
+    pub id: u64,
+    pub owner: CompactString,
+    pub text: String,
+    // secs from now
+    pub due_in: u64,
+}
+
+#[derive(Clone)]
+pub struct Reminder {
+    pub id: u64,
+    pub text: String,
+    pub created: u64,
+}
+
+pub enum ScheduleError {
+    TooMany,
+    NotFound,
+    NotAllowed,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    pending: BTreeMap<u64, Pending>,
+    // reminders for offline registered users
+    missed: HashMap<CompactString, Vec<Reminder>>,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    inner: Mutex<Inner>,
+}
+
+impl Scheduler {
+    pub fn add(&self, state: &State, owner: &str, kind: Kind, text: String, delay: Duration) -> Result<u64, ScheduleError> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.pending.values().filter(|pending| pending.owner == owner).count() >= MAX_PENDING {
+            return Err(ScheduleError::TooMany);
+        }
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let state = state.clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let pending = state.scheduler.inner.lock().unwrap().pending.remove(&id);
+            if let Some(pending) = pending {
+                state.scheduler.deliver(&state, id, pending);
+            }
+        });
+        let created = unix_secs();
+        inner.pending.insert(id, Pending {
+            owner: owner.into(),
+            kind,
+            text,
+            created,
+            due: created + delay.as_secs(),
+            task: task.abort_handle(),
+        });
+        Ok(id)
+    }
+    fn deliver(&self, state: &State, id: u64, pending: Pending) {
+        let Pending { owner, kind, text, created, .. } = pending;
+        match kind {
+            Kind::Remind => {
+                let reminder = Reminder { id, text, created };
+                match state.names.direct(&owner, UserMsg::Reminder(reminder.clone())) {
+                    Ok(_) => (),
+                    Err(DirectError::NotOnline) if state.accounts.is_registered(&owner) => {
+                        let mut inner = self.inner.lock().unwrap();
+                        let missed = inner.missed.entry(owner).or_default();
+                        if missed.len() >= MAX_PENDING {
+                            missed.remove(0);
+                        }
+                        missed.push(reminder);
+                    },
+                    Err(_) => tracing::debug!("dropped reminder {id} for {owner}"),
+                }
+            },
+            Kind::Post { room, registered } => {
+                let mut msg = ChatMsg::new(&room, &owner, text);
+                // can't fail, nobody else has seen it yet
+                Arc::get_mut(&mut msg).unwrap().registered = registered;
+                if state.rooms.post(msg).is_err() {
+                    tracing::info!("dropped scheduled post {id} by {owner}, nobody is in {room}");
+                }
+            },
+        }
+    }
+    // reminders that were due while they were offline
+    pub fn missed(&self, name: &str) -> Vec<Reminder> {
+        self.inner.lock().unwrap().missed.remove(name).unwrap_or_default()
+    }
+    // pending reminders of the user, or posts into the room
+    pub fn list(&self, filter: impl Fn(&CompactString, &Kind) -> bool) -> Vec<Listed> {
+        let now = unix_secs();
+        self.inner
+            .lock()
+            .unwrap()
+            .pending
+            .iter()
+            .filter(|(_, pending)| filter(&pending.owner, &pending.kind))
+            .map(|(&id, pending)| Listed {
+                id,
+                owner: pending.owner.clone(),
+                text: pending.text.clone(),
+                due_in: pending.due.saturating_sub(now),
+            })
+            .collect()
+    }
+    // allowed is given the owner and kind of the item
+    pub fn cancel(&self, id: u64, allowed: impl Fn(&CompactString, &Kind) -> bool) -> Result<(), ScheduleError> {
+        let mut inner = self.inner.lock().unwrap();
+        let pending = inner.pending.get(&id).ok_or(ScheduleError::NotFound)?;
+        if !allowed(&pending.owner, &pending.kind) {
+            return Err(ScheduleError::NotAllowed);
+        }
+        inner.pending.remove(&id).unwrap().task.abort();
+        Ok(())
+    }
+    // everything pending follows its owner
+    pub fn rename(&self, prev_name: &str, new_name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        for pending in inner.pending.values_mut().filter(|pending| pending.owner == prev_name) {
+            pending.owner = new_name.into();
+        }
+    }
+    // guests' reminders and posts can't wait for them to come
+    // back, otherwise they'd go to or be posted as whoever
+    // takes their name next
+    pub fn forget(&self, owner: &str) {
+        self.inner.lock().unwrap().pending.retain(|_, pending| {
+            let forget = pending.owner == owner;
+            if forget {
+                pending.task.abort();
+            }
+            !forget
+        });
+    }
+}
+
+// e.g. 90s, 10m, 1h30m or 2d, up to MAX_DELAY_SECS
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let mut secs = 0;
+    let mut number = None::<u64>;
+    for c in duration.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            number = Some(number.unwrap_or(0).checked_mul(10)?.checked_add(digit as u64)?);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 24 * 3600,
+            _ => return None,
+        };
+        secs = number.take()?.checked_mul(unit)?.checked_add(secs)?;
+    }
+    // every number needs a unit
+    if number.is_some() {
+        return None;
+    }
+    (1..=MAX_DELAY_SECS).contains(&secs).then(|| Duration::from_secs(secs))
+}
+
+// a duration, or a utc time of day like 17:30 which
+// is tomorrow if it's already passed today
+pub fn parse_time(time: &str) -> Option<Duration> {
+    parse_time_at(time, unix_secs())
+}
+
+fn parse_time_at(time: &str, now: u64) -> Option<Duration> {
+    let Some((hours, mins)) = time.split_once(':') else {
+        return parse_duration(time);
+    };
+    if mins.len() != 2 {
+        return None;
+    }
+    let (hours, mins) = (hours.parse::<u64>().ok()?, mins.parse::<u64>().ok()?);
+    if hours > 23 || mins > 59 {
+        return None;
+    }
+    let day = 24 * 3600;
+    let now = now % day;
+    let at = hours * 3600 + mins * 60;
+    let secs = if at > now { at - now } else { at + day - now };
+    Some(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(duration: Option<Duration>) -> Option<u64> {
+        duration.map(|duration| duration.as_secs())
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(secs(parse_duration("90s")), Some(90));
+        assert_eq!(secs(parse_duration("1h30m")), Some(5400));
+        assert_eq!(secs(parse_duration("1H30M")), Some(5400));
+        assert_eq!(secs(parse_duration("2d")), Some(2 * 24 * 3600));
+    }
+
+    #[test]
+    fn rejects_bad_durations() {
+        assert_eq!(parse_duration("90"), None);
+        assert_eq!(parse_duration("1h30"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("5w"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("0s"), None);
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+        assert_eq!(parse_duration("999999999999999999d"), None);
+    }
+
+    #[test]
+    fn bounds_durations() {
+        assert_eq!(secs(parse_duration("7d")), Some(MAX_DELAY_SECS));
+        assert_eq!(parse_duration("7d1s"), None);
+    }
+
+    #[test]
+    fn parses_times_of_day() {
+        let day = 24 * 3600;
+        // 17:00 on some day
+        let now = 100 * day + 17 * 3600;
+        assert_eq!(secs(parse_time_at("17:30", now)), Some(1800));
+        assert_eq!(secs(parse_time_at("09:00", now)), Some(16 * 3600));
+        assert_eq!(secs(parse_time_at("17:00", now)), Some(day));
+        assert_eq!(secs(parse_time_at("0:00", now)), Some(7 * 3600));
+        assert_eq!(secs(parse_time_at("10m", now)), Some(600));
+    }
+
+    #[test]
+    fn rejects_bad_times() {
+        assert_eq!(parse_time_at("24:00", 0), None);
+        assert_eq!(parse_time_at("12:60", 0), None);
+        assert_eq!(parse_time_at("12:5", 0), None);
+        assert_eq!(parse_time_at("ab:cd", 0), None);
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use chat_server::markup;
+use compact_str::CompactString;
+use crate::ChatMsg;
+
+// inverted index over every msg still in some room's
+// history, live or dormant, kept up to date by Rooms
+// as msgs are posted, edited, deleted or pushed out
+
+#[derive(Default)]
+struct Inner {
+    msgs: HashMap<u64, Arc<ChatMsg>>,
+    terms: HashMap<CompactString, HashSet<u64>>,
+}
+
+#[derive(Default)]
+pub struct Index {
+    inner: Mutex<Inner>,
+}
+
+pub struct Results {
+    // how many msgs matched, only the newest are returned
+    pub total: usize,
+    pub msgs: Vec<Arc<ChatMsg>>,
+}
+
+// lowercase words, ignoring markup and punctuation
+pub fn terms(text: &str) -> HashSet<CompactString> {
+    markup::strip(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase().into())
+        .collect()
+}
+
+impl Inner {
+    fn remove(&mut self, id: u64) {
+        let Some(msg) = self.msgs.remove(&id) else {
+            return;
+        };
+        for term in terms(&msg.text) {
+            if let Some(ids) = self.terms.get_mut(&term) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.terms.remove(&term);
+                }
+            }
+        }
+    }
+}
+
+impl Index {
+    // also replaces an edited msg
+    pub fn insert(&self, msg: Arc<ChatMsg>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(msg.id);
+        for term in terms(&msg.text) {
+            inner.terms.entry(term).or_default().insert(msg.id);
+        }
+        inner.msgs.insert(msg.id, msg);
+    }
+    pub fn remove(&self, id: u64) {
+        self.inner.lock().unwrap().remove(id);
+    }
+    // msgs containing every term, newest first,
+    // from every room if room is none
+    pub fn search(&self, query: &HashSet<CompactString>, room: Option<&str>, limit: usize) -> Results {
+        let inner = self.inner.lock().unwrap();
+        let mut sets: Vec<&HashSet<u64>> = Vec::with_capacity(query.len());
+        for term in query {
+            match inner.terms.get(term) {
+                Some(ids) => sets.push(ids),
+                None => return Results { total: 0, msgs: Vec::new() },
+            }
+        }
+        // walk the rarest term and check the rest
+        sets.sort_by_key(|ids| ids.len());
+        let Some((rarest, rest)) = sets.split_first() else {
+            return Results { total: 0, msgs: Vec::new() };
+        };
+        let mut msgs: Vec<&Arc<ChatMsg>> = rarest
+            .iter()
+            .filter(|id| rest.iter().all(|ids| ids.contains(id)))
+            .filter_map(|id| inner.msgs.get(id))
+            .filter(|msg| room.is_none_or(|room| msg.room == room))
+            .collect();
+        msgs.sort_unstable_by_key(|msg| std::cmp::Reverse(msg.id));
+        Results {
+            total: msgs.len(),
+            msgs: msgs.into_iter().take(limit).cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(results: &Results) -> Vec<u64> {
+        results.msgs.iter().map(|msg| msg.id).collect()
+    }
+
+    #[test]
+    fn tokenizes_words() {
+        let expected: HashSet<CompactString> = ["hello", "world", "it", "s", "bold", "42"].into_iter().map(Into::into).collect();
+        assert_eq!(terms("Hello, WORLD! it's *bold* 42"), expected);
+        assert!(terms("... --- !!!").is_empty());
+    }
+
+    #[test]
+    fn matches_every_term() {
+        let index = Index::default();
+        let (a, b) = (ChatMsg::new("den", "al", "red apple".to_owned()), ChatMsg::new("den", "al", "green apple".to_owned()));
+        index.insert(a.clone());
+        index.insert(b.clone());
+        assert_eq!(ids(&index.search(&terms("apple"), None, 10)), [b.id, a.id]);
+        assert_eq!(ids(&index.search(&terms("APPLE red"), None, 10)), [a.id]);
+        assert!(index.search(&terms("red green"), None, 10).msgs.is_empty());
+        assert!(index.search(&terms("pear"), None, 10).msgs.is_empty());
+        assert!(index.search(&HashSet::new(), None, 10).msgs.is_empty());
+    }
+
+    #[test]
+    fn filters_by_room() {
+        let index = Index::default();
+        let (a, b) = (ChatMsg::new("den", "al", "apple".to_owned()), ChatMsg::new("main", "al", "apple".to_owned()));
+        index.insert(a.clone());
+        index.insert(b.clone());
+        assert_eq!(ids(&index.search(&terms("apple"), Some("den"), 10)), [a.id]);
+        assert_eq!(index.search(&terms("apple"), None, 10).total, 2);
+    }
+
+    #[test]
+    fn follows_edits_and_deletes() {
+        let index = Index::default();
+        let msg = ChatMsg::new("den", "al", "red apple".to_owned());
+        index.insert(msg.clone());
+        index.insert(msg.edit("green pear".to_owned()));
+        assert!(index.search(&terms("apple"), None, 10).msgs.is_empty());
+        assert_eq!(ids(&index.search(&terms("pear"), None, 10)), [msg.id]);
+        index.remove(msg.id);
+        assert!(index.search(&terms("pear"), None, 10).msgs.is_empty());
+        // terms nothing uses anymore are dropped
+        assert!(index.inner.lock().unwrap().terms.is_empty());
+    }
+
+    #[test]
+    fn returns_the_newest_up_to_the_limit() {
+        let index = Index::default();
+        let msgs: Vec<Arc<ChatMsg>> = (0..5).map(|idx| ChatMsg::new("den", "al", format!("apple {idx}"))).collect();
+        for msg in &msgs {
+            index.insert(msg.clone());
+        }
+        let results = index.search(&terms("apple"), None, 2);
+        assert_eq!(results.total, 5);
+        assert_eq!(ids(&results), [msgs[4].id, msgs[3].id]);
+    }
+}
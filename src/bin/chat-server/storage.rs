@@ -0,0 +1,545 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use anyhow::Context;
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use crate::accounts::{Account, OfflineMsg};
+use crate::config::{StorageBackend, StorageConfig};
+use crate::transcript::Logged;
+
+// everything which should survive a restart is recorded as a
+// change and handed to a dedicated thread which writes it to
+// the storage backend, so nobody ever waits on the disk, at
+// startup the backend replays what it saved into a snapshot
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredMsg {
+    pub id: u64,
+    pub room: CompactString,
+    pub from: CompactString,
+    pub text: String,
+    pub reply_to: Option<u64>,
+    pub action: bool,
+    pub edited: bool,
+    #[serde(default)]
+    pub registered: bool,
+    // unix timestamp in secs
+    pub sent: u64,
+}
+
+// changes waiting to be written, once full
+// changes are dropped rather than waited on
+const STORE_QUEUE_LEN: usize = 16 * 1024;
+// the log is compacted once it has twice as many
+// lines as it did after the last compaction
+const MIN_COMPACT_LINES: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Change {
+    Registered {
+        name: CompactString,
+        password_hash: String,
+    },
+    Queued {
+        to: CompactString,
+        msg: OfflineMsg,
+    },
+    InboxRead {
+        name: CompactString,
+    },
+    Operator {
+        room: CompactString,
+        name: CompactString,
+        added: bool,
+    },
+    Ban {
+        room: CompactString,
+        name: CompactString,
+        banned: bool,
+    },
+    Topic {
+        room: CompactString,
+        topic: Option<String>,
+    },
+    Msg(StoredMsg),
+    Edited {
+        room: CompactString,
+        id: u64,
+        text: String,
+    },
+    Deleted {
+        room: CompactString,
+        id: u64,
+    },
+    // ids of deleted and trimmed msgs are never reused
+    NextMsgId {
+        id: u64,
+    },
+}
+
+#[derive(Default, Clone)]
+pub struct RoomRecord {
+    pub topic: Option<String>,
+    pub operators: HashSet<CompactString>,
+    pub bans: HashSet<CompactString>,
+    pub history: VecDeque<StoredMsg>,
+    // joins, leaves and renames, never
+    // stored and only kept alongside msgs
+    pub log: VecDeque<Logged>,
+}
+
+impl RoomRecord {
+    pub fn is_empty(&self) -> bool {
+        self.topic.is_none() && self.operators.is_empty() && self.bans.is_empty() && self.history.is_empty()
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Snapshot {
+    pub accounts: HashMap<CompactString, Account>,
+    pub rooms: HashMap<CompactString, RoomRecord>,
+    pub next_msg_id: u64,
+}
+
+impl Snapshot {
+    fn apply(&mut self, change: Change, history_len: usize) {
+        match change {
+            Change::Registered { name, password_hash } => {
+                self.accounts.insert(name, Account {
+                    password_hash,
+                    inbox: VecDeque::new(),
+                });
+            },
+            Change::Queued { to, msg } => {
+                if let Some(account) = self.accounts.get_mut(&to) {
+                    account.inbox.push_back(msg);
+                }
+            },
+            Change::InboxRead { name } => {
+                if let Some(account) = self.accounts.get_mut(&name) {
+                    account.inbox.clear();
+                }
+            },
+            Change::Operator { room, name, added } => {
+                // guests used to become operators too,
+                // their names can be taken by anyone
+                if added && !self.accounts.contains_key(&name) {
+                    return;
+                }
+                let operators = &mut self.rooms.entry(room).or_default().operators;
+                match added {
+                    true => operators.insert(name),
+                    false => operators.remove(&name),
+                };
+            },
+            Change::Ban { room, name, banned } => {
+                let bans = &mut self.rooms.entry(room).or_default().bans;
+                match banned {
+                    true => bans.insert(name),
+                    false => bans.remove(&name),
+                };
+            },
+            Change::Topic { room, topic } => {
+                self.rooms.entry(room).or_default().topic = topic;
+            },
+            Change::Msg(msg) => {
+                self.next_msg_id = self.next_msg_id.max(msg.id + 1);
+                let history = &mut self.rooms.entry(msg.room.clone()).or_default().history;
+                history.push_back(msg);
+                while history.len() > history_len {
+                    history.pop_front();
+                }
+            },
+            Change::Edited { room, id, text } => {
+                let msg = self
+                    .rooms
+                    .get_mut(&room)
+                    .and_then(|room| room.history.iter_mut().find(|msg| msg.id == id));
+                if let Some(msg) = msg {
+                    msg.text = text;
+                    msg.edited = true;
+                }
+            },
+            Change::Deleted { room, id } => {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    room.history.retain(|msg| msg.id != id);
+                }
+            },
+            Change::NextMsgId { id } => {
+                self.next_msg_id = self.next_msg_id.max(id);
+            },
+        }
+    }
+    // the fewest changes which recreate the snapshot
+    fn changes(&self) -> impl Iterator<Item = Change> + '_ {
+        let accounts = self.accounts.iter().flat_map(|(name, account)| {
+            let registered = Change::Registered {
+                name: name.clone(),
+                password_hash: account.password_hash.clone(),
+            };
+            let queued = account.inbox.iter().map(|msg| Change::Queued {
+                to: name.clone(),
+                msg: msg.clone(),
+            });
+            std::iter::once(registered).chain(queued)
+        });
+        let rooms = self.rooms.iter().flat_map(|(room_name, room)| {
+            let topic = room.topic.clone().map(|topic| Change::Topic {
+                room: room_name.clone(),
+                topic: Some(topic),
+            });
+            let operators = room.operators.iter().map(|name| Change::Operator {
+                room: room_name.clone(),
+                name: name.clone(),
+                added: true,
+            });
+            let bans = room.bans.iter().map(|name| Change::Ban {
+                room: room_name.clone(),
+                name: name.clone(),
+                banned: true,
+            });
+            let history = room.history.iter().cloned().map(Change::Msg);
+            topic.into_iter().chain(operators).chain(bans).chain(history)
+        });
+        let next_msg_id = Change::NextMsgId { id: self.next_msg_id };
+        accounts.chain(rooms).chain(std::iter::once(next_msg_id))
+    }
+}
+
+pub trait Storage: Send + 'static {
+    // everything saved so far, only called once at startup
+    fn load(&mut self) -> anyhow::Result<Snapshot>;
+    // called from the storage thread with whatever
+    // changes piled up since the last call
+    fn write(&mut self, changes: &[Change]) -> anyhow::Result<()>;
+}
+
+// nothing survives a restart
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn load(&mut self) -> anyhow::Result<Snapshot> {
+        Ok(Snapshot::default())
+    }
+    fn write(&mut self, _changes: &[Change]) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+// every change is appended to a file as a json line, the file
+// is compacted at startup, whenever it's grown to twice its
+// compacted size and whenever a msg is deleted so deleted
+// msgs don't linger on disk
+pub struct LogStorage {
+    path: PathBuf,
+    history_len: usize,
+    file: Option<BufWriter<File>>,
+    // what the file would replay to, to compact from
+    snapshot: Snapshot,
+    lines: usize,
+    compacted_lines: usize,
+}
+
+impl LogStorage {
+    pub fn new(path: PathBuf, history_len: usize) -> Self {
+        Self {
+            path,
+            history_len,
+            file: None,
+            snapshot: Snapshot::default(),
+            lines: 0,
+            compacted_lines: 0,
+        }
+    }
+    fn replay(&self) -> anyhow::Result<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(snapshot),
+            Err(err) => return Err(err.into()),
+        };
+        for (idx, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(change) => snapshot.apply(change, self.history_len),
+                // most likely the last line was cut
+                // short by a crash, skip it and carry on
+                Err(err) => tracing::warn!("skipping line {} of {}: {err}", idx + 1, self.path.display()),
+            }
+        }
+        Ok(snapshot)
+    }
+    // rewrites the file from the snapshot and reopens it
+    fn compact(&mut self) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut lines = 0;
+        for change in self.snapshot.changes() {
+            serde_json::to_writer(&mut writer, &change)?;
+            writer.write_all(b"\n")?;
+            lines += 1;
+        }
+        writer.into_inner()?.sync_all()?;
+        // a crash mid-compaction leaves the previous log intact
+        fs::rename(&tmp, &self.path)?;
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        self.file = Some(BufWriter::new(file));
+        self.lines = lines;
+        self.compacted_lines = lines;
+        Ok(())
+    }
+}
+
+impl Storage for LogStorage {
+    fn load(&mut self) -> anyhow::Result<Snapshot> {
+        self.snapshot = self
+            .replay()
+            .with_context(|| format!("could not read {}", self.path.display()))?;
+        self.compact()
+            .with_context(|| format!("could not compact {}", self.path.display()))?;
+        Ok(self.snapshot.clone())
+    }
+    fn write(&mut self, changes: &[Change]) -> anyhow::Result<()> {
+        for change in changes {
+            self.snapshot.apply(change.clone(), self.history_len);
+        }
+        self.lines += changes.len();
+        let deleted = changes.iter().any(|change| matches!(change, Change::Deleted { .. }));
+        if deleted || self.lines >= MIN_COMPACT_LINES.max(self.compacted_lines * 2) {
+            return self
+                .compact()
+                .with_context(|| format!("could not compact {}", self.path.display()));
+        }
+        let file = self.file.as_mut().context("storage was never loaded")?;
+        for change in changes {
+            serde_json::to_writer(&mut *file, change)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+// cheap to clone handle for recording changes
+#[derive(Clone)]
+pub struct Store {
+    tx: mpsc::Sender<Change>,
+    // changes which didn't fit in the queue
+    dropped: Arc<AtomicU64>,
+}
+
+impl Store {
+    // loads the snapshot and starts the storage thread
+    pub fn open(config: &StorageConfig) -> anyhow::Result<(Self, Snapshot)> {
+        let mut storage: Box<dyn Storage> = match config.backend {
+            StorageBackend::Memory => Box::new(MemoryStorage),
+            StorageBackend::Log => Box::new(LogStorage::new(config.path.clone(), config.history_per_room)),
+        };
+        let snapshot = storage.load()?;
+        let (tx, mut rx) = mpsc::channel(STORE_QUEUE_LEN);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let store = Self { tx, dropped };
+        let dropped = store.dropped.clone();
+        std::thread::Builder::new()
+            .name("storage".to_owned())
+            .spawn(move || {
+                let mut changes = Vec::new();
+                let mut reported = 0;
+                while let Some(change) = rx.blocking_recv() {
+                    changes.push(change);
+                    while let Ok(change) = rx.try_recv() {
+                        changes.push(change);
+                    }
+                    if let Err(err) = storage.write(&changes) {
+                        tracing::error!("could not save {} changes: {err:#}", changes.len());
+                    }
+                    changes.clear();
+                    let dropped = dropped.load(Ordering::Relaxed);
+                    if dropped > reported {
+                        tracing::error!("storage can't keep up, dropped {} changes, {dropped} so far", dropped - reported);
+                        reported = dropped;
+                    }
+                }
+            })?;
+        Ok((store, snapshot))
+    }
+    pub fn record(&self, change: Change) {
+        // closed only if the storage thread died,
+        // which it already logged
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(change) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(room: &str, id: u64, text: &str) -> Change {
+        Change::Msg(StoredMsg {
+            id,
+            room: room.into(),
+            from: "al".into(),
+            text: text.to_owned(),
+            reply_to: None,
+            action: false,
+            edited: false,
+            registered: true,
+            sent: 0,
+        })
+    }
+
+    fn registered(name: &str) -> Change {
+        Change::Registered {
+            name: name.into(),
+            password_hash: format!("hash of {name}"),
+        }
+    }
+
+    // changes in a stable order so snapshots can be compared
+    fn dump(snapshot: &Snapshot) -> Vec<String> {
+        let mut changes: Vec<String> = snapshot
+            .changes()
+            .map(|change| serde_json::to_string(&change).unwrap())
+            .collect();
+        changes.sort();
+        changes
+    }
+
+    fn history(snapshot: &Snapshot, room: &str) -> Vec<(u64, String)> {
+        snapshot.rooms[room].history.iter().map(|msg| (msg.id, msg.text.clone())).collect()
+    }
+
+    // removed when dropped so failed tests don't leave files behind
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("chat-storage-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir.join("chat.log"))
+        }
+        fn lines(&self) -> Vec<String> {
+            fs::read_to_string(&self.0).unwrap().lines().map(str::to_owned).collect()
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    #[test]
+    fn only_registered_names_become_operators() {
+        let mut snapshot = Snapshot::default();
+        snapshot.apply(registered("al"), 10);
+        for name in ["al", "guest"] {
+            snapshot.apply(Change::Operator { room: "den".into(), name: name.into(), added: true }, 10);
+        }
+        assert_eq!(snapshot.rooms["den"].operators, HashSet::from(["al".into()]));
+        snapshot.apply(Change::Operator { room: "den".into(), name: "al".into(), added: false }, 10);
+        assert!(snapshot.rooms["den"].operators.is_empty());
+    }
+
+    #[test]
+    fn trims_history() {
+        let mut snapshot = Snapshot::default();
+        for id in 1..=3 {
+            snapshot.apply(msg("den", id, &format!("msg {id}")), 2);
+        }
+        assert_eq!(history(&snapshot, "den"), [(2, "msg 2".to_owned()), (3, "msg 3".to_owned())]);
+        assert_eq!(snapshot.next_msg_id, 4);
+    }
+
+    #[test]
+    fn changes_recreate_the_snapshot() {
+        let mut snapshot = Snapshot::default();
+        let changes = [
+            registered("al"),
+            registered("bob"),
+            Change::Queued {
+                to: "bob".into(),
+                msg: OfflineMsg { from: "al".to_owned(), text: "hi".to_owned(), sent: 1 },
+            },
+            Change::Operator { room: "den".into(), name: "al".into(), added: true },
+            Change::Ban { room: "den".into(), name: "eve".into(), banned: true },
+            Change::Topic { room: "den".into(), topic: Some("dens".to_owned()) },
+            msg("den", 1, "first"),
+            msg("den", 2, "second"),
+            msg("main", 3, "third"),
+            Change::Edited { room: "den".into(), id: 2, text: "edited".to_owned() },
+            Change::Deleted { room: "main".into(), id: 3 },
+        ];
+        for change in changes {
+            snapshot.apply(change, 10);
+        }
+        let mut replayed = Snapshot::default();
+        for change in snapshot.changes() {
+            replayed.apply(change, 10);
+        }
+        assert_eq!(dump(&replayed), dump(&snapshot));
+        assert_eq!(replayed.next_msg_id, 4);
+        assert!(replayed.rooms["den"].history[1].edited);
+        assert_eq!(replayed.accounts["bob"].inbox.len(), 1);
+    }
+
+    #[test]
+    fn skips_truncated_last_line() {
+        let log = TempLog::new("truncated");
+        fs::create_dir_all(log.0.parent().unwrap()).unwrap();
+        let lines = [
+            serde_json::to_string(&registered("al")).unwrap(),
+            serde_json::to_string(&msg("den", 1, "hi")).unwrap(),
+        ];
+        let cut = serde_json::to_string(&msg("den", 2, "cut short")).unwrap();
+        let contents = format!("{}\n{}\n{}", lines[0], lines[1], &cut[..cut.len() / 2]);
+        fs::write(&log.0, contents).unwrap();
+        let snapshot = LogStorage::new(log.0.clone(), 10).load().unwrap();
+        assert!(snapshot.accounts.contains_key("al"));
+        assert_eq!(history(&snapshot, "den"), [(1, "hi".to_owned())]);
+        // compacted at startup without the cut line
+        assert!(log.lines().iter().all(|line| !line.contains("cut short")));
+    }
+
+    #[test]
+    fn compacts_deleted_msgs_away() {
+        let log = TempLog::new("deleted");
+        let mut storage = LogStorage::new(log.0.clone(), 10);
+        storage.load().unwrap();
+        storage.write(&[msg("den", 1, "keep"), msg("den", 2, "secret")]).unwrap();
+        assert!(log.lines().iter().any(|line| line.contains("secret")));
+        storage.write(&[Change::Deleted { room: "den".into(), id: 2 }]).unwrap();
+        assert!(log.lines().iter().all(|line| !line.contains("secret")));
+        // the deleted msg's id isn't handed out again
+        let snapshot = LogStorage::new(log.0.clone(), 10).load().unwrap();
+        assert_eq!(history(&snapshot, "den"), [(1, "keep".to_owned())]);
+        assert_eq!(snapshot.next_msg_id, 3);
+    }
+
+    #[test]
+    fn compacts_once_grown() {
+        let log = TempLog::new("grown");
+        let mut storage = LogStorage::new(log.0.clone(), 10);
+        storage.load().unwrap();
+        let topic = |idx: usize| Change::Topic { room: "den".into(), topic: Some(format!("topic {idx}")) };
+        let changes: Vec<Change> = (0..MIN_COMPACT_LINES - 10).map(topic).collect();
+        storage.write(&changes).unwrap();
+        assert!(log.lines().len() >= MIN_COMPACT_LINES - 10);
+        storage.write(&(0..10).map(topic).collect::<Vec<_>>()).unwrap();
+        // just the last topic and the next msg id
+        assert_eq!(log.lines().len(), 2);
+        let snapshot = LogStorage::new(log.0.clone(), 10).load().unwrap();
+        assert_eq!(snapshot.rooms["den"].topic.as_deref(), Some("topic 9"));
+    }
+}
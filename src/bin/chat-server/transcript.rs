@@ -0,0 +1,291 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use chat_server::{format_utc, markup, unix_secs};
+use compact_str::CompactString;
+use serde::Serialize;
+use crate::ChatMsg;
+
+// room history rendered for /export, msgs come from the
+// room's recent msgs and joins, leaves and renames from
+// a log kept next to them, both are capped at
+// storage.history_per_room but only msgs are stored so
+// exports after a restart have no joins or leaves
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Jsonl,
+    Markdown,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format.to_ascii_lowercase().as_str() {
+            "text" | "txt" => Some(Self::Text),
+            "jsonl" | "json" => Some(Self::Jsonl),
+            "markdown" | "md" => Some(Self::Markdown),
+            _ => None,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Jsonl => "jsonl",
+            Self::Markdown => "markdown",
+        }
+    }
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Text => "txt",
+            Self::Jsonl => "jsonl",
+            Self::Markdown => "md",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Event {
+    Joined(CompactString),
+    Left(CompactString),
+    Renamed {
+        from: CompactString,
+        to: CompactString,
+    },
+}
+
+// a join, leave or rename, placed after
+// whichever msg was newest when it happened
+#[derive(Clone)]
+pub struct Logged {
+    pub after: u64,
+    pub sent: u64,
+    pub event: Event,
+}
+
+pub struct Transcript {
+    pub msgs: Vec<Arc<ChatMsg>>,
+    pub events: Vec<Logged>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum JsonEntry<'a> {
+    Msg {
+        id: u64,
+        from: &'a str,
+        text: &'a str,
+        sent: u64,
+        reply_to: Option<u64>,
+        action: bool,
+        edited: bool,
+    },
+    Joined {
+        name: &'a str,
+        sent: u64,
+    },
+    Left {
+        name: &'a str,
+        sent: u64,
+    },
+    Renamed {
+        from: &'a str,
+        to: &'a str,
+        sent: u64,
+    },
+}
+
+fn escape_markdown(text: &str, out: &mut String) {
+    for c in text.chars() {
+        if "\\`*_[]<>#|".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+// our markup is almost markdown already, except
+// *bold* which markdown would render as italic
+fn to_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for (style, span) in markup::parse(text) {
+        let marker = match style {
+            markup::Style::Plain => "",
+            markup::Style::Bold => "**",
+            markup::Style::Italic => "_",
+            markup::Style::Code => "`",
+        };
+        out.push_str(marker);
+        match style {
+            markup::Style::Code => out.push_str(span),
+            _ => escape_markdown(span, &mut out),
+        }
+        out.push_str(marker);
+    }
+    out
+}
+
+impl Transcript {
+    pub fn len(&self) -> usize {
+        self.msgs.len() + self.events.len()
+    }
+    pub fn render(&self, room: &str, format: Format) -> String {
+        let mut out = String::new();
+        match format {
+            Format::Text => {
+                let _ = writeln!(out, "Transcript of {room} - {} entries, times are utc", self.len());
+            },
+            Format::Markdown => {
+                let _ = writeln!(out, "# Transcript of {room}\n\n{} entries, times are utc\n", self.len());
+            },
+            Format::Jsonl => (),
+        }
+        let mut events = self.events.iter().peekable();
+        for msg in &self.msgs {
+            while let Some(logged) = events.next_if(|logged| logged.after < msg.id) {
+                render_event(logged, format, &mut out);
+            }
+            render_msg(msg, format, &mut out);
+        }
+        for logged in events {
+            render_event(logged, format, &mut out);
+        }
+        // every entry ends with a newline
+        out.pop();
+        out
+    }
+}
+
+fn render_msg(msg: &ChatMsg, format: Format, out: &mut String) {
+    let (id, from, sent) = (msg.id, &msg.from, format_utc(msg.sent));
+    let edited = if msg.edited { " (edited)" } else { "" };
+    let _ = match format {
+        Format::Text => {
+            let text = markup::strip(&msg.text);
+            match (msg.action, msg.reply_to) {
+                (true, _) => writeln!(out, "{sent} [{id}] * {from} {text}{edited}"),
+                (false, Some(parent)) => writeln!(out, "{sent} [{id}] {from} replied to [{parent}]: {text}{edited}"),
+                (false, None) => writeln!(out, "{sent} [{id}] {from}: {text}{edited}"),
+            }
+        },
+        Format::Markdown => {
+            let text = to_markdown(&msg.text);
+            let mut from = String::new();
+            escape_markdown(&msg.from, &mut from);
+            match (msg.action, msg.reply_to) {
+                (true, _) => writeln!(out, "- {sent} \\[{id}\\] \\* **{from}** {text}{edited}"),
+                (false, Some(parent)) => writeln!(out, "- {sent} \\[{id}\\] **{from}** replied to \\[{parent}\\]: {text}{edited}"),
+                (false, None) => writeln!(out, "- {sent} \\[{id}\\] **{from}**: {text}{edited}"),
+            }
+        },
+        Format::Jsonl => {
+            let entry = JsonEntry::Msg {
+                id,
+                from,
+                text: &msg.text,
+                sent: msg.sent,
+                reply_to: msg.reply_to,
+                action: msg.action,
+                edited: msg.edited,
+            };
+            writeln!(out, "{}", serde_json::to_string(&entry).unwrap())
+        },
+    };
+}
+
+fn render_event(logged: &Logged, format: Format, out: &mut String) {
+    let sent = logged.sent;
+    if format == Format::Jsonl {
+        let entry = match &logged.event {
+            Event::Joined(name) => JsonEntry::Joined { name, sent },
+            Event::Left(name) => JsonEntry::Left { name, sent },
+            Event::Renamed { from, to } => JsonEntry::Renamed { from, to, sent },
+        };
+        let _ = writeln!(out, "{}", serde_json::to_string(&entry).unwrap());
+        return;
+    }
+    let text = match &logged.event {
+        Event::Joined(name) => format!("{name} joined"),
+        Event::Left(name) => format!("{name} left"),
+        Event::Renamed { from, to } => format!("{from} is now {to}"),
+    };
+    let text = match format {
+        Format::Markdown => {
+            let mut escaped = String::new();
+            escape_markdown(&text, &mut escaped);
+            escaped
+        },
+        _ => text,
+    };
+    let sent = format_utc(sent);
+    let _ = match format {
+        Format::Markdown => writeln!(out, "- {sent} _{text}_"),
+        _ => writeln!(out, "{sent} {text}"),
+    };
+}
+
+// writes a rendered transcript into dir, returning its path
+pub async fn save(dir: &Path, room: &str, format: Format, text: &str) -> std::io::Result<PathBuf> {
+    tokio::fs::create_dir_all(dir).await?;
+    let path = dir.join(format!("{room}-{}.{}", unix_secs(), format.extension()));
+    tokio::fs::write(&path, text).await?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn escaped(text: &str) -> String {
+        let mut out = String::new();
+        escape_markdown(text, &mut out);
+        out
+    }
+
+    // without the timestamp in front
+    fn rendered(msg: &ChatMsg, format: Format) -> String {
+        let mut out = String::new();
+        render_msg(msg, format, &mut out);
+        let sent = format_utc(msg.sent);
+        out.trim_end().replacen(&format!("{sent} "), "", 1)
+    }
+
+    #[test]
+    fn escapes_markdown() {
+        assert_eq!(escaped("*a* _b_ `c` [d](e) #f"), r"\*a\* \_b\_ \`c\` \[d\](e) \#f");
+        assert_eq!(escaped("plain text"), "plain text");
+    }
+
+    #[test]
+    fn converts_markup() {
+        assert_eq!(to_markdown("*bold* _italic_ `co*de`"), "**bold** _italic_ `co*de`");
+        assert_eq!(to_markdown("2 * 3 [link]"), r"2 \* 3 \[link\]");
+    }
+
+    #[test]
+    fn renders_actions() {
+        let msg = ChatMsg::action("den", "a_l", "waves *hard*".to_owned());
+        let id = msg.id;
+        assert_eq!(rendered(&msg, Format::Text), format!("[{id}] * a_l waves hard"));
+        assert_eq!(rendered(&msg, Format::Markdown), format!(r"- \[{id}\] \* **a\_l** waves **hard**"));
+    }
+
+    #[test]
+    fn renders_replies() {
+        let msg = ChatMsg::reply("den", "al", "same".to_owned(), Some(7));
+        let id = msg.id;
+        assert_eq!(rendered(&msg, Format::Text), format!("[{id}] al replied to [7]: same"));
+        assert_eq!(rendered(&msg, Format::Markdown), format!(r"- \[{id}\] **al** replied to \[7\]: same"));
+    }
+
+    #[test]
+    fn renders_edits() {
+        let msg = ChatMsg::new("den", "al", "typo".to_owned()).edit("fixed".to_owned());
+        let id = msg.id;
+        assert_eq!(rendered(&msg, Format::Text), format!("[{id}] al: fixed (edited)"));
+        assert_eq!(rendered(&msg, Format::Markdown), format!(r"- \[{id}\] **al**: fixed (edited)"));
+        let json: serde_json::Value = serde_json::from_str(&rendered(&msg, Format::Jsonl)).unwrap();
+        assert_eq!(json["edited"], true);
+        assert_eq!(json["text"], "fixed");
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{bail, Context};
+use chat_server::{single_line, token_eq, valid_name};
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use crate::config::{IncomingConfig, OutgoingHookConfig, WebhooksConfig};
+use crate::list::glob_match;
+use crate::{ChatMsg, RoomMsg, Rooms, MAX_MSG_LEN};
+
+// connects the server to local tooling over plain http,
+// outgoing hooks POST msgs matching their pattern to a url
+// and the incoming endpoint lets tools post msgs into
+// rooms as bots, both go through Rooms like users' msgs
+
+// msgs waiting to be POSTed to a hook before new ones are dropped
+const HOOK_QUEUE_LEN: usize = 256;
+// request line and headers of an incoming request
+const MAX_HEAD_LEN: u64 = 8 * 1024;
+const MAX_BODY_LEN: usize = 4 * 1024;
+
+pub struct Webhooks {
+    // names incoming requests post as
+    pub bots: HashSet<CompactString>,
+}
+
+impl Webhooks {
+    // must be called before users connect
+    pub async fn start(config: &WebhooksConfig, rooms: &Rooms) -> anyhow::Result<Self> {
+        let timeout = config.timeout();
+        let mut hooks = Vec::with_capacity(config.outgoing.len());
+        for hook in &config.outgoing {
+            hooks.push(Hook::start(hook, timeout)?);
+        }
+        if !hooks.is_empty() {
+            let hooks = Arc::new(hooks);
+            let mut opened = rooms.watch();
+            tokio::spawn(async move {
+                while let Some((room, room_rx)) = opened.recv().await {
+                    tokio::spawn(forward(hooks.clone(), room, room_rx));
+                }
+            });
+        }
+        let bots = tokens(&config.incoming)?;
+        let names = bots.values().cloned().collect();
+        if let Some(addr) = config.incoming.addr {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("could not listen for webhooks on {addr}"))?;
+            tracing::info!("Listening for webhooks on {addr}");
+            tokio::spawn(listen(listener, Arc::new(bots), rooms.clone(), timeout));
+        }
+        Ok(Self { bots: names })
+    }
+}
+
+// tokens to the names of the bots they post as
+fn tokens(config: &IncomingConfig) -> anyhow::Result<HashMap<String, CompactString>> {
+    let mut tokens = HashMap::with_capacity(config.bots.len());
+    for bot in &config.bots {
+        if !valid_name(Some(&bot.name)) {
+            bail!("webhook bot name {} must be 2 - 20 alphanumeric chars", bot.name);
+        }
+        if bot.token.is_empty() {
+            bail!("webhook bot {} has no token", bot.name);
+        }
+        if tokens.insert(bot.token.clone(), CompactString::from(&bot.name)).is_some() {
+            bail!("webhook bot {} shares its token with another bot", bot.name);
+        }
+    }
+    Ok(tokens)
+}
+
+// OUTGOING //
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    room: &'a str,
+    id: u64,
+    from: &'a str,
+    text: &'a str,
+    sent: u64,
+    reply_to: Option<u64>,
+    action: bool,
+}
+
+#[derive(Debug)]
+struct Url {
+    // host:port to connect to
+    addr: String,
+    host: String,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> anyhow::Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            bail!("webhook url {url} must start with http://");
+        };
+        let (host, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            bail!("webhook url {url} has no host");
+        }
+        let addr = match host.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => host.to_owned(),
+            _ => format!("{host}:80"),
+        };
+        Ok(Self {
+            addr,
+            host: host.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+}
+
+struct Hook {
+    rooms: HashSet<CompactString>,
+    pattern: String,
+    tx: mpsc::Sender<String>,
+}
+
+impl Hook {
+    // msgs are POSTed one at a time and in order
+    fn start(config: &OutgoingHookConfig, timeout: Duration) -> anyhow::Result<Self> {
+        let url = Url::parse(&config.url)?;
+        let (tx, mut rx) = mpsc::channel::<String>(HOOK_QUEUE_LEN);
+        tokio::spawn(async move {
+            while let Some(payload) = rx.recv().await {
+                match tokio::time::timeout(timeout, post(&url, &payload)).await {
+                    Ok(Ok(status)) if (200..300).contains(&status) => (),
+                    Ok(Ok(status)) => tracing::warn!("webhook {} answered with {status}", url.host),
+                    Ok(Err(err)) => tracing::warn!("could not deliver webhook to {}: {err}", url.host),
+                    Err(_) => tracing::warn!("webhook {} timed out", url.host),
+                }
+            }
+        });
+        Ok(Self {
+            rooms: config.rooms.iter().map(CompactString::from).collect(),
+            pattern: config.pattern.clone(),
+            tx,
+        })
+    }
+    fn matches(&self, msg: &ChatMsg) -> bool {
+        let in_room = self.rooms.contains("*") || self.rooms.contains(&msg.room);
+        in_room && glob_match(&self.pattern, &msg.text)
+    }
+}
+
+// returns the response's status code
+async fn post(url: &Url, payload: &str) -> std::io::Result<u16> {
+    let mut tcp = TcpStream::connect(&url.addr).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{payload}",
+        url.path,
+        url.host,
+        payload.len(),
+    );
+    tcp.write_all(request.as_bytes()).await?;
+    let mut status_line = String::new();
+    BufReader::new(tcp.take(MAX_HEAD_LEN)).read_line(&mut status_line).await?;
+    // e.g. "HTTP/1.1 204 No Content"
+    status_line
+        .split_ascii_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed response"))
+}
+
+// runs until everyone has left the room and it's removed
+async fn forward(hooks: Arc<Vec<Hook>>, room: CompactString, mut room_rx: broadcast::Receiver<RoomMsg>) {
+    loop {
+        let msg = match room_rx.recv().await {
+            Ok(RoomMsg::Msg(msg)) => msg,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(n)) => {
+                tracing::warn!("webhooks missed {n} messages in {room}");
+                continue;
+            },
+            Err(RecvError::Closed) => break,
+        };
+        let mut payload = None;
+        for hook in hooks.iter().filter(|hook| hook.matches(&msg)) {
+            let payload = payload.get_or_insert_with(|| {
+                let payload = Payload {
+                    room: &msg.room,
+                    id: msg.id,
+                    from: &msg.from,
+                    text: &msg.text,
+                    sent: msg.sent,
+                    reply_to: msg.reply_to,
+                    action: msg.action,
+                };
+                serde_json::to_string(&payload).unwrap()
+            });
+            if let Err(TrySendError::Full(_)) = hook.tx.try_send(payload.clone()) {
+                tracing::warn!("webhook queue is full, dropped message {}", msg.id);
+            }
+        }
+    }
+}
+
+// INCOMING //
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Incoming {
+    text: String,
+    #[serde(default)]
+    reply_to: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    reason: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn error(status: u16, reason: &'static str, error: impl Into<String>) -> Self {
+        let body = serde_json::json!({ "error": error.into() }).to_string();
+        Self { status, reason, body }
+    }
+}
+
+async fn listen(listener: TcpListener, bots: Arc<HashMap<String, CompactString>>, rooms: Rooms, timeout: Duration) {
+    loop {
+        let (tcp, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!("could not accept webhook connection: {err}");
+                continue;
+            },
+        };
+        let (bots, rooms) = (bots.clone(), rooms.clone());
+        tokio::spawn(async move {
+            if tokio::time::timeout(timeout, serve(tcp, addr, &bots, &rooms)).await.is_err() {
+                tracing::debug!("webhook request from {addr} timed out");
+            }
+        });
+    }
+}
+
+async fn serve(mut tcp: TcpStream, addr: SocketAddr, bots: &HashMap<String, CompactString>, rooms: &Rooms) {
+    let response = match read_request(&mut tcp).await {
+        Ok(request) => handle(request, addr, bots, rooms),
+        Err(response) => response,
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason,
+        response.body.len(),
+        response.body,
+    );
+    let _ = tcp.write_all(response.as_bytes()).await;
+    let _ = tcp.shutdown().await;
+}
+
+async fn read_request(tcp: &mut (impl AsyncRead + Unpin)) -> Result<Request, Response> {
+    let bad_request = |error: &str| Response::error(400, "Bad Request", error);
+    let mut reader = BufReader::new(tcp.take(MAX_HEAD_LEN));
+    let mut line = String::new();
+    let read = reader.read_line(&mut line).await.map_err(|_| bad_request("malformed request"))?;
+    let mut request_line = line.split_ascii_whitespace();
+    let (Some(method), Some(path), Some(_version)) = (request_line.next(), request_line.next(), request_line.next()) else {
+        return Err(bad_request(if read == 0 { "empty request" } else { "malformed request line" }));
+    };
+    let (method, path) = (method.to_owned(), path.to_owned());
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await.map_err(|_| bad_request("malformed headers"))? == 0 {
+            return Err(bad_request("headers are too long or incomplete"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| bad_request("malformed header"))?;
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+    }
+    let len = match headers.get("content-length") {
+        Some(len) => len.parse::<usize>().map_err(|_| bad_request("malformed content-length"))?,
+        None => 0,
+    };
+    if len > MAX_BODY_LEN {
+        return Err(Response::error(413, "Payload Too Large", format!("body can be up to {MAX_BODY_LEN} bytes")));
+    }
+    // whatever of the body was buffered with the headers
+    let mut body = reader.buffer().to_vec();
+    body.truncate(len);
+    let buffered = body.len();
+    body.resize(len, 0);
+    let tcp = reader.into_inner().into_inner();
+    tcp.read_exact(&mut body[buffered..]).await.map_err(|_| bad_request("incomplete body"))?;
+    Ok(Request { method, path, headers, body })
+}
+
+// the scheme is case insensitive, e.g. "bearer {token}"
+fn bearer_token(auth: &str) -> Option<&str> {
+    let (scheme, token) = auth.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+fn handle(request: Request, addr: SocketAddr, bots: &HashMap<String, CompactString>, rooms: &Rooms) -> Response {
+    // the only route is /rooms/{room}/messages
+    let room = request
+        .path
+        .strip_prefix("/rooms/")
+        .and_then(|rest| rest.strip_suffix("/messages"))
+        .filter(|room| valid_name(Some(room)));
+    let Some(room) = room else {
+        return Response::error(404, "Not Found", "only POST /rooms/{room}/messages is supported");
+    };
+    if request.method != "POST" {
+        return Response::error(405, "Method Not Allowed", "only POST is supported");
+    }
+    let token = request
+        .headers
+        .get("authorization")
+        .and_then(|auth| bearer_token(auth))
+        .unwrap_or_default();
+    let bot = bots.iter().find(|(bot_token, _)| token_eq(bot_token, token)).map(|(_, name)| name);
+    let Some(bot) = bot else {
+        tracing::warn!("{addr} sent a webhook with a wrong token");
+        return Response::error(401, "Unauthorized", "missing or wrong bearer token");
+    };
+    let incoming: Incoming = match serde_json::from_slice(&request.body) {
+        Ok(incoming) => incoming,
+        Err(err) => return Response::error(400, "Bad Request", format!("could not parse body: {err}")),
+    };
+    let text = incoming.text.trim();
+    if text.is_empty() || !single_line(text) {
+        return Response::error(400, "Bad Request", "text must be a single non-empty line");
+    }
+    if text.len() > MAX_MSG_LEN {
+        return Response::error(400, "Bad Request", format!("text can be up to {MAX_MSG_LEN} chars"));
+    }
+    if rooms.list_users(room).is_none() {
+        return Response::error(404, "Not Found", format!("nobody is in {room}"));
+    }
+    // filtered like anyone else's msgs, operators still see what was flagged
+    let outcome = rooms.moderate(room, bot, text.to_owned());
+    let Some(text) = outcome.text else {
+        return Response::error(422, "Unprocessable Content", outcome.notice());
+    };
+    let msg = ChatMsg::reply(room, bot, text, incoming.reply_to);
+    let id = msg.id;
+    match rooms.post(msg) {
+        Ok(_) => {
+            tracing::debug!("{addr} posted message {id} to {room} as {bot}");
+            let body = serde_json::json!({ "id": id }).to_string();
+            Response { status: 200, reason: "OK", body }
+        },
+        Err(_) => match incoming.reply_to {
+            Some(parent) => Response::error(404, "Not Found", format!("no recent message {parent} in {room}")),
+            None => Response::error(404, "Not Found", format!("nobody is in {room}")),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn reads_body_split_across_reads() {
+        let (mut client, mut server) = duplex(1024);
+        client
+            .write_all(b"POST /rooms/main/messages HTTP/1.1\r\nContent-Length: 10\r\n\r\n{\"te")
+            .await
+            .unwrap();
+        let writing = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            client.write_all(b"xt\":1}").await.unwrap();
+        };
+        let (request, _) = tokio::join!(read_request(&mut server), writing);
+        let request = request.unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/rooms/main/messages");
+        assert_eq!(request.body, b"{\"text\":1}");
+    }
+
+    #[tokio::test]
+    async fn rejects_long_bodies() {
+        let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_LEN + 1);
+        let response = read_request(&mut head.as_bytes()).await.unwrap_err();
+        assert_eq!(response.status, 413);
+    }
+
+    #[tokio::test]
+    async fn rejects_unterminated_headers() {
+        let mut head: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 2\r\n";
+        let response = read_request(&mut head).await.unwrap_err();
+        assert_eq!(response.status, 400);
+        assert!(response.body.contains("incomplete"));
+    }
+
+    #[tokio::test]
+    async fn rejects_short_bodies() {
+        let mut head: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nab";
+        let response = read_request(&mut head).await.unwrap_err();
+        assert!(response.body.contains("incomplete body"));
+    }
+
+    #[test]
+    fn parses_bearer_tokens() {
+        assert_eq!(bearer_token("Bearer s3cret"), Some("s3cret"));
+        assert_eq!(bearer_token("bearer s3cret"), Some("s3cret"));
+        assert_eq!(bearer_token("BEARER s3cret"), Some("s3cret"));
+        assert_eq!(bearer_token("Basic s3cret"), None);
+        assert_eq!(bearer_token("Bearer"), None);
+    }
+
+    #[test]
+    fn parses_urls() {
+        let url = Url::parse("http://example.com").unwrap();
+        assert_eq!((url.addr.as_str(), url.host.as_str(), url.path.as_str()), ("example.com:80", "example.com", "/"));
+        let url = Url::parse("http://127.0.0.1:8080/hooks/chat").unwrap();
+        assert_eq!((url.addr.as_str(), url.path.as_str()), ("127.0.0.1:8080", "/hooks/chat"));
+        let url = Url::parse("http://[::1]:8080/hook").unwrap();
+        assert_eq!((url.addr.as_str(), url.host.as_str()), ("[::1]:8080", "[::1]:8080"));
+        let url = Url::parse("http://[::1]/hook").unwrap();
+        assert_eq!((url.addr.as_str(), url.host.as_str()), ("[::1]:80", "[::1]"));
+        assert!(Url::parse("https://example.com").is_err());
+        assert!(Url::parse("http:///hook").is_err());
+    }
+}
@@ -4,7 +4,7 @@ use crossterm::terminal::{
 };
 use futures::{SinkExt, StreamExt};
 use ratatui::backend::CrosstermBackend;
-use ratatui::layout::{Constraint, Layout};
+use ratatui::layout::{Constraint, Direction, Layout};
 use ratatui::style::{Style, Stylize};
 use ratatui::text::{Line, Span};
 use ratatui::widgets::{Block, Borders, List, ListItem};
@@ -13,10 +13,13 @@ use tracing_appender::non_blocking::WorkerGuard;
 use tracing_appender::rolling::Rotation;
 use std::borrow::Cow;
 use std::io;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
 use tui_textarea::{Input, Key, TextArea};
-use chat_server::{connection_refused, parse_socket_addr, file_logging};
+use chat_server::{connection_refused, format_secs, markup, unix_secs, parse_socket_addr, file_logging};
+use chat_server::protocol::{ClientEvent, Presence, ReactionCount, SearchHit, ServerEvent, SWITCH_TO_JSON};
+use chat_server::protocol::{TYPING_REFRESH_SECS, TYPING_TIMEOUT_SECS};
 
 // i quickly threw this code together
 // it's not particularly clean
@@ -31,43 +34,173 @@ fn textarea_new() -> TextArea<'static> {
     textarea
 }
 
+// a msg from a user or a note from the server
+struct Entry {
+    // only room msgs have ids
+    id: Option<u64>,
+    from: Option<String>,
+    text: String,
+    reply_to: Option<u64>,
+    reactions: Vec<ReactionCount>,
+    // sent with /me
+    action: bool,
+    edited: bool,
+    deleted: bool,
+}
+
+impl Entry {
+    fn user(from: String, text: String) -> Self {
+        Self {
+            id: None,
+            from: Some(from),
+            text,
+            reply_to: None,
+            reactions: Vec::new(),
+            action: false,
+            edited: false,
+            deleted: false,
+        }
+    }
+    fn room(id: u64, from: String, text: String, reply_to: Option<u64>) -> Self {
+        Self {
+            id: Some(id),
+            reply_to,
+            ..Self::user(from, text)
+        }
+    }
+    fn server(text: String) -> Self {
+        Self {
+            from: None,
+            ..Self::user(String::new(), text)
+        }
+    }
+}
+
+// results of the last /search, up and down pick one,
+// enter jumps to it and esc goes back to the room
+struct SearchView {
+    query: String,
+    total: usize,
+    hits: Vec<SearchHit>,
+    selected: usize,
+}
+
+fn search_to_list(view: &SearchView) -> List<'_> {
+    let list_items: Vec<ListItem> = view
+        .hits
+        .iter()
+        .enumerate()
+        .map(|(idx, hit)| {
+            let line = format!("[{}] {} in {}: {}", hit.id, hit.from, hit.room, markup::strip(&hit.text));
+            match idx == view.selected {
+                true => ListItem::new(line.reversed()),
+                false => ListItem::new(line),
+            }
+        })
+        .collect();
+    List::new(list_items)
+}
+
+// styles the *bold*, _italic_ and `code`
+// markup in a single line of a msg
+fn markup_spans(line: &str) -> Vec<Span<'static>> {
+    markup::parse(line)
+        .into_iter()
+        .map(|(style, text)| {
+            let span = Span::raw(text.to_owned());
+            match style {
+                markup::Style::Plain => span,
+                markup::Style::Bold => span.bold(),
+                markup::Style::Italic => span.italic(),
+                markup::Style::Code => span.yellow(),
+            }
+        })
+        .collect()
+}
+
+// wraps already styled spans so styles carry
+// on across a line break in the middle of a span
+fn wrap_spans(spans: &[Span<'static>], width: usize) -> Vec<Line<'static>> {
+    let text: String = spans.iter().map(|span| span.content.as_ref()).collect();
+    let options = textwrap::Options::new(width).wrap_algorithm(textwrap::WrapAlgorithm::new_optimal_fit());
+    let mut lines = Vec::new();
+    // wrapped lines are in order and only
+    // drop the whitespace between them
+    let mut cursor = 0;
+    for wrapped in textwrap::wrap(&text, options) {
+        let start = cursor + text[cursor..].find(wrapped.as_ref()).unwrap_or(0);
+        let end = start + wrapped.len();
+        cursor = end;
+        let mut line = Vec::new();
+        let mut span_start = 0;
+        for span in spans {
+            let span_end = span_start + span.content.len();
+            let (from, to) = (span_start.max(start), span_end.min(end));
+            if from < to {
+                line.push(Span::styled(text[from..to].to_owned(), span.style));
+            }
+            span_start = span_end;
+        }
+        lines.push(Line::from(line));
+    }
+    lines
+}
+
 fn messages_to_list(
-    msgs: &[String],
+    msgs: &[Entry],
     min_lines: usize,
     max_length: usize,
+    // highlighted and shown as the last msg
+    jumped_to: Option<u64>,
 ) -> List<'_> {
     let mut list_items = Vec::new();
+    let end = jumped_to
+        .and_then(|id| msgs.iter().rposition(|entry| entry.id == Some(id)))
+        .map_or(msgs.len(), |idx| idx + 1);
     // only interested in most recent msgs
-    'outer: for msg in msgs.iter().rev() {
-        let user_msg = msg.contains(':');
-        let lines = textwrap::wrap(
-            msg,
-            textwrap::Options::new(max_length)
-                .wrap_algorithm(textwrap::WrapAlgorithm::new_optimal_fit()),
-        );
-        let mut styled_lines = Vec::new();
-        if user_msg {
-            let mut lines = lines.into_iter();
-            let first_line = lines.next().unwrap();
-            let mut parts = first_line.split(':');
-            let mut first_styled_line = Vec::new();
-            first_styled_line.push(parts.next().unwrap().to_owned().bold());
-            for part in parts {
-                first_styled_line.push(Span::raw(":"));
-                first_styled_line.push(part.to_owned().into());
-            }
-            styled_lines.push(Line::from(first_styled_line));
-            for line in lines {
-                styled_lines.push(Line::from(line.into_owned()));
+    'outer: for entry in msgs[..end].iter().rev() {
+        let user_msg = entry.from.is_some();
+        let edited = if entry.edited { " (edited)" } else { "" };
+        let msg = match (&entry.from, entry.id) {
+            (Some(from), Some(id)) if entry.action => Cow::from(format!("[{id}] * {from} {}{edited}", entry.text)),
+            (Some(from), Some(id)) => Cow::from(format!("[{id}] {from}: {}{edited}", entry.text)),
+            (Some(from), None) => Cow::from(format!("{from}: {}", entry.text)),
+            (None, _) => Cow::from(&entry.text),
+        };
+        // replies are indented under their parent
+        let indent = if entry.reply_to.is_some() { "    " } else { "" };
+        let spans: Vec<Span> = if entry.deleted {
+            vec![msg.into_owned().dim().crossed_out()]
+        } else if entry.action {
+            markup_spans(&msg).into_iter().map(|span| span.italic()).collect()
+        } else if user_msg {
+            match msg.split_once(':') {
+                Some((from, rest)) => {
+                    let mut spans = vec![from.to_owned().bold(), Span::raw(":")];
+                    spans.extend(markup_spans(rest));
+                    spans
+                },
+                None => vec![msg.into_owned().bold()],
             }
         } else {
-            styled_lines.extend(
-                lines
-                    .into_iter()
-                    .map(|line| line.into_owned().dim().italic().into()),
-            );
+            vec![msg.into_owned().dim().italic()]
+        };
+        let mut styled_lines = wrap_spans(&spans, max_length.saturating_sub(indent.len()).max(1));
+        if !entry.reactions.is_empty() && !entry.deleted {
+            let counts: Vec<String> = entry
+                .reactions
+                .iter()
+                .map(|reaction| format!("{} {}", reaction.emoji, reaction.count))
+                .collect();
+            styled_lines.push(format!("  {}", counts.join("  ")).dim().into());
         }
-        for line in styled_lines.into_iter().rev() {
+        for mut line in styled_lines.into_iter().rev() {
+            if jumped_to.is_some() && entry.id == jumped_to {
+                line = line.reversed();
+            }
+            if !indent.is_empty() {
+                line.spans.insert(0, Span::raw(indent));
+            }
             list_items.push(ListItem::new(line));
             if list_items.len() >= min_lines {
                 break 'outer;
@@ -82,6 +215,45 @@ fn messages_to_list(
     List::new(list_items)
 }
 
+// away users are dimmed
+fn users_to_list(users: &[Presence]) -> List<'_> {
+    let list_items: Vec<ListItem> = users
+        .iter()
+        .map(|user| match user.away {
+            true => ListItem::new(user.name.as_str().dim()),
+            false => ListItem::new(user.name.as_str()),
+        })
+        .collect();
+    List::new(list_items)
+}
+
+// keeps passwords out of the logs
+fn redact(line: &str) -> Cow<'_, str> {
+    let mut words = line.split_ascii_whitespace();
+    match (words.next(), words.next()) {
+        (Some("/login"), Some(name)) => Cow::from(format!("/login {name} ***")),
+        (Some(command @ ("/login" | "/register" | "/admin")), _) => Cow::from(format!("{command} ***")),
+        _ => Cow::from(line),
+    }
+}
+
+// shown above the input box
+fn typing_to_line(typing: &[(String, Instant)]) -> Line<'_> {
+    let text = match typing {
+        [] => return Line::default(),
+        [(name, _)] => format!("{name} is typing..."),
+        [(first, _), (second, _)] => format!("{first} and {second} are typing..."),
+        _ => "Several people are typing...".to_owned(),
+    };
+    Line::from(text.dim().italic())
+}
+
+// whether what's in the input box should tell the
+// room we're typing, commands are nobody's business
+fn composing(textarea: &TextArea) -> bool {
+    !textarea.is_empty() && !textarea.lines()[0].starts_with('/')
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let addr = parse_socket_addr();
@@ -102,6 +274,8 @@ async fn main() -> anyhow::Result<()> {
     let (reader, writer) = conn.split();
     let mut tcp_sink = FramedWrite::new(writer, LinesCodec::new());
     let mut tcp_stream = FramedRead::new(reader, LinesCodec::new());
+    // server sends plain text until we switch protocols
+    tcp_sink.send(SWITCH_TO_JSON).await?;
 
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
@@ -115,31 +289,77 @@ async fn main() -> anyhow::Result<()> {
 
     let mut textarea = textarea_new();
     let layout = Layout::default()
-        .constraints([Constraint::Percentage(100), Constraint::Min(3)]);
+        .constraints([Constraint::Percentage(100), Constraint::Length(1), Constraint::Min(3)]);
+    let top_layout = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(20), Constraint::Length(24)]);
 
-    let mut messages: Vec<String> = Vec::new();
+    let mut messages: Vec<Entry> = Vec::new();
     let mut current_room = "main".to_owned();
+    let mut topic: Option<String> = None;
+    let mut my_name = String::new();
+    let mut users: Vec<Presence> = Vec::new();
+    let mut search: Option<SearchView> = None;
+    let mut jumped_to: Option<u64> = None;
+    // parts of an /export received so far
+    let mut transcript = String::new();
+    // others in the room and when we last heard they're typing
+    let mut typing: Vec<(String, Instant)> = Vec::new();
+    // when we last told the room we're typing
+    let mut typing_sent: Option<Instant> = None;
+    // to notice typers who went quiet
+    let mut typing_timer = tokio::time::interval(Duration::from_secs(1));
 
     let mut term_stream = crossterm::event::EventStream::new();
 
     loop {
         let draw_res = term.draw(|f| {
             let chunks = layout.split(f.size());
+            let top_chunks = top_layout.split(chunks[0]);
 
-            let msgs_height = chunks[0].height - 2; // -2 for borders
-            let msgs_width = chunks[0].width - 2; // -2 for borders
-            let msgs_title = format!("Room - {current_room}");
-            let msgs = messages_to_list(
-                &messages,
-                msgs_height.into(),
-                msgs_width.into(),
-            )
-            .block(Block::default().borders(Borders::ALL).title(msgs_title));
-            f.render_widget(msgs, chunks[0]);
+            let msgs_height = top_chunks[0].height - 2; // -2 for borders
+            let msgs_width = top_chunks[0].width - 2; // -2 for borders
+            let mut msgs_title = match &topic {
+                Some(topic) => format!("Room - {current_room} - {topic}"),
+                None => format!("Room - {current_room}"),
+            };
+            if let Some(id) = jumped_to {
+                msgs_title.push_str(&format!(" - at [{id}], esc to go back"));
+            }
+            match &search {
+                Some(view) => {
+                    let search_title = format!(
+                        "Search - {} - {} of {}, enter to jump, esc to close",
+                        view.query,
+                        view.hits.len(),
+                        view.total,
+                    );
+                    let results = search_to_list(view)
+                        .block(Block::default().borders(Borders::ALL).title(search_title));
+                    f.render_widget(results, top_chunks[0]);
+                },
+                None => {
+                    let msgs = messages_to_list(
+                        &messages,
+                        msgs_height.into(),
+                        msgs_width.into(),
+                        jumped_to,
+                    )
+                    .block(Block::default().borders(Borders::ALL).title(msgs_title));
+                    f.render_widget(msgs, top_chunks[0]);
+                },
+            }
+
+            let users_title = format!("Users - {}", users.len());
+            let users_list = users_to_list(&users)
+                .block(Block::default().borders(Borders::ALL).title(users_title));
+            f.render_widget(users_list, top_chunks[1]);
+
+            f.render_widget(typing_to_line(&typing), chunks[1]);
 
             // render input box
             let widget = textarea.widget();
-            f.render_widget(widget, chunks[1]);
+            f.render_widget(widget, chunks[2]);
         });
 
         match draw_res {
@@ -155,6 +375,41 @@ async fn main() -> anyhow::Result<()> {
                         Err(_) => break,
                     };
                     match event.into() {
+                        // close the search results, then
+                        // go back to the latest msgs
+                        Input { key: Key::Esc, .. } if search.is_some() => search = None,
+                        Input { key: Key::Esc, .. } if jumped_to.is_some() => jumped_to = None,
+                        Input { key: Key::Up, .. } if search.is_some() => {
+                            if let Some(view) = &mut search {
+                                view.selected = view.selected.saturating_sub(1);
+                            }
+                        },
+                        Input { key: Key::Down, .. } if search.is_some() => {
+                            if let Some(view) = &mut search {
+                                view.selected = (view.selected + 1).min(view.hits.len().saturating_sub(1));
+                            }
+                        },
+                        Input { key: Key::Enter, .. } if search.is_some() => {
+                            let Some(hit) = search.take().and_then(|mut view| {
+                                let selected = view.selected;
+                                (selected < view.hits.len()).then(|| view.hits.swap_remove(selected))
+                            }) else {
+                                continue;
+                            };
+                            // we only have what was sent since we connected
+                            // or joined the room, show anything older as a note
+                            match messages.iter().any(|entry| entry.id == Some(hit.id)) {
+                                true => jumped_to = Some(hit.id),
+                                false => messages.push(Entry::server(format!(
+                                    "[{}] {} in {} ({} ago): {}",
+                                    hit.id,
+                                    hit.from,
+                                    hit.room,
+                                    format_secs(unix_secs().saturating_sub(hit.sent)),
+                                    hit.text,
+                                ))),
+                            }
+                        },
                         // escape
                         Input { key: Key::Esc, .. } |
                         // ctrl+c
@@ -166,10 +421,14 @@ async fn main() -> anyhow::Result<()> {
                             if textarea.is_empty() {
                                 continue;
                             }
+                            if typing_sent.take().is_some() {
+                                let _ = tcp_sink.send(ClientEvent::TypingStop.to_json()).await;
+                            }
                             //messages.extend(textarea.into_lines());
                             for line in textarea.into_lines() {
-                                tracing::info!("SENT {line}");
-                                match tcp_sink.send(line).await {
+                                tracing::info!("SENT {}", redact(&line));
+                                let event = ClientEvent::Line { text: line };
+                                match tcp_sink.send(event.to_json()).await {
                                     Ok(_) => (),
                                     Err(_) => break,
                                 };
@@ -180,7 +439,25 @@ async fn main() -> anyhow::Result<()> {
                         input => {
                             // messages.push(format!("{:?}", input));
                             // TextArea::input returns if the input modified its text
-                            textarea.input_without_shortcuts(input);
+                            if !textarea.input_without_shortcuts(input) {
+                                continue;
+                            }
+                            let event = match (composing(&textarea), typing_sent) {
+                                (true, Some(sent)) if sent.elapsed() < Duration::from_secs(TYPING_REFRESH_SECS) => continue,
+                                (true, _) => {
+                                    typing_sent = Some(Instant::now());
+                                    ClientEvent::TypingStart
+                                },
+                                (false, Some(_)) => {
+                                    typing_sent = None;
+                                    ClientEvent::TypingStop
+                                },
+                                (false, None) => continue,
+                            };
+                            match tcp_sink.send(event.to_json()).await {
+                                Ok(_) => (),
+                                Err(_) => break,
+                            };
                         }
                     }
                 } else {
@@ -193,24 +470,204 @@ async fn main() -> anyhow::Result<()> {
                         Ok(msg) => msg,
                         Err(_) => break,
                     };
-                    if server_msg.starts_with("You joined ") {
-                        let room_name = server_msg
-                            .split_ascii_whitespace()
-                            .nth(2)
-                            .unwrap();
-                        current_room = room_name.to_owned();
-                    } else if server_msg.starts_with("You are ") {
-                        let name = server_msg
-                            .split_ascii_whitespace()
-                            .nth(2)
-                            .unwrap();
-                        _guard = Some(file_logging(Rotation::NEVER, &format!("chat-tui.{name}.log")));
-                    }
                     tracing::info!(" GOT {server_msg}");
-                    messages.push(server_msg);
+                    let event = match serde_json::from_str(&server_msg) {
+                        Ok(event) => event,
+                        // whatever the server sent before it
+                        // noticed we switched protocols
+                        Err(_) => {
+                            messages.push(Entry::server(server_msg));
+                            continue;
+                        },
+                    };
+                    let text = match event {
+                        ServerEvent::Welcome { name, room } => {
+                            _guard = Some(file_logging(Rotation::NEVER, &format!("chat-tui.{name}.log")));
+                            current_room = room;
+                            my_name = name;
+                            continue;
+                        },
+                        ServerEvent::Info { text } => text,
+                        ServerEvent::Msg { id, from, text, reply_to, .. } => {
+                            typing.retain(|(name, _)| *name != from);
+                            let entry = Entry::room(id, from, text, reply_to);
+                            // keep replies together under their parent
+                            // rather than wherever they arrived
+                            let parent = reply_to.and_then(|parent| {
+                                messages.iter().rposition(|entry| entry.id == Some(parent) || entry.reply_to == Some(parent))
+                            });
+                            match parent {
+                                Some(idx) => messages.insert(idx + 1, entry),
+                                None => messages.push(entry),
+                            }
+                            continue;
+                        },
+                        ServerEvent::Action { id, from, text, .. } => {
+                            typing.retain(|(name, _)| *name != from);
+                            let mut entry = Entry::room(id, from, text, None);
+                            entry.action = true;
+                            messages.push(entry);
+                            continue;
+                        },
+                        ServerEvent::Reactions { id, reactions, .. } => {
+                            if let Some(entry) = messages.iter_mut().rev().find(|entry| entry.id == Some(id)) {
+                                entry.reactions = reactions;
+                            }
+                            continue;
+                        },
+                        // update in place, it might have scrolled
+                        // off screen in which case nothing changes
+                        ServerEvent::Edited { id, text, .. } => {
+                            if let Some(entry) = messages.iter_mut().rev().find(|entry| entry.id == Some(id)) {
+                                entry.text = text;
+                                entry.edited = true;
+                            }
+                            continue;
+                        },
+                        ServerEvent::Deleted { id, .. } => {
+                            if let Some(entry) = messages.iter_mut().rev().find(|entry| entry.id == Some(id)) {
+                                entry.deleted = true;
+                            }
+                            continue;
+                        },
+                        ServerEvent::Joined { room, name } if name == my_name => {
+                            let text = format!("You joined {room}");
+                            current_room = room;
+                            // the server follows up with the new room's topic
+                            topic = None;
+                            typing.clear();
+                            text
+                        },
+                        ServerEvent::Topic { by, topic: new_topic, .. } => {
+                            let text = match (&by, &new_topic) {
+                                (Some(by), Some(new_topic)) => Some(format!("{by} set the topic - {new_topic}")),
+                                (Some(by), None) => Some(format!("{by} cleared the topic")),
+                                (None, _) => None,
+                            };
+                            topic = new_topic;
+                            match text {
+                                Some(text) => text,
+                                None => continue,
+                            }
+                        },
+                        ServerEvent::Joined { name, .. } => {
+                            let text = format!("{name} joined");
+                            // might already be in the snapshot
+                            // we got when joining the room
+                            if !users.iter().any(|user| user.name == name) {
+                                users.push(Presence { name, away: false, reason: None });
+                            }
+                            text
+                        },
+                        ServerEvent::Left { room, name } if name == my_name => format!("You left {room}"),
+                        ServerEvent::Left { name, .. } => {
+                            users.retain(|user| user.name != name);
+                            typing.retain(|(typer, _)| *typer != name);
+                            format!("{name} left")
+                        },
+                        ServerEvent::Renamed { from, to, .. } => {
+                            if from == my_name {
+                                my_name.clone_from(&to);
+                            }
+                            if let Some(user) = users.iter_mut().find(|user| user.name == from) {
+                                user.name.clone_from(&to);
+                            }
+                            if let Some((typer, _)) = typing.iter_mut().find(|(typer, _)| *typer == from) {
+                                typer.clone_from(&to);
+                            }
+                            format!("{from} is now {to}")
+                        },
+                        ServerEvent::Away { name, reason, .. } => {
+                            let text = match (name == my_name, &reason) {
+                                (true, _) => "You are away".to_owned(),
+                                (false, Some(reason)) => format!("{name} is away: {reason}"),
+                                (false, None) => format!("{name} is away"),
+                            };
+                            if let Some(user) = users.iter_mut().find(|user| user.name == name) {
+                                user.away = true;
+                                user.reason = reason;
+                            }
+                            text
+                        },
+                        ServerEvent::Back { name, .. } => {
+                            let text = match name == my_name {
+                                true => "You are back".to_owned(),
+                                false => format!("{name} is back"),
+                            };
+                            if let Some(user) = users.iter_mut().find(|user| user.name == name) {
+                                user.away = false;
+                                user.reason = None;
+                            }
+                            text
+                        },
+                        ServerEvent::Direct { from, to, text } => {
+                            messages.push(Entry::user(format!("{from} -> {to}"), text));
+                            continue;
+                        },
+                        // replaces whatever we knew about the previous room
+                        ServerEvent::Users { users: presence, .. } => {
+                            users = presence;
+                            continue;
+                        },
+                        ServerEvent::TypingStart { name, .. } => {
+                            match typing.iter_mut().find(|(typer, _)| *typer == name) {
+                                Some((_, heard)) => *heard = Instant::now(),
+                                None => typing.push((name, Instant::now())),
+                            }
+                            continue;
+                        },
+                        ServerEvent::TypingStop { name, .. } => {
+                            typing.retain(|(typer, _)| *typer != name);
+                            continue;
+                        },
+                        ServerEvent::Whois(whois) => whois.to_text(),
+                        ServerEvent::Poll(poll) => poll.to_text(),
+                        ServerEvent::Reminder { text, .. } => format!("Reminder - {text}"),
+                        ServerEvent::Moderated { note, .. } => format!("Moderation - {note}"),
+                        // saved next to our logs rather than
+                        // shown since it's meant for pasting
+                        ServerEvent::Transcript { room, format, part, parts, text } => {
+                            if part == 1 {
+                                transcript.clear();
+                            }
+                            transcript.push_str(&text);
+                            if part < parts {
+                                continue;
+                            }
+                            let extension = match format.as_str() {
+                                "markdown" => "md",
+                                "jsonl" => "jsonl",
+                                _ => "txt",
+                            };
+                            let path = format!("chat-tui.{room}.{extension}");
+                            match std::fs::write(&path, std::mem::take(&mut transcript)) {
+                                Ok(_) => format!("Saved transcript of {room} to {path}"),
+                                Err(err) => format!("Could not save transcript to {path}: {err}"),
+                            }
+                        },
+                        ServerEvent::SearchResults { total: 0, query, .. } => format!("No messages match \"{query}\""),
+                        ServerEvent::SearchResults { query, total, hits, .. } => {
+                            search = Some(SearchView { query, total, hits, selected: 0 });
+                            continue;
+                        },
+                        ServerEvent::Ping { id } => {
+                            let pong = ClientEvent::Pong { id };
+                            match tcp_sink.send(pong.to_json()).await {
+                                Ok(_) => continue,
+                                Err(_) => break,
+                            };
+                        },
+                    };
+                    messages.push(Entry::server(text));
                 },
                 None => break,
             },
+            _ = typing_timer.tick() => {
+                // they might have disconnected or
+                // just stopped without sending
+                let timeout = Duration::from_secs(TYPING_TIMEOUT_SECS);
+                typing.retain(|(_, heard)| heard.elapsed() < timeout);
+            },
         }
     }
 
@@ -219,3 +676,47 @@ async fn main() -> anyhow::Result<()> {
     term.show_cursor()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::style::Modifier;
+
+    fn contents(lines: &[Line]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| line.spans.iter().map(|span| span.content.as_ref()).collect())
+            .collect()
+    }
+
+    fn bold(span: &Span) -> bool {
+        span.style.add_modifier.contains(Modifier::BOLD)
+    }
+
+    #[test]
+    fn styles_cross_line_breaks() {
+        let lines = wrap_spans(&markup_spans("see *very bold words* here"), 10);
+        assert_eq!(contents(&lines), ["see very", "bold words", "here"]);
+        let first: Vec<(&str, bool)> = lines[0].spans.iter().map(|span| (span.content.as_ref(), bold(span))).collect();
+        assert_eq!(first, [("see ", false), ("very", true)]);
+        assert!(lines[1].spans.iter().all(bold));
+        assert!(!lines[2].spans.iter().any(bold));
+    }
+
+    #[test]
+    fn redacts_passwords() {
+        assert_eq!(redact("/login bob hunter22"), "/login bob ***");
+        assert_eq!(redact("/register hunter22"), "/register ***");
+        assert_eq!(redact("/admin hunter22"), "/admin ***");
+        assert_eq!(redact("/admin"), "/admin ***");
+        assert_eq!(redact("/login"), "/login ***");
+        assert_eq!(redact("/loginx a b"), "/loginx a b");
+        assert_eq!(redact("hello /admin pw"), "hello /admin pw");
+    }
+
+    #[test]
+    fn wraps_long_words() {
+        let lines = wrap_spans(&[Span::raw("abcdefgh")], 3);
+        assert_eq!(contents(&lines), ["abc", "def", "gh"]);
+    }
+}
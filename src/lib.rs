@@ -3,11 +3,14 @@
 // RANDOM NAME GENERATION //
 
 use compact_str::CompactString;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 mod adjectives;
 mod animals;
 mod english;
 mod rust;
+pub mod markup;
+pub mod protocol;
 
 use adjectives::ADJECTIVES;
 use animals::ANIMALS;
@@ -92,24 +95,39 @@ macro_rules! b {
 // COMMAND LINE //
 
 use std::net::{IpAddr, SocketAddr, Ipv4Addr};
-use clap::Parser;
+use clap::{Args, Parser};
 
 pub const DEFAULT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 pub const DEFAULT_PORT: u16 = 42069;
 
-#[derive(Parser)]
-#[command(long_about = None)]
-struct Cli {
+// binaries which need more args than
+// just the address can flatten this
+// into their own clap parser
+#[derive(Args)]
+pub struct AddrArgs {
     #[arg(short, long, default_value_t = DEFAULT_IP)]
-    ip: IpAddr,
+    pub ip: IpAddr,
 
     #[arg(short, long, default_value_t = DEFAULT_PORT)]
-    port: u16,
+    pub port: u16,
+}
+
+impl AddrArgs {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
+#[derive(Parser)]
+#[command(long_about = None)]
+struct Cli {
+    #[command(flatten)]
+    addr: AddrArgs,
 }
 
 pub fn parse_socket_addr() -> SocketAddr {
     let cli = Cli::parse();
-    SocketAddr::new(cli.ip, cli.port)
+    cli.addr.socket_addr()
 }
 
 // LOGGING //
@@ -117,7 +135,8 @@ pub fn parse_socket_addr() -> SocketAddr {
 const LOGS_DIR: &str = "logs";
 
 use std::io;
-use tracing_appender::{non_blocking::WorkerGuard, rolling::{RollingFileAppender, Rotation}};
+use std::path::Path;
+use tracing_appender::{non_blocking::WorkerGuard, rolling::{InitError, RollingFileAppender, Rotation}};
 use tracing_subscriber::{fmt, EnvFilter, layer::SubscriberExt};
 
 pub fn stdout_logging() {
@@ -140,6 +159,17 @@ pub fn file_logging(rotation: Rotation, log_file: &str) -> WorkerGuard {
     guard
 }
 
+// files named {prefix}.{date}.log in dir, only the newest
+// max_files are kept so give each appender its own dir
+pub fn rolling_appender(dir: &Path, rotation: Rotation, prefix: &str, max_files: usize) -> Result<RollingFileAppender, InitError> {
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(prefix)
+        .filename_suffix("log")
+        .max_log_files(max_files)
+        .build(dir)
+}
+
 // RANDOM MSG GENERATION //
 
 pub fn random_english_msg() -> String {
@@ -174,6 +204,14 @@ pub fn random_rust_msg() -> String {
 
 // MISC //
 
+// compares every byte so timing doesn't leak secrets
+pub fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// for user and room names
+pub const MAX_NAME_LEN: usize = 20;
+
 pub fn valid_name(name: Option<&str>) -> bool {
     match name {
         None => false,
@@ -181,7 +219,7 @@ pub fn valid_name(name: Option<&str>) -> bool {
             if name.len() < 2 {
                 return false;
             }
-            if name.len() > 20 {
+            if name.len() > MAX_NAME_LEN {
                 return false;
             }
             name
@@ -191,6 +229,51 @@ pub fn valid_name(name: Option<&str>) -> bool {
     }
 }
 
+// decoded json strings can hold newlines or terminal
+// escapes which would forge lines on text clients
+pub fn single_line(text: &str) -> bool {
+    !text.chars().any(char::is_control)
+}
+
+// e.g. 1h 2m 3s
+pub fn format_secs(secs: u64) -> String {
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match (hours, mins) {
+        (0, 0) => format!("{secs}s"),
+        (0, _) => format!("{mins}m {secs}s"),
+        _ => format!("{hours}h {mins}m {secs}s"),
+    }
+}
+
+// secs since the unix epoch
+pub fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// unix timestamp as "2024-05-01 13:37:00" in utc, see
+// http://howardhinnant.github.io/date_algorithms.html
+pub fn format_utc(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+    )
+}
+
 pub fn connection_refused(tried: SocketAddr) -> String {
     let mut msg = format!("No server listening on {tried}\n");
     msg.push_str("Try running: cargo run --release --bin chat-server");
@@ -207,3 +290,34 @@ pub fn connection_refused(tried: SocketAddr) -> String {
     }
     msg
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_control_chars() {
+        assert!(single_line("hi there, *bold* 👋"));
+        assert!(!single_line("hi\n[1] admin: forged"));
+        assert!(!single_line("hi\rforged"));
+        assert!(!single_line("\x1b[2Jcleared"));
+        assert!(!single_line("tab\there"));
+    }
+
+    #[test]
+    fn formats_epoch() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00");
+    }
+
+    #[test]
+    fn formats_leap_day() {
+        assert_eq!(format_utc(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_utc(951868800), "2000-03-01 00:00:00");
+    }
+
+    #[test]
+    fn formats_year_boundary() {
+        assert_eq!(format_utc(1704067199), "2023-12-31 23:59:59");
+        assert_eq!(format_utc(1704067200), "2024-01-01 00:00:00");
+    }
+}
@@ -0,0 +1,142 @@
+use std::borrow::Cow;
+
+// INLINE MARKUP //
+
+// users can write *bold*, _italic_ and `code`, the server
+// passes msgs through untouched to structured clients,
+// which style them, and strips the markers for plain
+// text users, markers only count if they hug a word so
+// things like 2*3*4 and snake_case_names are left alone
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Style {
+    Plain,
+    Bold,
+    Italic,
+    Code,
+}
+
+fn style(marker: char) -> Option<Style> {
+    match marker {
+        '*' => Some(Style::Bold),
+        '_' => Some(Style::Italic),
+        '`' => Some(Style::Code),
+        _ => None,
+    }
+}
+
+// finds the marker closing the one at start, if any
+fn closing(text: &str, start: usize, marker: char) -> Option<usize> {
+    let inner = start + marker.len_utf8();
+    let first = text[inner..].chars().next()?;
+    if first.is_whitespace() || first == marker {
+        return None;
+    }
+    for (idx, c) in text[inner..].char_indices().skip(1) {
+        let end = inner + idx;
+        if c != marker {
+            continue;
+        }
+        let before = text[..end].chars().next_back();
+        let after = text[end + marker.len_utf8()..].chars().next();
+        if before.is_some_and(|c| !c.is_whitespace()) && !after.is_some_and(char::is_alphanumeric) {
+            return Some(end);
+        }
+    }
+    None
+}
+
+// splits text into styled spans, markers are dropped
+pub fn parse(text: &str) -> Vec<(Style, &str)> {
+    let mut spans = Vec::new();
+    let mut plain_start = 0;
+    let mut idx = 0;
+    let mut prev: Option<char> = None;
+    while let Some(c) = text[idx..].chars().next() {
+        let opens = !prev.is_some_and(char::is_alphanumeric);
+        let found = style(c)
+            .filter(|_| opens)
+            .and_then(|style| closing(text, idx, c).map(|end| (style, end)));
+        match found {
+            Some((style, end)) => {
+                if plain_start < idx {
+                    spans.push((Style::Plain, &text[plain_start..idx]));
+                }
+                spans.push((style, &text[idx + c.len_utf8()..end]));
+                idx = end + c.len_utf8();
+                plain_start = idx;
+                prev = Some(c);
+            },
+            None => {
+                idx += c.len_utf8();
+                prev = Some(c);
+            },
+        }
+    }
+    if plain_start < text.len() {
+        spans.push((Style::Plain, &text[plain_start..]));
+    }
+    spans
+}
+
+// what plain text users see
+pub fn strip(text: &str) -> Cow<'_, str> {
+    let spans = parse(text);
+    if spans.iter().all(|(style, _)| *style == Style::Plain) {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(spans.into_iter().map(|(_, span)| span).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Style::*;
+
+    #[test]
+    fn styles() {
+        assert_eq!(parse("a *b* _c_ `d`"), [(Plain, "a "), (Bold, "b"), (Plain, " "), (Italic, "c"), (Plain, " "), (Code, "d")]);
+        assert_eq!(parse("*two words*"), [(Bold, "two words")]);
+        assert_eq!(parse("*end*."), [(Bold, "end"), (Plain, ".")]);
+    }
+
+    #[test]
+    fn unclosed_markers() {
+        assert_eq!(parse("*bold"), [(Plain, "*bold")]);
+        assert_eq!(parse("a _b c"), [(Plain, "a _b c")]);
+        assert_eq!(parse("`"), [(Plain, "`")]);
+        assert_eq!(parse("**"), [(Plain, "**")]);
+        // markers have to hug the text they style
+        assert_eq!(parse("* not bold *"), [(Plain, "* not bold *")]);
+        assert_eq!(parse("*a *"), [(Plain, "*a *")]);
+    }
+
+    #[test]
+    fn markers_inside_words() {
+        assert_eq!(parse("snake_case_names"), [(Plain, "snake_case_names")]);
+        assert_eq!(parse("2*3*4"), [(Plain, "2*3*4")]);
+        assert_eq!(parse("*a*b"), [(Plain, "*a*b")]);
+        assert_eq!(parse("_a_b_"), [(Italic, "a_b")]);
+    }
+
+    #[test]
+    fn nested_markers() {
+        // only the outermost marker styles a span
+        assert_eq!(parse("*bold _not italic_*"), [(Bold, "bold _not italic_")]);
+        assert_eq!(parse("_*a*_"), [(Italic, "*a*")]);
+    }
+
+    #[test]
+    fn code_spans() {
+        assert_eq!(parse("`a*b*c`"), [(Code, "a*b*c")]);
+        assert_eq!(parse("run `cargo test` now"), [(Plain, "run "), (Code, "cargo test"), (Plain, " now")]);
+    }
+
+    #[test]
+    fn stripping() {
+        assert!(matches!(strip("no markup"), Cow::Borrowed("no markup")));
+        assert!(matches!(strip("snake_case"), Cow::Borrowed(_)));
+        assert_eq!(strip("a *b* `c`"), "a b c");
+        assert_eq!(strip("é *ü*"), "é ü");
+    }
+}
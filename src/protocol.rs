@@ -0,0 +1,355 @@
+use serde::{Deserialize, Serialize};
+use crate::format_secs;
+
+// STRUCTURED PROTOCOL //
+
+// by default the server speaks plain text lines so it
+// can be used with telnet, clients which want to know
+// what each line means can send this line to switch
+// their connection to json lines, after which every
+// line in either direction is one json event
+pub const SWITCH_TO_JSON: &str = "/protocol json";
+
+// clients send typing-start at most this often while
+// the user keeps typing, and treat anyone they haven't
+// heard from in TYPING_TIMEOUT_SECS as having stopped
+pub const TYPING_REFRESH_SECS: u64 = 3;
+pub const TYPING_TIMEOUT_SECS: u64 = 6;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ServerEvent {
+    // sent right after switching protocols
+    Welcome {
+        name: String,
+        room: String,
+    },
+    // text meant only for this user, like
+    // command output or error msgs
+    Info {
+        text: String,
+    },
+    Msg {
+        id: u64,
+        room: String,
+        from: String,
+        // may contain *bold*, _italic_ and `code`
+        // markup, see markup.rs
+        text: String,
+        // id of the msg this replies to
+        reply_to: Option<u64>,
+    },
+    // sent with /me, e.g. "* Alice waves"
+    Action {
+        id: u64,
+        room: String,
+        from: String,
+        text: String,
+    },
+    // replaces the text of the msg with the same id
+    Edited {
+        id: u64,
+        room: String,
+        from: String,
+        text: String,
+    },
+    Deleted {
+        id: u64,
+        room: String,
+        by: String,
+    },
+    Joined {
+        room: String,
+        name: String,
+    },
+    Left {
+        room: String,
+        name: String,
+    },
+    Renamed {
+        room: String,
+        from: String,
+        to: String,
+    },
+    Away {
+        room: String,
+        name: String,
+        reason: Option<String>,
+    },
+    Back {
+        room: String,
+        name: String,
+    },
+    // direct msg, sent to both the sender and recipient
+    Direct {
+        from: String,
+        to: String,
+        text: String,
+    },
+    // replaces all previous reactions to the msg
+    Reactions {
+        room: String,
+        id: u64,
+        reactions: Vec<ReactionCount>,
+    },
+    // sent on joining a room with a topic, and whenever it
+    // changes, by is missing when it wasn't just changed
+    Topic {
+        room: String,
+        by: Option<String>,
+        topic: Option<String>,
+    },
+    // answer to /export, split into parts
+    // numbered from 1 to keep events small
+    Transcript {
+        room: String,
+        format: String,
+        part: usize,
+        parts: usize,
+        text: String,
+    },
+    // what moderation did to someone's msg,
+    // only sent to room operators and admins
+    Moderated {
+        room: String,
+        note: String,
+    },
+    // everyone in the room, sent whenever we join one,
+    // after which joined, left, renamed, away and back
+    // events are enough to keep the list up to date
+    Users {
+        room: String,
+        users: Vec<Presence>,
+    },
+    // answer to /whois
+    Whois(Whois),
+    // someone else in the room started or stopped
+    // typing, never stored and never sent to telnet users
+    TypingStart {
+        room: String,
+        name: String,
+    },
+    TypingStop {
+        room: String,
+        name: String,
+    },
+    // set with /remind, created is a unix timestamp in secs
+    Reminder {
+        id: u64,
+        text: String,
+        created: u64,
+    },
+    // sent when a poll is created, on every vote
+    // and once more when it's closed
+    Poll(Poll),
+    // answer to /search, room is missing when
+    // every room was searched, newest hits first
+    SearchResults {
+        query: String,
+        room: Option<String>,
+        total: usize,
+        hits: Vec<SearchHit>,
+    },
+    // client must answer with a pong
+    // or eventually be disconnected
+    Ping {
+        id: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Presence {
+    pub name: String,
+    pub away: bool,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SearchHit {
+    pub id: u64,
+    pub room: String,
+    pub from: String,
+    pub text: String,
+    // unix timestamp in secs
+    pub sent: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Whois {
+    pub name: String,
+    pub room: String,
+    pub connected_secs: u64,
+    pub idle_secs: u64,
+    pub away: bool,
+    pub reason: Option<String>,
+    // only sent to admins
+    pub addr: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Poll {
+    pub room: String,
+    pub id: u64,
+    pub by: String,
+    pub question: String,
+    pub options: Vec<PollOption>,
+    pub closed: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PollOption {
+    pub text: String,
+    pub votes: usize,
+}
+
+impl Poll {
+    pub fn votes(&self) -> usize {
+        self.options.iter().map(|option| option.votes).sum()
+    }
+    // how plain text users see it, the full poll when
+    // it's created, a short tally on every vote after
+    // and who won once it's closed
+    pub fn to_text(&self) -> String {
+        let (id, question, votes) = (self.id, &self.question, self.votes());
+        let tally: Vec<String> = self
+            .options
+            .iter()
+            .map(|option| format!("{} {}", option.text, option.votes))
+            .collect();
+        if self.closed {
+            let most = self.options.iter().map(|option| option.votes).max().unwrap_or(0);
+            let winners: Vec<&str> = self
+                .options
+                .iter()
+                .filter(|option| option.votes == most)
+                .map(|option| option.text.as_str())
+                .collect();
+            let result = match winners.as_slice() {
+                _ if votes == 0 => "nobody voted".to_owned(),
+                [winner] => format!("{winner} won with {most} of {votes} votes"),
+                tied => format!("{} tied with {most} of {votes} votes", tied.join(" and ")),
+            };
+            return format!("Poll {id} closed - {question} - {result} - {}", tally.join(", "));
+        }
+        if votes > 0 {
+            return format!("Poll {id} - {question} - {}", tally.join(", "));
+        }
+        let mut text = format!("Poll {id} by {} - {question}", self.by);
+        for (idx, option) in self.options.iter().enumerate() {
+            text.push_str(&format!("\n  {}) {}", idx + 1, option.text));
+        }
+        text.push_str(&format!("\n  vote with /vote {id} {{option}}"));
+        text
+    }
+}
+
+impl Whois {
+    // how plain text users see it
+    pub fn to_text(&self) -> String {
+        let away = match (self.away, &self.reason) {
+            (true, Some(reason)) => reason.as_str(),
+            (true, None) => "yes",
+            (false, _) => "no",
+        };
+        let mut text = format!(
+            "{}\n  room - {}\n  connected - {}\n  idle - {}\n  away - {away}",
+            self.name,
+            self.room,
+            format_secs(self.connected_secs),
+            format_secs(self.idle_secs),
+        );
+        if let Some(addr) = &self.addr {
+            text.push_str("\n  address - ");
+            text.push_str(addr);
+        }
+        text
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ClientEvent {
+    // anything a telnet user could type,
+    // chat msgs as well as commands
+    Line {
+        text: String,
+    },
+    Pong {
+        id: u64,
+    },
+    // see TYPING_REFRESH_SECS
+    TypingStart,
+    // after sending or clearing what was typed
+    TypingStop,
+}
+
+impl ServerEvent {
+    pub fn to_json(&self) -> String {
+        // serializing our own enums can't fail
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl ClientEvent {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll(votes: &[usize], closed: bool) -> Poll {
+        Poll {
+            room: "main".to_owned(),
+            id: 1,
+            by: "al".to_owned(),
+            question: "lunch?".to_owned(),
+            options: ["pizza", "soup", "salad"]
+                .iter()
+                .zip(votes)
+                .map(|(text, &votes)| PollOption {
+                    text: text.to_string(),
+                    votes,
+                })
+                .collect(),
+            closed,
+        }
+    }
+
+    #[test]
+    fn shows_new_polls_in_full() {
+        let text = poll(&[0, 0, 0], false).to_text();
+        assert_eq!(text, "Poll 1 by al - lunch?\n  1) pizza\n  2) soup\n  3) salad\n  vote with /vote 1 {option}");
+    }
+
+    #[test]
+    fn tallies_votes() {
+        assert_eq!(poll(&[2, 1, 0], false).to_text(), "Poll 1 - lunch? - pizza 2, soup 1, salad 0");
+    }
+
+    #[test]
+    fn closes_with_winner() {
+        let text = poll(&[2, 1, 0], true).to_text();
+        assert_eq!(text, "Poll 1 closed - lunch? - pizza won with 2 of 3 votes - pizza 2, soup 1, salad 0");
+    }
+
+    #[test]
+    fn closes_with_tie() {
+        let text = poll(&[2, 2, 1], true).to_text();
+        assert_eq!(text, "Poll 1 closed - lunch? - pizza and soup tied with 2 of 5 votes - pizza 2, soup 2, salad 1");
+    }
+
+    #[test]
+    fn closes_without_votes() {
+        let text = poll(&[0, 0, 0], true).to_text();
+        assert_eq!(text, "Poll 1 closed - lunch? - nobody voted - pizza 0, soup 0, salad 0");
+    }
+}